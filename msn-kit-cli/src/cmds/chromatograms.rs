@@ -0,0 +1,92 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+
+use std::convert::TryFrom;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+
+use msn_kit::chromatogram::Chromatogram;
+use msn_kit::io::mzml_parser::MzMLReader;
+
+/// The formats chromatograms can be written in.
+#[derive(Debug, PartialEq)]
+pub enum ChromatogramFormat {
+    /// One json record per chromatogram, one per line.
+    Json,
+
+    /// Tab separated values, one row per time point.
+    Tsv,
+}
+
+impl FromStr for ChromatogramFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "tsv" => Ok(Self::Tsv),
+            _ => Err("Cannot parse chromatogram format."),
+        }
+    }
+}
+
+fn write_tsv_rows<W: Write>(writer: &mut W, c: &Chromatogram) -> std::io::Result<()> {
+    let mz = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+
+    for (time, intensity) in c.times.iter().zip(&c.intensities) {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            c.id,
+            mz(c.precursor_mz),
+            mz(c.product_mz),
+            time,
+            intensity
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the chromatograms of an mzML input, with times in seconds.
+///
+/// # Arguments
+///
+/// * `input` - The mzML to read.
+/// * `output` - Where to write the chromatograms.
+/// * `format` - Whether to write json lines or tsv.
+pub fn chromatograms<R: Read, W: Write>(
+    input: R,
+    output: W,
+    format: ChromatogramFormat,
+) -> std::io::Result<()> {
+    let reader = MzMLReader::from_reader(BufReader::new(input));
+    let mut writer = BufWriter::new(output);
+
+    if format == ChromatogramFormat::Tsv {
+        writeln!(writer, "id\tprecursor_mz\tproduct_mz\ttime\tintensity")?;
+    }
+
+    for chromatogram in reader.chromatograms() {
+        let chromatogram = Chromatogram::try_from(chromatogram?)?;
+
+        let result = match format {
+            ChromatogramFormat::Json => serde_json::to_writer(&mut writer, &chromatogram)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n")),
+            ChromatogramFormat::Tsv => write_tsv_rows(&mut writer, &chromatogram),
+        };
+
+        if let Err(e) = result {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => return Ok(()),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    match writer.flush() {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(()),
+    }
+}
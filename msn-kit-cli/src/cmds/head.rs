@@ -1,34 +1,29 @@
 // (c) Copyright 2021 Trent Hauck
 // All Rights Reserved
 
-use std::io::{Read, Write};
-
-use msn_kit::io;
+use msn_kit::{io, spectrum};
 
 /// Take the first `number` records from input and write them to output.
 ///
 /// # Arguments
 ///
-/// * `input` - The input reader object.
+/// * `spectra` - The spectra to read from.
 /// * `output` - The output writer object.
 /// * `number` - How many records to keep.
-pub fn head<R: Read, W: Write>(
-    input: R,
-    mgf_writer: &mut io::mgf_parser::MGFWriter<W>,
-    number: i32,
-) -> std::io::Result<()> {
-    let mgf_parser = io::mgf_parser::MGFReader::new(input);
-
+pub fn head<I, W>(spectra: I, writer: &mut W, number: i32) -> std::io::Result<()>
+where
+    I: Iterator<Item = std::io::Result<spectrum::Spectrum>>,
+    W: io::SpectrumWriter,
+{
     let head_number = number - 1;
 
-    let spectra = mgf_parser.spectra();
     for (i, s) in spectra.enumerate() {
         if i as i32 > head_number {
             break;
         }
 
         let writer_s = s?;
-        mgf_writer.write(writer_s)?;
+        writer.write(writer_s)?;
     }
 
     Ok(())
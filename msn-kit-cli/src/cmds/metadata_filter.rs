@@ -1,39 +1,39 @@
 // (c) Copyright 2021 Trent Hauck
 // All Rights Reserved
 
-use std::io::{Read, Write};
+use msn_kit::{io, spectrum};
 
-use msn_kit::io;
-
-/// Write data from input to output, while filtering for key by value.
+/// Write data from input to output, while filtering for key by value. Only the peaks of the
+/// spectra that are kept are parsed.
 ///
 /// # Arguments
 ///
-/// * `input` - A BufReader to read new lines.
+/// * `spectra` - The spectra to filter.
 /// * `output` - A Writer to write output MGF records.
 /// * `key` - The key within metadata to find.
 /// * `value` - The value to filter with, match against key.
 ///
-pub fn metadata_filter<R: Read, W: Write>(
-    input: R,
-    mgf_writer: &mut io::mgf_parser::MGFWriter<W>,
+pub fn metadata_filter<I, W>(
+    spectra: I,
+    writer: &mut W,
     key: String,
     value: Option<String>,
-) -> std::io::Result<()> {
-    let mgf_parser = io::mgf_parser::MGFReader::new(input);
-
-    let spectra = mgf_parser.spectra();
+) -> std::io::Result<()>
+where
+    I: Iterator<Item = std::io::Result<spectrum::LazySpectrum>>,
+    W: io::SpectrumWriter,
+{
     for spectrum in spectra {
         let writer_s = spectrum?;
 
         match writer_s.metadata.get(&key) {
-            Some(key_value) => {
+            Some(_) => {
                 if let Some(ref found_value) = value {
-                    if key_value == found_value {
-                        mgf_writer.write(writer_s)?;
+                    if writer_s.metadata.get_all(&key).any(|v| v == found_value) {
+                        writer.write(writer_s.into_spectrum()?)?;
                     }
                 } else {
-                    mgf_writer.write(writer_s)?;
+                    writer.write(writer_s.into_spectrum()?)?;
                 }
             }
             None => {
@@ -1,6 +1,7 @@
 // (c) Copyright 2021 Trent Hauck
 // All Rights Reserved
 
+pub mod chromatograms;
 pub mod head;
 pub mod metadata_filter;
 pub mod mzml_cat;
@@ -26,22 +26,15 @@ impl<W: Write> SpectrumWriter<W> {
 pub fn cat<R: Read, W: Write>(input: R, output: W) -> std::io::Result<()> {
     let buf_reader = BufReader::new(input);
 
-    let mut reader = MzMLReader::from_reader(buf_reader);
+    let reader = MzMLReader::from_reader(buf_reader);
     let mut writer = SpectrumWriter { writer: output };
 
-    loop {
-        let spectrum = reader.read_spectrum();
-
-        match spectrum {
-            Ok(a) => {
-                if let Err(e) = writer.write_spectrum(&a) {
-                    match e.kind() {
-                        std::io::ErrorKind::BrokenPipe => break,
-                        _ => return Err(e),
-                    }
-                }
+    for spectrum in reader.spectra() {
+        if let Err(e) = writer.write_spectrum(&spectrum?) {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => break,
+                _ => return Err(e),
             }
-            Err(_) => break,
         }
     }
 
@@ -3,27 +3,27 @@
 
 use std::{
     collections::HashMap,
-    io::{BufWriter, Read, Write},
+    io::{BufWriter, Write},
 };
 
 use serde::{Deserialize, Serialize};
 
-use msn_kit::{io, spectrum};
+use msn_kit::spectrum;
 
 impl SummaryStatistics {
     fn new() -> Self {
-        return SummaryStatistics {
+        SummaryStatistics {
             n_spectra: 0,
             n_peaks: 0,
             metadata_field_counts: HashMap::<String, i32>::new(),
             n_no_peaks: 0,
-        };
+        }
     }
 
-    fn add_spectrum(&mut self, s: spectrum::Spectrum) {
+    fn add_spectrum(&mut self, s: spectrum::LazySpectrum) {
         self.n_spectra += 1;
 
-        let n_peaks = s.mz.len() as i32;
+        let n_peaks = s.n_peaks() as i32;
         if n_peaks == 0 {
             self.n_no_peaks += 1;
         }
@@ -57,13 +57,14 @@ impl SummaryStatistics {
     }
 }
 
-pub fn stats<R: Read, W: Write>(input: R, writer: W) -> std::io::Result<()> {
-    let mgf_parser = io::mgf_parser::MGFReader::new(input);
-
+pub fn stats<I, W>(spectra: I, writer: W) -> std::io::Result<()>
+where
+    I: Iterator<Item = std::io::Result<spectrum::LazySpectrum>>,
+    W: Write,
+{
     let mut stats = SummaryStatistics::new();
     let buf_writer = &mut BufWriter::new(writer);
 
-    let spectra = mgf_parser.spectra();
     for spectrum in spectra {
         let s = spectrum?;
         stats.add_spectrum(s);
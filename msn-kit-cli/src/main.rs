@@ -2,13 +2,13 @@
 // All Rights Reserved
 
 use std::fs::File;
-use std::io::{stdin, stdout};
+use std::io::{stdin, stdout, BufRead, Write};
 use std::path::PathBuf;
 
 mod cmds;
 
 use clap::Parser;
-use msn_kit::io;
+use msn_kit::io::{self, SpectrumReader, SpectrumWriter};
 
 #[derive(Parser)]
 #[clap(
@@ -21,6 +21,33 @@ struct Opts {
     #[clap(short, help = "The output file to write to", default_value = "mgf")]
     output_format: io::Format,
 
+    #[clap(
+        short,
+        long,
+        help = "The input format, json, mgf, mzml, mzxml, msp or ms2 [default: detected from the input]"
+    )]
+    input_format: Option<io::Format>,
+
+    #[clap(
+        short = 'z',
+        long,
+        help = "Compress the output with gzip, bzip2, zstd, xz or none [default: from the output file's extension]"
+    )]
+    compression: Option<io::compression::Compression>,
+
+    #[clap(
+        long,
+        parse(from_os_str),
+        help = "The file to write to instead of stdout"
+    )]
+    output_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Reject MGF input with comments, CRLF line endings, space separated or extra peak columns"
+    )]
+    strict: bool,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -40,6 +67,9 @@ enum SubCommand {
 
     #[clap(override_help = "Cat an MzML file.")]
     MzMLCat(MzMLCat),
+
+    #[clap(override_help = "Write the chromatograms in an mzML file as json lines or tsv")]
+    Chromatograms(Chromatograms),
 }
 
 #[derive(Parser)]
@@ -63,6 +93,20 @@ struct MzMLCat {
     input: Option<PathBuf>,
 }
 
+#[derive(Parser)]
+struct Chromatograms {
+    #[clap(
+        short,
+        long,
+        help = "The format to write, json or tsv",
+        default_value = "json"
+    )]
+    format: cmds::chromatograms::ChromatogramFormat,
+
+    #[clap(parse(from_os_str), help = "The input path or stdin")]
+    input: Option<PathBuf>,
+}
+
 #[derive(Parser)]
 struct FilterByKeyValue {
     #[clap(short, help = "The key to check, values missing the key are omitted")]
@@ -75,42 +119,103 @@ struct FilterByKeyValue {
     input: Option<PathBuf>,
 }
 
+/// Opens the input path or stdin, decompressing it if needed, along with the format implied by
+/// the path's extension.
+fn open_input(input: Option<PathBuf>) -> std::io::Result<(Box<dyn BufRead>, Option<io::Format>)> {
+    match input {
+        None => Ok((io::compression::decompress(stdin())?, None)),
+        Some(p) => {
+            let format = io::Format::from_path(&p);
+            Ok((io::compression::open(p)?, format))
+        }
+    }
+}
+
+/// Opens the output file or stdout, compressed as asked for or as implied by the file's extension.
+fn open_output(
+    output_file: Option<PathBuf>,
+    compression: Option<io::compression::Compression>,
+) -> std::io::Result<io::compression::Encoder<Box<dyn Write>>> {
+    let (writer, implied): (Box<dyn Write>, io::compression::Compression) = match output_file {
+        None => (Box::new(stdout()), io::compression::Compression::None),
+        Some(p) => {
+            let implied = io::compression::Compression::from_path(&p);
+            (Box::new(File::create(p)?), implied)
+        }
+    };
+
+    io::compression::Encoder::new(writer, compression.unwrap_or(implied))
+}
+
+/// Opens a `SpectrumReader` over the input path or stdin. Unless `input_format` is given, the
+/// format is detected from the start of the input, then the path's extension, defaulting to MGF.
+/// MGF is read with `MGFReader::with_strict` when `strict` is set.
+fn read_spectra(
+    input: Option<PathBuf>,
+    input_format: Option<io::Format>,
+    strict: bool,
+) -> std::io::Result<Box<dyn io::SpectrumReader>> {
+    let (mut reader, path_format) = open_input(input)?;
+
+    let format = match input_format {
+        Some(format) => format,
+        None => io::Format::sniff_reader(&mut reader)?
+            .or(path_format)
+            .unwrap_or(io::Format::Mgf),
+    };
+
+    if strict && format == io::Format::Mgf {
+        return Ok(Box::new(
+            io::mgf_parser::MGFReader::new(reader)
+                .with_strict(true)
+                .with_inherit_header(true),
+        ));
+    }
+
+    io::from_reader(reader, format)
+}
+
 /// Main entrypoint for the CLI.
 fn main() -> std::io::Result<()> {
     let opts: Opts = Opts::parse();
 
-    let output_enum = io::Format::from(opts.output_format);
-
-    let writer = &mut io::mgf_parser::MGFWriter::new(stdout(), output_enum);
-
-    match opts.subcmd {
-        SubCommand::MzMLCat(t) => match t.input {
-            None => cmds::mzml_cat::cat(stdin(), stdout()),
-            Some(p) => {
-                let f = File::open(p).unwrap();
-                cmds::mzml_cat::cat(f, stdout())
-            }
-        },
-        SubCommand::Stats(t) => match t.input {
-            None => cmds::stats::stats(stdin(), stdout()),
-            Some(p) => {
-                let f = File::open(p).unwrap();
-                cmds::stats::stats(f, stdout())
-            }
-        },
-        SubCommand::Head(t) => match t.input {
-            None => cmds::head::head(stdin(), writer, t.number),
-            Some(p) => {
-                let f = File::open(p).unwrap();
-                cmds::head::head(f, writer, t.number)
-            }
-        },
-        SubCommand::MetadataFilter(t) => match t.input {
-            None => cmds::metadata_filter::metadata_filter(stdin(), writer, t.key, t.value),
-            Some(p) => {
-                let f = File::open(p).unwrap();
-                cmds::metadata_filter::metadata_filter(f, writer, t.key, t.value)
-            }
-        },
+    let mut output = open_output(opts.output_file, opts.compression)?;
+    let output_format = opts.output_format;
+    let input_format = opts.input_format;
+    let strict = opts.strict;
+
+    let result = match opts.subcmd {
+        SubCommand::MzMLCat(t) => cmds::mzml_cat::cat(open_input(t.input)?.0, &mut output),
+        SubCommand::Chromatograms(t) => {
+            cmds::chromatograms::chromatograms(open_input(t.input)?.0, &mut output, t.format)
+        }
+        SubCommand::Stats(t) => cmds::stats::stats(
+            read_spectra(t.input, input_format, strict)?.into_lazy_spectra(),
+            &mut output,
+        ),
+        SubCommand::Head(t) => {
+            let mut writer = io::to_writer(&mut output, output_format)?;
+            cmds::head::head(
+                read_spectra(t.input, input_format, strict)?.into_spectra(),
+                &mut writer,
+                t.number,
+            )
+            .and_then(|_| writer.finish())
+        }
+        SubCommand::MetadataFilter(t) => {
+            let mut writer = io::to_writer(&mut output, output_format)?;
+            cmds::metadata_filter::metadata_filter(
+                read_spectra(t.input, input_format, strict)?.into_lazy_spectra(),
+                &mut writer,
+                t.key,
+                t.value,
+            )
+            .and_then(|_| writer.finish())
+        }
+    };
+
+    match result.and_then(|_| output.finish()) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
     }
 }
@@ -0,0 +1,51 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! A Chromatogram is an intensity trace over time, e.g. the total ion current or an SRM
+//! transition.
+
+use serde::{Deserialize, Serialize};
+
+/// # Examples
+///
+/// A two point trace of an SRM transition.
+///
+/// ```
+/// let mut c = msn_kit::chromatogram::Chromatogram::new(
+///     String::from("SRM SIC Q1=445.34 Q3=329.2"),
+///     vec![30.0, 36.0],
+///     vec![11.0, 30.0],
+/// );
+/// c.precursor_mz = Some(445.34);
+/// c.product_mz = Some(329.2);
+///
+/// assert_eq!(c.times, vec![30.0, 36.0]);
+/// ```
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chromatogram {
+    pub id: String,
+    pub precursor_mz: Option<f64>,
+    pub product_mz: Option<f64>,
+    /// The retention times in seconds.
+    pub times: Vec<f64>,
+    pub intensities: Vec<f64>,
+}
+
+impl Chromatogram {
+    /// Create a new Chromatogram without precursor or product m/z.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The chromatogram id, e.g. `TIC`.
+    /// * `times` - The retention times in seconds.
+    /// * `intensities` - The intensities vector.
+    ///
+    pub fn new(id: String, times: Vec<f64>, intensities: Vec<f64>) -> Self {
+        Self {
+            id,
+            precursor_mz: None,
+            product_mz: None,
+            times,
+            intensities,
+        }
+    }
+}
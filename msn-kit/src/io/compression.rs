@@ -0,0 +1,326 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Module for transparently reading and writing compressed files.
+//!
+//! Compressed input is detected from its first bytes rather than its name, so e.g. a gzipped
+//! file piped through stdin is read the same as `run.mzML.gz`. Output is compressed by the
+//! `Compression` asked for, or the one implied by the output path's extension.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+/// The compression formats that can be read and written.
+///
+/// # Examples
+///
+/// ```
+/// use std::str::FromStr;
+/// use msn_kit::io::compression::Compression;
+///
+/// assert_eq!(Compression::from_str("gz").unwrap(), Compression::Gzip);
+/// assert_eq!(Compression::from_magic(b"BZh91AY"), Compression::Bzip2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// Plain, uncompressed data.
+    None,
+
+    /// gzip, including multi-member files such as bgzip output.
+    Gzip,
+
+    /// bzip2
+    Bzip2,
+
+    /// Zstandard
+    Zstd,
+
+    /// xz
+    Xz,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// How much of the input is read up front, enough for the magic bytes and for the first buffer
+/// of plain input to hold what `Format::sniff` needs.
+const PEEK_LEN: u64 = 8 * 1024;
+
+impl Compression {
+    /// Returns the compression of data starting with `bytes`.
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if bytes.starts_with(BZIP2_MAGIC) {
+            Self::Bzip2
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else if bytes.starts_with(XZ_MAGIC) {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+
+    /// Returns the compression implied by the extension of `path`, e.g. `run.mgf.gz`.
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| Self::from_extension(&e.to_lowercase()))
+            .unwrap_or(Self::None)
+    }
+
+    /// Returns the compression for a file extension, without the dot.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "gz" | "gzip" => Some(Self::Gzip),
+            "bz2" => Some(Self::Bzip2),
+            "zst" => Some(Self::Zstd),
+            "xz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+}
+
+/// Creates a `Compression` from a name or extension, e.g. `gzip` or `gz`.
+impl FromStr for Compression {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "bzip2" | "bz2" => Ok(Self::Bzip2),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            _ => Err("Cannot parse compression."),
+        }
+    }
+}
+
+/// Wraps `reader`, decompressing it if it starts with a known compression's magic bytes.
+///
+/// The first 8 KiB of the input are read before returning, so the returned reader's first
+/// `fill_buf` sees all of them if the input isn't compressed.
+///
+/// # Arguments
+///
+/// * `reader` - The possibly compressed input.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use flate2::{write::GzEncoder, Compression};
+///
+/// let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+/// encoder.write_all(b"BEGIN IONS\n").unwrap();
+/// let gzipped = encoder.finish().unwrap();
+///
+/// let mut text = String::new();
+/// msn_kit::io::compression::decompress(std::io::Cursor::new(gzipped))
+///     .unwrap()
+///     .read_to_string(&mut text)
+///     .unwrap();
+/// assert_eq!(text, "BEGIN IONS\n");
+/// ```
+pub fn decompress<R: Read + 'static>(mut reader: R) -> std::io::Result<Box<dyn BufRead>> {
+    let mut peeked = Vec::with_capacity(PEEK_LEN as usize);
+    (&mut reader).take(PEEK_LEN).read_to_end(&mut peeked)?;
+
+    let compression = Compression::from_magic(&peeked);
+    let reader = Cursor::new(peeked).chain(reader);
+
+    Ok(match compression {
+        Compression::None => Box::new(BufReader::new(reader)),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(reader)?)),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+    })
+}
+
+/// Opens the file at `path` for reading, decompressing it if needed.
+pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn BufRead>> {
+    decompress(File::open(path)?)
+}
+
+/// Creates the file at `path`, compressed as implied by its extension.
+pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Encoder<File>> {
+    let compression = Compression::from_path(path.as_ref());
+
+    Encoder::new(File::create(path)?, compression)
+}
+
+enum Inner<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+/// A writer that compresses what's written to it.
+///
+/// The compressed stream is finished when the encoder is dropped, but errors are lost then, so
+/// call `finish` to see them.
+pub struct Encoder<W: Write> {
+    inner: Inner<W>,
+    finished: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create a new Encoder.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where to write the compressed output.
+    /// * `compression` - The compression to use, `Compression::None` to pass writes through.
+    ///
+    pub fn new(writer: W, compression: Compression) -> std::io::Result<Self> {
+        let inner = match compression {
+            Compression::None => Inner::None(writer),
+            Compression::Gzip => {
+                Inner::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Bzip2 => {
+                Inner::Bzip2(BzEncoder::new(writer, bzip2::Compression::default()))
+            }
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(writer, 0)?),
+            Compression::Xz => Inner::Xz(XzEncoder::new(writer, 6)),
+        };
+
+        Ok(Self {
+            inner,
+            finished: false,
+        })
+    }
+
+    /// Writes the end of the compressed stream and flushes the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.try_finish()
+    }
+
+    fn try_finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        match &mut self.inner {
+            Inner::None(w) => w.flush(),
+            Inner::Gzip(w) => w.try_finish().and_then(|_| w.get_mut().flush()),
+            Inner::Bzip2(w) => w.try_finish().and_then(|_| w.get_mut().flush()),
+            Inner::Zstd(w) => w.do_finish().and_then(|_| w.get_mut().flush()),
+            Inner::Xz(w) => w.try_finish().and_then(|_| w.get_mut().flush()),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.inner {
+            Inner::None(w) => w.write(buf),
+            Inner::Gzip(w) => w.write(buf),
+            Inner::Bzip2(w) => w.write(buf),
+            Inner::Zstd(w) => w.write(buf),
+            Inner::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.inner {
+            Inner::None(w) => w.flush(),
+            Inner::Gzip(w) => w.flush(),
+            Inner::Bzip2(w) => w.flush(),
+            Inner::Zstd(w) => w.flush(),
+            Inner::Xz(w) => w.flush(),
+        }
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        let _ = self.try_finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MGF: &[u8] = b"BEGIN IONS\nTITLE=1\n13.0\t1.0\nEND IONS\n";
+
+    #[test]
+    fn round_trip_test() {
+        let compressions = vec![
+            Compression::None,
+            Compression::Gzip,
+            Compression::Bzip2,
+            Compression::Zstd,
+            Compression::Xz,
+        ];
+
+        for compression in compressions {
+            let mut compressed = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut compressed, compression).unwrap();
+                encoder.write_all(MGF).unwrap();
+                encoder.finish().unwrap();
+            }
+            assert_eq!(Compression::from_magic(&compressed), compression);
+
+            let mut decompressed = Vec::new();
+            decompress(Cursor::new(compressed))
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, MGF, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn drop_finishes_test() {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut compressed, Compression::Zstd).unwrap();
+            encoder.write_all(MGF).unwrap();
+        }
+
+        let mut decompressed = Vec::new();
+        decompress(Cursor::new(compressed))
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, MGF);
+    }
+
+    #[test]
+    fn short_input_test() {
+        let mut decompressed = Vec::new();
+        decompress(&b"{}"[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, b"{}");
+
+        assert_eq!(
+            Compression::from_path(Path::new("run.mzML.gz")),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("run.mgf")),
+            Compression::None
+        );
+    }
+}
@@ -0,0 +1,215 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Module for reading and writing spectra as json lines, one json record per spectrum.
+
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::io::{compression, SpectrumReader, SpectrumWriter};
+use crate::spectrum::Spectrum;
+
+/// Reads spectra from json lines, as written by `JsonWriter`, one line at a time.
+///
+/// Blank lines are skipped. Errors give the line number of the record that couldn't be read,
+/// counting from 1.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::json_parser::JsonReader;
+/// use msn_kit::io::SpectrumReader;
+///
+/// let json = b"{\"metadata\":{\"TITLE\":\"1\"},\"mz\":[100.5],\"intensities\":[10.0]}\n\n{\"mz\":[]}\n";
+/// let mut reader = JsonReader::new(&json[..]);
+///
+/// let spectrum = reader.next_spectrum().unwrap().unwrap();
+/// assert_eq!(spectrum.metadata["TITLE"], "1");
+///
+/// let error = reader.next_spectrum().unwrap_err();
+/// assert!(error.to_string().starts_with("Invalid spectrum on line 3"));
+/// ```
+pub struct JsonReader<R: BufRead> {
+    reader: R,
+    line: String,
+    line_number: usize,
+}
+
+impl JsonReader<Box<dyn BufRead>> {
+    /// Open the json lines file at `path`, decompressing it if it's compressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the possibly compressed json lines file.
+    ///
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(compression::open(path)?))
+    }
+}
+
+impl<R: BufRead> JsonReader<R> {
+    /// Create a new JsonReader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The json lines to read.
+    ///
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    /// Returns the number of lines read so far, which is the line of the last spectrum read.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    fn invalid(&self, message: String) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid spectrum on line {}: {}", self.line_number, message),
+        )
+    }
+}
+
+impl<R: BufRead> SpectrumReader for JsonReader<R> {
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+
+            if self.line.trim().is_empty() {
+                continue;
+            }
+
+            let spectrum: Spectrum =
+                serde_json::from_str(&self.line).map_err(|e| self.invalid(e.to_string()))?;
+
+            if spectrum.mz.len() != spectrum.intensities.len() {
+                return Err(self.invalid(format!(
+                    "{} m/z values but {} intensities",
+                    spectrum.mz.len(),
+                    spectrum.intensities.len()
+                )));
+            }
+
+            return Ok(Some(spectrum));
+        }
+    }
+}
+
+/// Writes spectra as json lines.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::json_parser::JsonWriter;
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let mut writer = JsonWriter::new(Vec::new());
+/// writer.write(Spectrum::new(Metadata::new(), vec![100.5], vec![10.0])).unwrap();
+///
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "{\"metadata\":{},\"mz\":[100.5],\"intensities\":[10.0]}\n");
+/// ```
+#[derive(Debug)]
+pub struct JsonWriter<W: Write> {
+    writer: std::io::BufWriter<W>,
+}
+
+impl<W: Write> JsonWriter<W> {
+    /// Create a new JsonWriter.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - An object that can be written to.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: std::io::BufWriter::new(writer),
+        }
+    }
+
+    /// Write spectrum to the underlying buffer as a line of json.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        let result = serde_json::to_writer(&mut self.writer, &spectrum);
+        self.writer.write_all(b"\n")?;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::other("Error writing json for spectrum.")),
+        }
+    }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> SpectrumWriter for JsonWriter<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        JsonWriter::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::mgf_parser::MGFReader;
+
+    #[test]
+    fn round_trip_test() {
+        let mgf = b"BEGIN IONS\nTITLE=1\n13.0\t1.0\nEND IONS\n\nBEGIN IONS\nEND IONS\n";
+        let spectra: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), 2);
+
+        let mut writer = JsonWriter::new(Vec::new());
+        for s in MGFReader::new(&mgf[..]).into_spectra() {
+            SpectrumWriter::write(&mut writer, s.unwrap()).unwrap();
+        }
+        let json = writer.into_inner().unwrap();
+
+        let read: Vec<Spectrum> = JsonReader::new(&json[..])
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(read, spectra);
+    }
+
+    #[test]
+    fn line_number_test() {
+        let json = b"\n{\"metadata\":{},\"mz\":[1.0],\"intensities\":[2.0]}\r\n\n{\"metadata\":{},\"mz\":[1.0, 2.0],\"intensities\":[2.0]}\n";
+        let mut reader = JsonReader::new(&json[..]);
+
+        assert_eq!(reader.next_spectrum().unwrap().unwrap().mz, vec![1.0]);
+        assert_eq!(reader.line_number(), 2);
+
+        let error = reader.next_spectrum().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "Invalid spectrum on line 4: 2 m/z values but 1 intensities"
+        );
+
+        assert!(reader.next_spectrum().unwrap().is_none());
+    }
+}
@@ -1,14 +1,318 @@
 // (c) Copyright 2021 Trent Hauck
 // All Rights Reserved
 //! Module for reading and writing MGF files.
-use std::io::{Error, ErrorKind, Write};
+use std::fmt;
+use std::io::Write;
 
-use crate::io::Format;
-use crate::spectrum::Spectrum;
+use crate::io::{compression, SpectrumReader, SpectrumWriter};
+use crate::metadata::{self, Metadata};
+use crate::spectrum::{LazySpectrum, Spectrum};
 
+/// The global parameters of an MGF file, e.g. `CHARGE=2+ and 3+` or `MASS=Monoisotopic`, given
+/// before its first `BEGIN IONS`. They're kept in the order they were read, and a parameter can
+/// be given more than once, e.g. several `COM` lines.
+///
+/// ```
+/// use msn_kit::io::mgf_parser::MGFHeader;
+///
+/// let mut header = MGFHeader::new();
+/// header.insert("MASS", "Monoisotopic");
+/// header.insert("CHARGE", "2+");
+/// header.insert("CHARGE", "2+ and 3+");
+/// header.append("COM", "first comment");
+/// header.append("COM", "second comment");
+///
+/// assert_eq!(header.get("CHARGE"), Some("2+ and 3+"));
+/// assert_eq!(header.get_all("COM").collect::<Vec<_>>(), vec!["first comment", "second comment"]);
+/// assert_eq!(header.iter().next(), Some(("MASS", "Monoisotopic")));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MGFHeader {
+    params: Vec<(String, String)>,
+}
+
+impl MGFHeader {
+    /// Create an empty header.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the first value of the parameter `key`, if it's set.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value of the parameter `key`, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the parameter `key` to `value`, replacing its first value and removing any others.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The parameter name, e.g. `CHARGE`.
+    /// * `value` - The parameter's value.
+    ///
+    pub fn insert(&mut self, key: &str, value: &str) {
+        match self.params.iter().position(|(k, _)| k == key) {
+            Some(i) => {
+                self.params[i].1 = value.to_string();
+                let mut j = i + 1;
+                while j < self.params.len() {
+                    if self.params[j].0 == key {
+                        self.params.remove(j);
+                    } else {
+                        j += 1;
+                    }
+                }
+            }
+            None => self.params.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Add the parameter `key` with `value` after the others, keeping any previous values.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The parameter name, e.g. `COM`.
+    /// * `value` - The parameter's value.
+    ///
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.params.push((key.to_string(), value.to_string()));
+    }
+
+    /// Returns an iterator over the (key, value) parameters.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Returns the number of parameters.
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    /// Returns true if there are no parameters.
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+/// An error encountered while reading MGF.
+///
+/// Errors in the input carry an `MgfLocation` with the line, byte offset and spectrum they were
+/// found in and the offending text, so a bad record can be found in a large file.
+#[derive(Debug)]
+pub enum MgfError {
+    /// Reading from the underlying reader failed.
+    Io(std::io::Error),
+
+    /// A line outside of a `BEGIN IONS` block that isn't a global parameter or comment.
+    ExpectedBegin(MgfLocation),
+
+    /// The input ended inside a `BEGIN IONS` block.
+    MissingEnd(MgfLocation),
+
+    /// A line in a `BEGIN IONS` block that's neither a parameter nor a peak.
+    InvalidLine(MgfLocation),
+
+    /// A line with leading or trailing whitespace, only an error when strict.
+    UnexpectedWhitespace(MgfLocation),
+
+    /// A peak's m/z isn't a number.
+    InvalidMz(MgfLocation),
+
+    /// A peak's intensity isn't a number.
+    InvalidIntensity(MgfLocation),
+}
+
+/// Where in the input an `MgfError` was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MgfLocation {
+    /// The line number, counting from 1.
+    pub line: usize,
+
+    /// The byte offset of the start of the line.
+    pub offset: u64,
+
+    /// The spectrum being read, counting from 1, or the next one for errors between spectra.
+    pub spectrum: usize,
+
+    /// The offending line, or just the value of an invalid m/z or intensity.
+    pub text: String,
+}
+
+impl MgfError {
+    /// Returns where the error was found, unless it's an `Io` error.
+    pub fn location(&self) -> Option<&MgfLocation> {
+        match self {
+            MgfError::Io(_) => None,
+            MgfError::ExpectedBegin(location)
+            | MgfError::MissingEnd(location)
+            | MgfError::InvalidLine(location)
+            | MgfError::UnexpectedWhitespace(location)
+            | MgfError::InvalidMz(location)
+            | MgfError::InvalidIntensity(location) => Some(location),
+        }
+    }
+}
+
+impl fmt::Display for MgfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            MgfError::Io(e) => return write!(f, "io error reading MGF: {}", e),
+            MgfError::ExpectedBegin(_) => "expected 'BEGIN IONS'",
+            MgfError::MissingEnd(_) => "expected 'END IONS' before the end of the input",
+            MgfError::InvalidLine(_) => "invalid line",
+            MgfError::UnexpectedWhitespace(_) => "unexpected whitespace",
+            MgfError::InvalidMz(_) => "invalid m/z",
+            MgfError::InvalidIntensity(_) => "invalid intensity",
+        };
+        write!(f, "{}", message)?;
+
+        if let Some(location) = self.location() {
+            if !location.text.is_empty() {
+                write!(f, " {:?}", location.text)?;
+            }
+            write!(
+                f,
+                " on line {} at byte {} in spectrum {}",
+                location.line, location.offset, location.spectrum
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for MgfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MgfError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MgfError {
+    fn from(e: std::io::Error) -> Self {
+        MgfError::Io(e)
+    }
+}
+
+impl From<MgfError> for std::io::Error {
+    fn from(e: MgfError) -> Self {
+        match e {
+            MgfError::Io(e) => e,
+            e @ MgfError::MissingEnd(_) => {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e)
+            }
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// The line, offset and spectrum of a line that was read.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    line: usize,
+    offset: u64,
+    spectrum: usize,
+}
+
+impl Position {
+    fn locate(self, text: &str) -> MgfLocation {
+        MgfLocation {
+            line: self.line,
+            offset: self.offset,
+            spectrum: self.spectrum,
+            text: text.trim_end_matches(['\r', '\n']).to_string(),
+        }
+    }
+}
+
+/// The unparsed peaks of a lazily read MGF record, with where each was read.
+#[derive(Debug, Default)]
+pub(crate) struct MgfPeaks {
+    /// The tab separated m/z and intensity of each peak, one per line.
+    lines: String,
+    positions: Vec<Position>,
+}
+
+impl MgfPeaks {
+    fn push(&mut self, mz: &str, intensity: &str, position: Position) {
+        self.lines.push_str(mz);
+        self.lines.push('\t');
+        self.lines.push_str(intensity);
+        self.lines.push('\n');
+        self.positions.push(position);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Parses the peaks into m/z and intensity vectors.
+    pub(crate) fn parse(&self) -> Result<(Vec<f64>, Vec<f64>), MgfError> {
+        self.lines
+            .lines()
+            .zip(self.positions.iter())
+            .map(|(line, position)| {
+                let (mz, intensity) = line.split_once('\t').unwrap_or((line, ""));
+                parse_peak(mz, intensity, *position)
+            })
+            .collect()
+    }
+}
+
+/// Reads spectra from MGF.
+///
+/// By default the reader is tolerant of the variations common in MGF written by other tools:
+/// Windows line endings and trailing whitespace, `#`, `;` and `!` comment lines, peaks separated
+/// by spaces rather than tabs, and extra peak columns such as a charge or annotation, which are
+/// ignored. `with_strict` turns these into errors, for validating MGF files.
+///
+/// Global parameters before the first `BEGIN IONS` are read into an `MGFHeader`, see `header`.
 #[derive(Debug)]
 pub struct MGFReader<A> {
     reader: A,
+    strict: bool,
+    header: MGFHeader,
+    header_read: bool,
+    inherit_header: bool,
+    skip_malformed: bool,
+    skipped: Vec<MgfError>,
+    /// Set between a `BEGIN IONS` and its `END IONS`.
+    in_block: bool,
+    n_spectra: usize,
+    line_number: usize,
+    line_offset: u64,
+    next_offset: u64,
+}
+
+impl<A> MGFReader<A> {
+    fn from_buf(reader: A) -> Self {
+        Self {
+            reader,
+            strict: false,
+            header: MGFHeader::new(),
+            header_read: false,
+            inherit_header: false,
+            skip_malformed: false,
+            skipped: Vec::new(),
+            in_block: false,
+            n_spectra: 0,
+            line_number: 0,
+            line_offset: 0,
+            next_offset: 0,
+        }
+    }
 }
 
 impl<R> MGFReader<std::io::BufReader<R>>
@@ -22,9 +326,19 @@ where
     /// * `reader` - An object that implements the Read trait
     ///
     pub fn new(reader: R) -> Self {
-        Self {
-            reader: std::io::BufReader::new(reader),
-        }
+        Self::from_buf(std::io::BufReader::new(reader))
+    }
+}
+
+impl MGFReader<Box<dyn std::io::BufRead>> {
+    /// Open the MGF file at `path`, decompressing it if it's compressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the possibly compressed MGF file.
+    ///
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::from_buf(compression::open(path)?))
     }
 }
 
@@ -32,97 +346,382 @@ impl<R> MGFReader<R>
 where
     R: std::io::BufRead,
 {
+    /// Only accept MGF with `\n` line endings, no comments or surrounding whitespace, and peaks
+    /// of exactly a tab separated m/z and intensity.
+    ///
+    /// # Arguments
+    ///
+    /// * `strict` - Whether to reject MGF that isn't in this exact form.
+    ///
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Add the header's global parameters to each spectrum's metadata, unless the spectrum sets
+    /// the key itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `inherit_header` - Whether spectra inherit the header's parameters.
+    ///
+    pub fn with_inherit_header(mut self, inherit_header: bool) -> Self {
+        self.inherit_header = inherit_header;
+        self
+    }
+
+    /// Skip malformed spectra and keep reading, rather than returning an error. The errors are
+    /// collected in `skipped`. Lazily read peaks aren't parsed until
+    /// `LazySpectrum::into_spectrum`, so a bad m/z or intensity is only skipped when reading
+    /// spectra.
+    ///
+    /// # Arguments
+    ///
+    /// * `skip_malformed` - Whether to skip malformed spectra.
+    ///
+    /// ```
+    /// use msn_kit::io::mgf_parser::{MGFReader, MgfError};
+    ///
+    /// let mgf = b"BEGIN IONS\nTITLE=1\n1,234.5\t10\nEND IONS\nBEGIN IONS\nTITLE=2\nEND IONS\n";
+    /// let mut reader = MGFReader::new(&mgf[..]).with_skip_malformed(true);
+    ///
+    /// let mut spectrum = msn_kit::spectrum::Spectrum::empty();
+    /// reader.read(&mut spectrum).unwrap();
+    /// assert_eq!(spectrum.metadata["TITLE"], "2");
+    ///
+    /// match &reader.skipped()[0] {
+    ///     MgfError::InvalidMz(location) => {
+    ///         assert_eq!((location.line, location.spectrum), (3, 1));
+    ///         assert_eq!(location.text, "1,234.5");
+    ///     }
+    ///     e => panic!("unexpected error {}", e),
+    /// }
+    /// ```
+    pub fn with_skip_malformed(mut self, skip_malformed: bool) -> Self {
+        self.skip_malformed = skip_malformed;
+        self
+    }
+
+    /// Returns the errors of the spectra skipped so far, see `with_skip_malformed`.
+    pub fn skipped(&self) -> &[MgfError] {
+        &self.skipped
+    }
+
+    /// Returns the global parameters before the first `BEGIN IONS`, reading them if no record
+    /// has been read yet.
+    ///
+    /// ```
+    /// use msn_kit::io::mgf_parser::MGFReader;
+    ///
+    /// let mgf = b"MASS=Monoisotopic\nCHARGE=2+ and 3+\nBEGIN IONS\nTITLE=1\nEND IONS\n";
+    /// let mut reader = MGFReader::new(&mgf[..]);
+    ///
+    /// assert_eq!(reader.header().unwrap().get("CHARGE"), Some("2+ and 3+"));
+    /// ```
+    pub fn header(&mut self) -> Result<&MGFHeader, MgfError> {
+        let mut line = String::new();
+
+        while !self.header_read {
+            if !self.next_line(&mut line)? {
+                self.header_read = true;
+                break;
+            }
+
+            let content = match self.content(&line) {
+                Some(l) => l,
+                None => continue,
+            };
+
+            if self.is_marker(content, "BEGIN IONS") {
+                self.header_read = true;
+                self.begin();
+                break;
+            }
+
+            match content.split_once('=') {
+                Some((k, v)) if !self.strict => self.header.append(k.trim(), v.trim()),
+                Some((k, v)) if content.trim() == content => self.header.append(k, v),
+                _ => return Err(MgfError::ExpectedBegin(self.location(&line))),
+            }
+        }
+
+        Ok(&self.header)
+    }
+
     pub fn spectra(self) -> Records<R> {
         Records { reader: self }
     }
 
-    /// Read from the underlying reader into spectrum.
+    /// Returns an iterator over the spectra that leaves the peaks unparsed until
+    /// `LazySpectrum::into_spectrum` is called, for when only the metadata is needed.
+    pub fn lazy_spectra(self) -> LazyRecords<R> {
+        LazyRecords { reader: self }
+    }
+
+    /// Read from the underlying reader into spectrum. Returns `false` at the end of the input,
+    /// so an empty record can be told apart from there being no record.
     ///
     /// # Arguments
     ///
     /// * `s` - A spectrum object that will hold the new spectrum data.
     ///
-    pub fn read(&mut self, s: &mut Spectrum) -> std::io::Result<()> {
-        let mut line = String::new();
+    pub fn read(&mut self, s: &mut Spectrum) -> Result<bool, MgfError> {
+        self.skipping(|reader| {
+            s.metadata.clear();
+            s.mz.clear();
+            s.intensities.clear();
 
-        s.metadata.clear();
-        s.mz.clear();
-        s.intensities.clear();
+            reader.read_record(&mut s.metadata, |mz, intensity, position| {
+                let (mz, intensity) = parse_peak(mz, intensity, position)?;
+                s.mz.push(mz);
+                s.intensities.push(intensity);
+                Ok(())
+            })
+        })
+    }
 
-        self.reader.read_line(&mut line)?;
+    /// Read the next record's metadata, keeping its peak lines unparsed. Returns `None` at the
+    /// end of the input.
+    pub fn read_lazy(&mut self) -> Result<Option<LazySpectrum>, MgfError> {
+        self.skipping(|reader| {
+            let mut metadata = Metadata::new();
+            let mut peaks = MgfPeaks::default();
 
-        if line.is_empty() {
-            return Ok(());
-        };
+            let found = reader.read_record(&mut metadata, |mz, intensity, position| {
+                peaks.push(mz, intensity, position);
+                Ok(())
+            })?;
 
+            Ok(found.then(|| LazySpectrum::from_mgf(metadata, peaks)))
+        })
+    }
+
+    /// Calls `read` until it reads a record, skipping the malformed ones if `skip_malformed`.
+    fn skipping<T, F>(&mut self, mut read: F) -> Result<T, MgfError>
+    where
+        F: FnMut(&mut Self) -> Result<T, MgfError>,
+    {
         loop {
-            if line == "\n" {
-                line.clear();
-                self.reader.read_line(&mut line)?;
-                continue;
+            match read(self) {
+                Err(e @ MgfError::Io(_)) => return Err(e),
+                Err(e) if self.skip_malformed => {
+                    self.recover()?;
+                    self.skipped.push(e);
+                }
+                result => return result,
             }
+        }
+    }
 
-            if line.is_empty() {
-                return Ok(());
-            }
+    /// Skips the rest of the block an error was found in, up to its `END IONS` or the next
+    /// `BEGIN IONS`.
+    fn recover(&mut self) -> Result<(), MgfError> {
+        let mut line = String::new();
 
-            if line == "BEGIN IONS\n" {
-                line.clear();
+        while self.in_block {
+            if !self.next_line(&mut line)? {
+                self.in_block = false;
                 break;
             }
 
-            if line != "BEGIN IONS\n" {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    format!("Expected 'BEGIN IONS' to start, got {}", line),
-                ));
+            match self.content(&line) {
+                Some(l) if self.is_marker(l, "END IONS") => self.in_block = false,
+                Some(l) if self.is_marker(l, "BEGIN IONS") => {
+                    self.begin();
+                    break;
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next record into `metadata`, passing the m/z and intensity of each peak line to
+    /// `peak`. Returns false if the input ended before another record started.
+    fn read_record<F>(&mut self, metadata: &mut Metadata, mut peak: F) -> Result<bool, MgfError>
+    where
+        F: FnMut(&str, &str, Position) -> Result<(), MgfError>,
+    {
+        self.header()?;
+
+        let mut line = String::new();
+
+        while !self.in_block {
+            if !self.next_line(&mut line)? {
+                return Ok(false);
+            }
+
+            match self.content(&line) {
+                None => continue,
+                Some(l) if self.is_marker(l, "BEGIN IONS") => self.begin(),
+                Some(_) => return Err(MgfError::ExpectedBegin(self.location(&line))),
             }
         }
 
         loop {
-            self.reader.read_line(&mut line)?;
+            if !self.next_line(&mut line)? {
+                let location = self.location("");
+                self.in_block = false;
+                return Err(MgfError::MissingEnd(location));
+            }
 
-            if line == "END IONS\n" {
+            let content = match self.content(&line) {
+                Some(l) => l,
+                None if self.strict => return Err(MgfError::InvalidLine(self.location(&line))),
+                None => continue,
+            };
+
+            if self.is_marker(content, "END IONS") {
+                self.in_block = false;
                 break;
             }
 
-            if line.contains('=') {
-                if let Some((k, v)) = line.trim().split_once("=") {
-                    s.metadata.insert(String::from(k), String::from(v));
+            if self.strict {
+                if content.trim() != content {
+                    return Err(MgfError::UnexpectedWhitespace(self.location(&line)));
+                } else if let Some((k, v)) = content.split_once('=') {
+                    metadata.append(k, v);
+                } else if let Some((mz, intensity)) = content.split_once('\t') {
+                    peak(mz, intensity, self.position())?;
                 } else {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "Could parse key value metadata.",
-                    ));
+                    return Err(MgfError::InvalidLine(self.location(&line)));
                 }
-                line.clear();
-            } else if line.contains('\t') {
-                if let Some((raw_mz, raw_intensity)) = line.trim().split_once("\t") {
-                    let new_mz: f64 = raw_mz.parse().unwrap();
-                    let new_intensity: f64 = raw_intensity.parse().unwrap();
-
-                    s.mz.push(new_mz);
-                    s.intensities.push(new_intensity);
-                } else {
-                    return Err(Error::new(ErrorKind::Other, "Vectors"));
+            } else if content.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+                let mut columns = content.split_whitespace();
+                match (columns.next(), columns.next()) {
+                    (Some(mz), Some(intensity)) => peak(mz, intensity, self.position())?,
+                    _ => return Err(MgfError::InvalidLine(self.location(&line))),
                 }
-                line.clear();
+            } else if let Some((k, v)) = content.split_once('=') {
+                metadata.append(k.trim(), v.trim());
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Error parsing data: {}", line),
-                ));
+                return Err(MgfError::InvalidLine(self.location(&line)));
             }
         }
 
-        Ok(())
+        if self.inherit_header {
+            // Keys are checked before any are added, so every value of a repeated key is inherited.
+            let inherited: Vec<(&str, &str)> = self
+                .header
+                .iter()
+                .filter(|(k, _)| !metadata.contains_key(k))
+                .collect();
+            for (k, v) in inherited {
+                metadata.append(k, v);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reads the next line into `line`, returning false at the end of the input.
+    fn next_line(&mut self, line: &mut String) -> Result<bool, MgfError> {
+        line.clear();
+        let n = self.reader.read_line(line)?;
+
+        if n > 0 {
+            self.line_number += 1;
+            self.line_offset = self.next_offset;
+            self.next_offset += n as u64;
+        }
+
+        Ok(n > 0)
+    }
+
+    /// Starts a record after its `BEGIN IONS`.
+    fn begin(&mut self) {
+        self.in_block = true;
+        self.n_spectra += 1;
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            line: self.line_number,
+            offset: self.line_offset,
+            spectrum: self.n_spectra + usize::from(!self.in_block),
+        }
+    }
+
+    fn location(&self, line: &str) -> MgfLocation {
+        self.position().locate(line)
+    }
+
+    /// Returns the content of `line`, or `None` if it's blank or, when not strict, a comment.
+    fn content<'l>(&self, line: &'l str) -> Option<&'l str> {
+        if self.strict {
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            return (!content.is_empty()).then_some(content);
+        }
+
+        let content = line.trim();
+        let comment = content.starts_with(['#', ';', '!']);
+        (!content.is_empty() && !comment).then_some(content)
+    }
+
+    /// Checks if `line` is the `BEGIN IONS` or `END IONS` marker, ignoring case unless strict.
+    fn is_marker(&self, line: &str, marker: &str) -> bool {
+        if self.strict {
+            line == marker
+        } else {
+            line.eq_ignore_ascii_case(marker)
+        }
+    }
+}
+
+impl<R> SpectrumReader for MGFReader<R>
+where
+    R: std::io::BufRead,
+{
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        let mut spectrum = Spectrum::empty();
+        let found = self.read(&mut spectrum)?;
+
+        Ok(found.then_some(spectrum))
+    }
+
+    fn next_lazy_spectrum(&mut self) -> std::io::Result<Option<LazySpectrum>> {
+        Ok(self.read_lazy()?)
     }
 }
 
+/// Parses an m/z and intensity column.
+fn parse_peak(
+    raw_mz: &str,
+    raw_intensity: &str,
+    position: Position,
+) -> Result<(f64, f64), MgfError> {
+    let mz = raw_mz
+        .parse()
+        .map_err(|_| MgfError::InvalidMz(position.locate(raw_mz)))?;
+    let intensity = raw_intensity
+        .parse()
+        .map_err(|_| MgfError::InvalidIntensity(position.locate(raw_intensity)))?;
+
+    Ok((mz, intensity))
+}
+
+/// A canonical order for MGF metadata keys, for `MGFWriter::with_key_order`.
+pub const CANONICAL_KEY_ORDER: [&str; 6] = [
+    "TITLE",
+    "PEPMASS",
+    "CHARGE",
+    "RTINSECONDS",
+    "SCANS",
+    "MSLEVEL",
+];
+
+/// Writes spectra as MGF.
+///
+/// Metadata is written in the spectrum's order and values are written as they are, so reading
+/// and writing an MGF file gives back the same bytes, unless `with_key_order` or
+/// `with_canonical_values` is given.
 #[derive(Debug)]
 pub struct MGFWriter<W: Write> {
     writer: std::io::BufWriter<W>,
-    output_format: Format,
+    header: Option<MGFHeader>,
+    key_order: Vec<String>,
+    canonical_values: bool,
 }
 
 impl<W: Write> MGFWriter<W> {
@@ -132,47 +731,91 @@ impl<W: Write> MGFWriter<W> {
     ///
     /// * `writer` - An object that can be written two.
     ///
-    pub fn new(writer: W, output_format: Format) -> Self {
+    pub fn new(writer: W) -> Self {
         MGFWriter {
             writer: std::io::BufWriter::new(writer),
-            output_format,
+            header: None,
+            key_order: Vec::new(),
+            canonical_values: false,
         }
     }
 
-    /// Write spectrum to the underlying buffer in the format.
+    /// Write the metadata keys in `keys` first, in that order, and then any other keys in the
+    /// spectrum's order.
     ///
     /// # Arguments
     ///
-    /// * `spectrum` - The spectrum to write.
+    /// * `keys` - The keys to write first, e.g. `CANONICAL_KEY_ORDER`.
     ///
-    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
-        match &self.output_format {
-            Format::Mgf => self.write_mgf(spectrum),
-            Format::Json => self.write_json(spectrum),
-            e => Err(Error::new(
-                ErrorKind::Other,
-                format!("Cannot parse, got output: {:?}", e),
-            )),
-        }
+    /// ```
+    /// use msn_kit::io::mgf_parser::{MGFWriter, CANONICAL_KEY_ORDER};
+    /// use msn_kit::metadata::Metadata;
+    /// use msn_kit::spectrum::Spectrum;
+    ///
+    /// let metadata: Metadata = [("SOURCE", "a"), ("PEPMASS", "445.3"), ("TITLE", "1")]
+    ///     .into_iter()
+    ///     .collect();
+    ///
+    /// let mut writer = MGFWriter::new(Vec::new()).with_key_order(&CANONICAL_KEY_ORDER);
+    /// writer.write(Spectrum::new(metadata, vec![], vec![])).unwrap();
+    ///
+    /// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    /// assert_eq!(output, "BEGIN IONS\nTITLE=1\nPEPMASS=445.3\nSOURCE=a\nEND IONS\n");
+    /// ```
+    pub fn with_key_order(mut self, keys: &[&str]) -> Self {
+        self.key_order = keys.iter().map(|k| k.to_string()).collect();
+        self
     }
 
-    /// Write spectrum to the underlying buffer in mgf format.
+    /// Write `PEPMASS`, `CHARGE`, `RTINSECONDS`, `SCANS` and `MSLEVEL` values in a canonical form,
+    /// e.g. `CHARGE=2+,3+` as `CHARGE=2+ and 3+`. Values that can't be parsed, and other keys,
+    /// are written as they are.
     ///
     /// # Arguments
     ///
-    /// * `spectrum` - The spectrum to write.
+    /// * `canonical_values` - Whether to rewrite the standard values canonically.
+    ///
+    /// ```
+    /// use msn_kit::io::mgf_parser::MGFWriter;
+    /// use msn_kit::metadata::Metadata;
+    /// use msn_kit::spectrum::Spectrum;
+    ///
+    /// let metadata: Metadata = [("PEPMASS", "898.7270\t12345.60")].into_iter().collect();
     ///
-    pub fn write_json(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
-        let result = serde_json::to_writer(&mut self.writer, &spectrum);
-        self.writer.write_all(b"\n")?;
+    /// let mut writer = MGFWriter::new(Vec::new()).with_canonical_values(true);
+    /// writer.write(Spectrum::new(metadata, vec![], vec![])).unwrap();
+    ///
+    /// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    /// assert_eq!(output, "BEGIN IONS\nPEPMASS=898.727 12345.6\nEND IONS\n");
+    /// ```
+    pub fn with_canonical_values(mut self, canonical_values: bool) -> Self {
+        self.canonical_values = canonical_values;
+        self
+    }
+
+    /// Write `header`'s global parameters before the first spectrum.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The global parameters, e.g. from `MGFReader::header`.
+    ///
+    pub fn with_header(mut self, header: MGFHeader) -> Self {
+        self.header = Some(header);
+        self
+    }
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(_) => Err(Error::new(
-                ErrorKind::Other,
-                "Error writing json for spectrum.",
-            )),
+    /// Writes the header, if it hasn't been written yet.
+    fn write_header(&mut self) -> std::io::Result<()> {
+        if let Some(header) = self.header.take() {
+            for (k, v) in header.iter() {
+                self.writer.write_all(format!("{}={}\n", k, v).as_bytes())?;
+            }
+            if !header.is_empty() {
+                self.writer.write_all(b"\n")?;
+            }
         }
+
+        Ok(())
     }
 
     /// Write spectrum to the underlying buffer in mgf format.
@@ -181,11 +824,25 @@ impl<W: Write> MGFWriter<W> {
     ///
     /// * `spectrum` - The spectrum to write.
     ///
-    pub fn write_mgf(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        self.write_header()?;
         self.writer.write_all(b"BEGIN IONS\n")?;
 
-        for (k, v) in spectrum.metadata.iter() {
-            let metadata = format!("{}={}\n", k, v);
+        let ordered = self
+            .key_order
+            .iter()
+            .flat_map(|k| spectrum.metadata.get_all(k).map(move |v| (k, v)));
+        let rest = spectrum
+            .metadata
+            .iter()
+            .filter(|(k, _)| !self.key_order.contains(k));
+
+        for (k, v) in ordered.chain(rest) {
+            let canonical = match self.canonical_values {
+                true => metadata::canonical(k, v),
+                false => None,
+            };
+            let metadata = format!("{}={}\n", k, canonical.as_ref().unwrap_or(v));
             self.writer.write_all(metadata.as_bytes())?;
         }
 
@@ -201,6 +858,23 @@ impl<W: Write> MGFWriter<W> {
 
         Ok(())
     }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(mut self) -> std::io::Result<W> {
+        self.write_header()?;
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> SpectrumWriter for MGFWriter<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        MGFWriter::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.write_header()?;
+        self.writer.flush()
+    }
 }
 
 pub struct Records<R>
@@ -214,21 +888,38 @@ impl<R> Iterator for Records<R>
 where
     R: std::io::BufRead,
 {
-    type Item = std::io::Result<Spectrum>;
+    type Item = Result<Spectrum, MgfError>;
 
     /// Implements the next ethod for the Records iterator.
     fn next(&mut self) -> Option<Self::Item> {
         let mut record = Spectrum::empty();
 
-        let resp = self.reader.read(&mut record);
-        match resp {
-            Ok(()) if record.is_empty() => None,
-            Ok(()) => Some(Ok(record)),
-            Err(e) => Some(Err(std::io::Error::new(std::io::ErrorKind::Other, e))),
+        match self.reader.read(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
         }
     }
 }
 
+pub struct LazyRecords<R>
+where
+    R: std::io::BufRead,
+{
+    reader: MGFReader<R>,
+}
+
+impl<R> Iterator for LazyRecords<R>
+where
+    R: std::io::BufRead,
+{
+    type Item = Result<LazySpectrum, MgfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_lazy().transpose()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,4 +977,401 @@ END IONS
 
         assert_eq!(test_s, filter_s);
     }
+
+    #[test]
+    fn test_lazy_reader() {
+        let test_s: Vec<Spectrum> = serde_json::from_str(SPECTRUM_SIMPLE).unwrap();
+
+        let lazy: Vec<LazySpectrum> = MGFReader::new(MGF_FILE_SIMPLE)
+            .lazy_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+
+        let n_peaks: Vec<usize> = lazy.iter().map(|s| s.n_peaks()).collect();
+        assert_eq!(n_peaks, vec![2, 0, 1]);
+
+        let spectra: Vec<Spectrum> = lazy
+            .into_iter()
+            .map(|s| s.into_spectrum().unwrap())
+            .collect();
+        assert_eq!(test_s, spectra);
+    }
+
+    #[test]
+    fn test_empty_record() {
+        let mgf = b"BEGIN IONS\nEND IONS\nBEGIN IONS\nTITLE=2\n1 1\nEND IONS\n";
+
+        let spectra: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), 2);
+        assert!(spectra[0].is_empty());
+        assert_eq!(spectra[1].metadata["TITLE"], "2");
+
+        let lazy = MGFReader::new(&mgf[..]).lazy_spectra().count();
+        let boxed = MGFReader::new(&mgf[..]).into_spectra().count();
+        assert_eq!((lazy, boxed), (2, 2));
+    }
+
+    #[test]
+    fn test_bad_peak() {
+        let mgf = b"BEGIN IONS\nTITLE=1\n13.0\tabc\nEND IONS\n";
+
+        let lazy = MGFReader::new(&mgf[..])
+            .lazy_spectra()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(lazy.metadata["TITLE"], "1");
+        assert!(lazy.into_spectrum().is_err());
+
+        assert!(MGFReader::new(&mgf[..]).spectra().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_tolerant_reader() {
+        let mgf = b"# written by another tool\r
+BEGIN IONS\r
+; a comment\r
+TITLE = first \r
+PEPMASS=898.727\r
+13.00 1.0\r
+14.00\t1.0\t2+\r
+15.00   2.0 \"y1\"\r
+\r
+END IONS  \r
+begin ions
+SCANS=2
+! another comment
+13.00 1.0
+end ions";
+
+        let spectra: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .spectra()
+            .map(|s| s.unwrap())
+            .collect();
+
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].metadata["TITLE"], "first");
+        assert_eq!(spectra[0].metadata["PEPMASS"], "898.727");
+        assert_eq!(spectra[0].mz, vec![13.0, 14.0, 15.0]);
+        assert_eq!(spectra[0].intensities, vec![1.0, 1.0, 2.0]);
+        assert_eq!(spectra[1].metadata["SCANS"], "2");
+
+        let lazy: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .lazy_spectra()
+            .map(|s| s.unwrap().into_spectrum().unwrap())
+            .collect();
+        assert_eq!(lazy, spectra);
+    }
+
+    #[test]
+    fn test_strict_reader() {
+        let strict: Vec<Spectrum> = MGFReader::new(MGF_FILE_SIMPLE)
+            .with_strict(true)
+            .spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(strict.len(), 3);
+
+        for mgf in [
+            &b"BEGIN IONS\r\nTITLE=1\r\nEND IONS\r\n"[..],
+            b"BEGIN IONS\n# comment\nTITLE=1\nEND IONS\n",
+            b"BEGIN IONS\nTITLE=1 \nEND IONS\n",
+            b"BEGIN IONS\n13.0 1.0\nEND IONS\n",
+            b"BEGIN IONS\n13.0\t1.0\t2+\nEND IONS\n",
+            b"BEGIN IONS\n\nTITLE=1\nEND IONS\n",
+        ] {
+            assert!(MGFReader::new(mgf).spectra().next().unwrap().is_ok());
+            assert!(MGFReader::new(mgf)
+                .with_strict(true)
+                .spectra()
+                .next()
+                .unwrap()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mgf = b"COM=global parameters
+CHARGE=2+ and 3+
+MASS=Monoisotopic
+COM=second comment
+
+BEGIN IONS
+TITLE=1
+CHARGE=1+
+13.0\t1.0
+END IONS
+BEGIN IONS
+TITLE=2
+END IONS
+";
+
+        let mut reader = MGFReader::new(&mgf[..]);
+        let header = reader.header().unwrap().clone();
+        assert_eq!(header.len(), 4);
+        assert_eq!(header.get("MASS"), Some("Monoisotopic"));
+        let comments: Vec<&str> = header.get_all("COM").collect();
+        assert_eq!(comments, vec!["global parameters", "second comment"]);
+
+        let spectra: Vec<Spectrum> = reader.spectra().map(|s| s.unwrap()).collect();
+        assert_eq!(spectra.len(), 2);
+        assert!(!spectra[1].metadata.contains_key("CHARGE"));
+
+        let inherited: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .with_inherit_header(true)
+            .lazy_spectra()
+            .map(|s| s.unwrap().into_spectrum().unwrap())
+            .collect();
+        assert_eq!(inherited[0].metadata["CHARGE"], "1+");
+        assert_eq!(inherited[1].metadata["CHARGE"], "2+ and 3+");
+        assert_eq!(inherited[1].metadata["MASS"], "Monoisotopic");
+        let comments: Vec<&String> = inherited[1].metadata.get_all("COM").collect();
+        assert_eq!(comments, vec!["global parameters", "second comment"]);
+
+        let mut writer = MGFWriter::new(Vec::new()).with_header(header.clone());
+        for s in MGFReader::new(&mgf[..]).spectra() {
+            writer.write(s.unwrap()).unwrap();
+        }
+        let written = writer.into_inner().unwrap();
+        assert!(written.starts_with(
+            b"COM=global parameters\nCHARGE=2+ and 3+\nMASS=Monoisotopic\nCOM=second comment\n"
+        ));
+
+        let mut reader = MGFReader::new(&written[..]);
+        assert_eq!(reader.header().unwrap(), &header);
+        let read: Vec<Spectrum> = reader.spectra().map(|s| s.unwrap()).collect();
+        assert_eq!(read, spectra);
+    }
+
+    #[test]
+    fn test_header_only_before_first_record() {
+        let mgf = b"BEGIN IONS\nTITLE=1\nEND IONS\nCHARGE=2+\nBEGIN IONS\nTITLE=2\nEND IONS\n";
+
+        let mut spectra = MGFReader::new(&mgf[..]).spectra();
+        assert!(spectra.next().unwrap().is_ok());
+        assert!(spectra.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_error_location() {
+        let mgf = b"CHARGE=2+\nBEGIN IONS\nTITLE=1\nEND IONS\nBEGIN IONS\nTITLE=2\n13.0\tNaN?\nEND IONS\n";
+
+        let e = MGFReader::new(&mgf[..])
+            .spectra()
+            .nth(1)
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(e, MgfError::InvalidIntensity(_)));
+        assert_eq!(
+            e.location(),
+            Some(&MgfLocation {
+                line: 7,
+                offset: 57,
+                spectrum: 2,
+                text: String::from("NaN?"),
+            })
+        );
+        assert_eq!(
+            e.to_string(),
+            "invalid intensity \"NaN?\" on line 7 at byte 57 in spectrum 2"
+        );
+
+        let lazy = MGFReader::new(&mgf[..])
+            .lazy_spectra()
+            .nth(1)
+            .unwrap()
+            .unwrap();
+        let e = lazy.into_spectrum().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            "invalid intensity \"NaN?\" on line 7 at byte 57 in spectrum 2"
+        );
+
+        let e = MGFReader::new(&b"BEGIN IONS\nTITLE=1\nEND IONS\nTITLE=2\n"[..])
+            .spectra()
+            .nth(1)
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(e, MgfError::ExpectedBegin(_)));
+        assert_eq!(e.location().unwrap().spectrum, 2);
+        assert_eq!(e.location().unwrap().text, "TITLE=2");
+    }
+
+    #[test]
+    fn test_skip_malformed() {
+        let mgf = b"BEGIN IONS
+TITLE=1
+1,234.5\t10
+END IONS
+stray text
+BEGIN IONS
+TITLE=2
+13.0\t1.0
+END IONS
+BEGIN IONS
+TITLE=3
+what is this
+BEGIN IONS
+TITLE=4
+END IONS
+BEGIN IONS
+TITLE=5
+";
+
+        let mut reader = MGFReader::new(&mgf[..]).with_skip_malformed(true);
+        let mut titles = Vec::new();
+        loop {
+            let mut spectrum = Spectrum::empty();
+            if !reader.read(&mut spectrum).unwrap() {
+                break;
+            }
+            titles.push(spectrum.metadata["TITLE"].clone());
+        }
+        assert_eq!(titles, vec!["2", "4"]);
+
+        let skipped: Vec<(usize, usize)> = reader
+            .skipped()
+            .iter()
+            .map(|e| {
+                let location = e.location().unwrap();
+                (location.line, location.spectrum)
+            })
+            .collect();
+        assert_eq!(skipped, vec![(3, 1), (5, 2), (12, 3), (17, 5)]);
+        assert!(matches!(reader.skipped()[3], MgfError::MissingEnd(_)));
+
+        let lazy: Vec<LazySpectrum> = MGFReader::new(&mgf[..])
+            .with_skip_malformed(true)
+            .lazy_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(lazy.len(), 3);
+
+        let spectra: Vec<Spectrum> = MGFReader::new(&mgf[..])
+            .with_skip_malformed(true)
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), 2);
+
+        assert!(MGFReader::new(&mgf[..])
+            .into_spectra()
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mgf = b"COM=library
+CHARGE=2+ and 3+
+
+BEGIN IONS
+TITLE=scan=1
+SCANS=1
+PEPMASS=445.34 1200
+COMMENT=first
+RTINSECONDS=30.5
+COMMENT=second
+CHARGE=2+,3+
+100.5\t10
+200\t20.25
+END IONS
+BEGIN IONS
+TITLE=scan=2
+PEPMASS=898.7270\t12345.60
+SCANS=0100-0105
+END IONS
+";
+
+        let mut reader = MGFReader::new(&mgf[..]);
+        let mut writer = MGFWriter::new(Vec::new()).with_header(reader.header().unwrap().clone());
+        for s in reader.spectra() {
+            writer.write(s.unwrap()).unwrap();
+        }
+        assert_eq!(writer.into_inner().unwrap(), mgf);
+
+        let spectrum = MGFReader::new(&mgf[..]).spectra().next().unwrap().unwrap();
+        let comments: Vec<&String> = spectrum.metadata.get_all("COMMENT").collect();
+        assert_eq!(comments, vec!["first", "second"]);
+
+        let mut writer = MGFWriter::new(Vec::new()).with_key_order(&CANONICAL_KEY_ORDER);
+        writer.write(spectrum).unwrap();
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let keys: Vec<&str> = written
+            .lines()
+            .filter_map(|l| l.split_once('=').map(|(k, _)| k))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "TITLE",
+                "PEPMASS",
+                "CHARGE",
+                "RTINSECONDS",
+                "SCANS",
+                "COMMENT",
+                "COMMENT"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_canonical_values() {
+        let mgf = b"BEGIN IONS
+TITLE=scan 100
+PEPMASS=898.7270\t12345.60
+CHARGE=2+,3+
+RTINSECONDS=10.50-12
+SCANS=0100-0105
+MSLEVEL=02
+SOURCE=2+,3+
+END IONS
+BEGIN IONS
+CHARGE=two
+END IONS
+";
+
+        let mut writer = MGFWriter::new(Vec::new()).with_canonical_values(true);
+        for s in MGFReader::new(&mgf[..]).spectra() {
+            writer.write(s.unwrap()).unwrap();
+        }
+
+        let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            written,
+            "BEGIN IONS
+TITLE=scan 100
+PEPMASS=898.727 12345.6
+CHARGE=2+ and 3+
+RTINSECONDS=10.5-12
+SCANS=100-105
+MSLEVEL=2
+SOURCE=2+,3+
+END IONS
+BEGIN IONS
+CHARGE=two
+END IONS
+"
+        );
+    }
+
+    #[test]
+    fn test_missing_end() {
+        let mgf = b"BEGIN IONS\nTITLE=1\n13.0\t1.0\n";
+        let mut spectra = MGFReader::new(&mgf[..]).spectra();
+        assert!(matches!(
+            spectra.next().unwrap(),
+            Err(MgfError::MissingEnd(_))
+        ));
+        assert!(spectra.next().is_none());
+
+        let e = MGFReader::new(&mgf[..]).next_spectrum().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
 }
@@ -2,11 +2,20 @@
 // All Rights Reserved
 //! Module containing input and output related functionality.
 
+pub mod compression;
+pub mod json_parser;
 pub mod mgf_parser;
+pub mod ms2_parser;
+pub mod msp_parser;
 pub mod mzml_parser;
+pub mod mzxml_parser;
 
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::path::Path;
 use std::str::FromStr;
 
+use crate::spectrum::{LazySpectrum, Spectrum};
+
 /// Types of formats that can be read or written.
 ///
 /// # Examples
@@ -20,7 +29,7 @@ use std::str::FromStr;
 /// let f = Format::from_str("mgf").unwrap();
 /// assert_eq!(f, Format::Mgf);
 /// ```
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Format {
     /// json newline format
     Json,
@@ -30,6 +39,127 @@ pub enum Format {
 
     /// mzML format specified here: <https://www.psidev.info/mzML>
     MzML,
+
+    /// mzXML, the predecessor of mzML
+    MzXML,
+
+    /// NIST MSP spectral library format
+    Msp,
+
+    /// MS1/MS2 text format used by Sequest and Crux
+    Ms2,
+}
+
+impl Format {
+    /// Returns the format implied by the extension of `path`, if it's a known one. A
+    /// compression extension such as `.gz` is looked past.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use msn_kit::io::Format;
+    ///
+    /// assert_eq!(Format::from_path(Path::new("run.mzML")), Some(Format::MzML));
+    /// assert_eq!(Format::from_path(Path::new("run.mgf.gz")), Some(Format::Mgf));
+    /// assert_eq!(Format::from_path(Path::new("run.txt")), None);
+    /// ```
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let mut extension = path.extension()?.to_str()?.to_lowercase();
+        if compression::Compression::from_extension(&extension).is_some() {
+            extension = Path::new(path.file_stem()?)
+                .extension()?
+                .to_str()?
+                .to_lowercase();
+        }
+
+        match extension.as_str() {
+            "json" | "jsonl" => Some(Self::Json),
+            "mgf" => Some(Self::Mgf),
+            "mzml" => Some(Self::MzML),
+            "mzxml" => Some(Self::MzXML),
+            "msp" => Some(Self::Msp),
+            "ms1" | "ms2" => Some(Self::Ms2),
+            _ => None,
+        }
+    }
+
+    /// Returns the format of the data starting with `bytes`, if it's recognized. Leading
+    /// whitespace, a byte order mark and MGF comment or global parameter lines are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use msn_kit::io::Format;
+    ///
+    /// assert_eq!(Format::sniff(b"BEGIN IONS\nTITLE=1\n"), Some(Format::Mgf));
+    /// assert_eq!(Format::sniff(b"<?xml version=\"1.0\"?>\n<mzML>"), Some(Format::MzML));
+    /// assert_eq!(Format::sniff(b"H\tCreationDate\t2021\n"), Some(Format::Ms2));
+    /// assert_eq!(Format::sniff(b"m/z,intensity\n"), None);
+    /// ```
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+
+        for line in bytes.split(|b| *b == b'\n') {
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with(b"<") {
+                return sniff_xml(bytes);
+            }
+            if line.starts_with(b"{") {
+                return Some(Self::Json);
+            }
+            if line.eq_ignore_ascii_case(b"BEGIN IONS") {
+                return Some(Self::Mgf);
+            }
+            if line.len() >= 5 && line[..5].eq_ignore_ascii_case(b"NAME:") {
+                return Some(Self::Msp);
+            }
+            if line.len() >= 2 && b"HS".contains(&line[0]) && line[1].is_ascii_whitespace() {
+                return Some(Self::Ms2);
+            }
+
+            // MGF files can start with comments and global parameters before the first record.
+            let mgf_header = b"#;!/".contains(&line[0])
+                || line.iter().position(|b| *b == b'=').is_some_and(|i| {
+                    i > 0
+                        && line[..i]
+                            .iter()
+                            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || *b == b'_')
+                });
+            if !mgf_header {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Returns the format of `reader`'s input from its buffered bytes, without consuming them.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The input, already decompressed.
+    ///
+    pub fn sniff_reader<R: BufRead + ?Sized>(reader: &mut R) -> std::io::Result<Option<Self>> {
+        Ok(Self::sniff(reader.fill_buf()?))
+    }
+}
+
+/// Returns the format of an xml document from the name of its root element.
+fn sniff_xml(bytes: &[u8]) -> Option<Format> {
+    let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+
+    if contains(b"<mzXML") || contains(b"<msRun") {
+        Some(Format::MzXML)
+    } else if contains(b"<mzML") || contains(b"<indexedmzML") {
+        Some(Format::MzML)
+    } else {
+        None
+    }
 }
 
 /// Creates a `Format` type, from a string.
@@ -41,19 +171,322 @@ impl FromStr for Format {
             "json" => Ok(Self::Json),
             "mgf" => Ok(Self::Mgf),
             "mzml" => Ok(Self::MzML),
+            "mzxml" => Ok(Self::MzXML),
+            "msp" => Ok(Self::Msp),
+            "ms2" => Ok(Self::Ms2),
             _ => Err("Cannot parse input format."),
         }
     }
 }
 
+/// Reads spectra one at a time, whatever the format of the input.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::{self, Format, SpectrumReader};
+///
+/// let mgf = b"BEGIN IONS\nTITLE=1\n100.5\t10\nEND IONS\n";
+/// let mut reader = io::from_reader(&mgf[..], Format::Mgf).unwrap();
+///
+/// let spectrum = reader.next_spectrum().unwrap().unwrap();
+/// assert_eq!(spectrum.mz, vec![100.5]);
+/// assert!(reader.next_spectrum().unwrap().is_none());
+/// ```
+pub trait SpectrumReader {
+    /// Read the next spectrum, or `None` at the end of the input.
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>>;
+
+    /// Read the next spectrum, leaving its peaks unparsed if the format allows it. The default
+    /// reads the whole spectrum.
+    fn next_lazy_spectrum(&mut self) -> std::io::Result<Option<LazySpectrum>> {
+        Ok(self.next_spectrum()?.map(LazySpectrum::from))
+    }
+
+    /// Returns an iterator over the remaining spectra.
+    fn into_spectra(self) -> SpectrumIter<Self>
+    where
+        Self: Sized,
+    {
+        SpectrumIter { reader: self }
+    }
+
+    /// Returns an iterator over the remaining spectra, with their peaks left unparsed if the
+    /// format allows it.
+    fn into_lazy_spectra(self) -> LazySpectrumIter<Self>
+    where
+        Self: Sized,
+    {
+        LazySpectrumIter { reader: self }
+    }
+}
+
+impl<R: SpectrumReader + ?Sized> SpectrumReader for Box<R> {
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        (**self).next_spectrum()
+    }
+
+    fn next_lazy_spectrum(&mut self) -> std::io::Result<Option<LazySpectrum>> {
+        (**self).next_lazy_spectrum()
+    }
+}
+
+/// Writes spectra one at a time, whatever the format of the output.
+pub trait SpectrumWriter {
+    /// Write a spectrum.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()>;
+
+    /// Write anything the format needs after the last spectrum and flush the output.
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+impl<W: SpectrumWriter + ?Sized> SpectrumWriter for Box<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        (**self).write(spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// An iterator over the spectra of a `SpectrumReader`.
+pub struct SpectrumIter<R: SpectrumReader> {
+    reader: R,
+}
+
+impl<R: SpectrumReader> Iterator for SpectrumIter<R> {
+    type Item = std::io::Result<Spectrum>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_spectrum().transpose()
+    }
+}
+
+/// An iterator over the lazily read spectra of a `SpectrumReader`.
+pub struct LazySpectrumIter<R: SpectrumReader> {
+    reader: R,
+}
+
+impl<R: SpectrumReader> Iterator for LazySpectrumIter<R> {
+    type Item = std::io::Result<LazySpectrum>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_lazy_spectrum().transpose()
+    }
+}
+
+/// Returns a `SpectrumReader` for `reader`, which holds spectra in `format`. MGF spectra inherit
+/// the file's global parameters, as the header isn't otherwise available through the trait.
+///
+/// # Arguments
+///
+/// * `reader` - The input, already decompressed.
+/// * `format` - The format of the input.
+///
+pub fn from_reader<'a, R: BufRead + 'a>(
+    reader: R,
+    format: Format,
+) -> std::io::Result<Box<dyn SpectrumReader + 'a>> {
+    Ok(match format {
+        Format::Json => Box::new(json_parser::JsonReader::new(reader)),
+        Format::Mgf => Box::new(mgf_parser::MGFReader::new(reader).with_inherit_header(true)),
+        Format::MzML => Box::new(mzml_parser::MzMLReader::from_reader(reader)),
+        Format::MzXML => Box::new(mzxml_parser::MzXMLReader::new(reader)),
+        Format::Msp => Box::new(msp_parser::MspReader::new(reader)),
+        Format::Ms2 => Box::new(ms2_parser::Ms2Reader::new(reader)),
+    })
+}
+
+/// Returns a `SpectrumWriter` that writes spectra to `writer` in `format`.
+///
+/// # Arguments
+///
+/// * `writer` - Where to write the spectra.
+/// * `format` - The format to write.
+///
+pub fn to_writer<'a, W: Write + 'a>(
+    writer: W,
+    format: Format,
+) -> std::io::Result<Box<dyn SpectrumWriter + 'a>> {
+    Ok(match format {
+        Format::Json => Box::new(json_parser::JsonWriter::new(writer)),
+        Format::Mgf => Box::new(mgf_parser::MGFWriter::new(writer)),
+        Format::MzML => Box::new(mzml_parser::MzMLWriter::new(writer)),
+        Format::MzXML => Box::new(mzxml_parser::MzXMLWriter::new(writer)),
+        Format::Msp => Box::new(msp_parser::MspWriter::new(writer)),
+        Format::Ms2 => Box::new(ms2_parser::Ms2Writer::new(writer)),
+    })
+}
+
+/// Opens the file at `path` for reading spectra, decompressing it if needed. The format is
+/// sniffed from the start of the file, falling back to the one implied by its extension.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file, e.g. `run.mzML` or `run.mgf.gz`.
+///
+pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn SpectrumReader>> {
+    let mut reader = compression::open(&path)?;
+
+    let format = Format::sniff_reader(&mut reader)?
+        .or_else(|| Format::from_path(path.as_ref()))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown format for {}", path.as_ref().display()),
+            )
+        })?;
+
+    from_reader(reader, format)
+}
+
+/// Creates the file at `path` for writing spectra in `format`, compressed as implied by its
+/// extension.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file to create.
+/// * `format` - The format to write.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::{self, Format, SpectrumReader};
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let path = std::env::temp_dir().join("msn_kit_create_example.mgf.gz");
+///
+/// let mut writer = io::create(&path, Format::Mgf).unwrap();
+/// writer.write(Spectrum::new(Metadata::new(), vec![100.5], vec![10.0])).unwrap();
+/// writer.finish().unwrap();
+/// drop(writer);
+///
+/// let spectra: Vec<Spectrum> = io::open(&path)
+///     .unwrap()
+///     .into_spectra()
+///     .map(|s| s.unwrap())
+///     .collect();
+/// assert_eq!(spectra[0].mz, vec![100.5]);
+/// ```
+pub fn create<P: AsRef<Path>>(path: P, format: Format) -> std::io::Result<Box<dyn SpectrumWriter>> {
+    to_writer(compression::create(path)?, format)
+}
+
+#[cfg(test)]
 mod tests {
+    use crate::io::compression::{self, Compression};
+    use crate::io::{from_reader, to_writer, Format, SpectrumReader};
+    use crate::metadata::Metadata;
+    use crate::spectrum::Spectrum;
+    use std::io::{Cursor, Read, Write};
+    use std::path::Path;
     use std::str::FromStr;
-    use crate::io::Format;
+
+    #[test]
+    fn round_trip() {
+        let metadata: Metadata = [
+            ("TITLE", "scan=7"),
+            ("SCANS", "7"),
+            ("MSLEVEL", "2"),
+            ("RTINSECONDS", "30.5"),
+            ("PEPMASS", "445.34"),
+            ("CHARGE", "2+"),
+            ("COMMENT", "a comment"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let spectrum = Spectrum::new(metadata, vec![100.5, 200.25], vec![10.0, 20.0]);
+
+        let formats = [
+            Format::Json,
+            Format::Mgf,
+            Format::MzML,
+            Format::MzXML,
+            Format::Msp,
+            Format::Ms2,
+        ];
+
+        for format in formats {
+            let mut output = Vec::new();
+            {
+                let mut writer = to_writer(&mut output, format).unwrap();
+                writer
+                    .write(Spectrum::new(
+                        spectrum.metadata.clone(),
+                        spectrum.mz.clone(),
+                        spectrum.intensities.clone(),
+                    ))
+                    .unwrap();
+                writer.finish().unwrap();
+            }
+
+            let mut reader = from_reader(&output[..], format).unwrap();
+            let read = reader.next_spectrum().unwrap().unwrap();
+            assert_eq!(read.mz, spectrum.mz, "{:?}", format);
+            assert_eq!(read.intensities, spectrum.intensities, "{:?}", format);
+
+            // JSON and MGF keep the metadata as is, the other formats add keys like INDEX.
+            match format {
+                Format::Json | Format::Mgf => {
+                    assert_eq!(read.metadata, spectrum.metadata, "{:?}", format)
+                }
+                _ => {
+                    for (key, value) in spectrum.metadata.iter() {
+                        assert_eq!(read.metadata.get(key), Some(value), "{:?} {}", format, key);
+                    }
+                }
+            }
+            assert!(reader.next_spectrum().unwrap().is_none(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn charge_sign_round_trip() {
+        for (pepmass, charge) in [("445.34", "2-"), ("898 1 2", "2+ and 3+")] {
+            let mut metadata = Metadata::new();
+            metadata.insert(String::from("PEPMASS"), String::from(pepmass));
+            metadata.insert(String::from("CHARGE"), String::from(charge));
+
+            for format in [Format::MzML, Format::MzXML, Format::Ms2] {
+                let mut output = Vec::new();
+                {
+                    let mut writer = to_writer(&mut output, format).unwrap();
+                    writer
+                        .write(Spectrum::new(metadata.clone(), vec![100.5], vec![10.0]))
+                        .unwrap();
+                    writer.finish().unwrap();
+                }
+
+                let read = from_reader(&output[..], format)
+                    .unwrap()
+                    .next_spectrum()
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(read.metadata["PEPMASS"], pepmass, "{:?}", format);
+                assert_eq!(read.metadata["CHARGE"], charge, "{:?}", format);
+            }
+        }
+    }
 
     #[test]
     fn from_str() {
-        let inputs = vec!["json", "mgf", "mzml"];
-        let expected = vec![Format::Json, Format::Mgf, Format::MzML];
+        let inputs = vec!["json", "mgf", "mzml", "mzxml", "msp", "ms2"];
+        let expected = vec![
+            Format::Json,
+            Format::Mgf,
+            Format::MzML,
+            Format::MzXML,
+            Format::Msp,
+            Format::Ms2,
+        ];
 
         let actual: Vec<Format> = inputs
             .into_iter()
@@ -61,4 +494,93 @@ mod tests {
             .collect();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn sniff() {
+        let inputs: Vec<&[u8]> = vec![
+            b"\n\nBEGIN IONS\nTITLE=1\n",
+            b"COM=library\r\nCHARGE=2+\n# comment\nBEGIN IONS\n",
+            b"; comment\r\nbegin ions\r\n",
+            b"\xef\xbb\xbf<?xml version=\"1.0\"?>\n<indexedmzML xmlns=\"http://psi.hupo.org/ms/mzml\">",
+            b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\n<mzXML xmlns=\"http://sashimi.sourceforge.net/schema_revision/mzXML_3.2\">",
+            b"{\"metadata\":{}}",
+            b"Name: caffeine\nNum Peaks: 2\n",
+            b"S\t1\t1\t500.5\n",
+            b"<html>",
+            b"m/z\tintensity\n",
+            b"",
+        ];
+        let expected = vec![
+            Some(Format::Mgf),
+            Some(Format::Mgf),
+            Some(Format::Mgf),
+            Some(Format::MzML),
+            Some(Format::MzXML),
+            Some(Format::Json),
+            Some(Format::Msp),
+            Some(Format::Ms2),
+            None,
+            None,
+            None,
+        ];
+
+        let actual: Vec<Option<Format>> = inputs.into_iter().map(Format::sniff).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn sniff_reader() {
+        let mzml = b"<?xml version=\"1.0\"?>\n<mzML>\n</mzML>\n";
+
+        let mut gzipped = Vec::new();
+        let mut encoder = compression::Encoder::new(&mut gzipped, Compression::Gzip).unwrap();
+        encoder.write_all(mzml).unwrap();
+        encoder.finish().unwrap();
+
+        for input in [&mzml[..], &gzipped[..]] {
+            let mut reader = compression::decompress(Cursor::new(input.to_vec())).unwrap();
+            assert_eq!(
+                Format::sniff_reader(&mut reader).unwrap(),
+                Some(Format::MzML)
+            );
+
+            let mut text = Vec::new();
+            reader.read_to_end(&mut text).unwrap();
+            assert_eq!(text, mzml);
+        }
+    }
+
+    #[test]
+    fn from_path() {
+        let inputs = vec![
+            "a.json",
+            "b.MGF",
+            "c.mzML",
+            "d.xml",
+            "e",
+            "f.mzML.zst",
+            "g.gz",
+            "h.mzXML",
+            "i.msp",
+            "j.ms1",
+        ];
+        let expected = vec![
+            Some(Format::Json),
+            Some(Format::Mgf),
+            Some(Format::MzML),
+            None,
+            None,
+            Some(Format::MzML),
+            None,
+            Some(Format::MzXML),
+            Some(Format::Msp),
+            Some(Format::Ms2),
+        ];
+
+        let actual: Vec<Option<Format>> = inputs
+            .into_iter()
+            .map(|i| Format::from_path(Path::new(i)))
+            .collect();
+        assert_eq!(expected, actual);
+    }
 }
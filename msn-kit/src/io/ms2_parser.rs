@@ -0,0 +1,517 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Module for reading and writing the MS1 and MS2 text formats used by Sequest and Crux.
+//!
+//! Each scan starts with an `S` line of the first and last scan numbers and, for MS2, the
+//! precursor m/z. These are read as `SCANS` (e.g. `12` or `12-14`), `PEPMASS` and `MSLEVEL`. The
+//! scan's `Z` lines, one per possible charge, are read as `CHARGE` (e.g. `2+ and 3+`) and `MH`,
+//! the matching singly protonated [M+H]+ masses separated by spaces. `I` lines are read as
+//! metadata with their own keys, and `D` lines are skipped.
+
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::io::{compression, SpectrumReader, SpectrumWriter};
+use crate::metadata::{parse_charges, parse_pepmass};
+use crate::spectrum::Spectrum;
+
+/// The mass of a proton, for computing [M+H]+ masses from a precursor m/z.
+const PROTON_MASS: f64 = 1.007276;
+
+/// Metadata keys that are written as `S` and `Z` lines rather than `I` lines.
+const CONVERTED_KEYS: [&str; 4] = ["SCANS", "PEPMASS", "CHARGE", "MH"];
+
+/// Reads `Spectrum` objects from an MS1 or MS2 file.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::ms2_parser::Ms2Reader;
+/// use msn_kit::io::SpectrumReader;
+///
+/// let ms2 = b"H\tExtractor\tMakeMS2\nS\t12\t12\t445.34\nI\tRetTime\t0.5\nZ\t2\t889.67\nZ\t3\t1334.0\n200.1 100\n";
+/// let mut reader = Ms2Reader::new(&ms2[..]);
+/// let spectrum = reader.next_spectrum().unwrap().unwrap();
+///
+/// assert_eq!(reader.headers()[0], (String::from("Extractor"), String::from("MakeMS2")));
+/// assert_eq!(spectrum.metadata["SCANS"], "12");
+/// assert_eq!(spectrum.metadata["CHARGE"], "2+ and 3+");
+/// assert_eq!(spectrum.metadata["MH"], "889.67 1334.0");
+/// assert_eq!(spectrum.metadata["RetTime"], "0.5");
+/// ```
+pub struct Ms2Reader<R: BufRead> {
+    reader: R,
+    line: String,
+    line_number: usize,
+    headers: Vec<(String, String)>,
+    next: Option<Spectrum>,
+}
+
+impl Ms2Reader<Box<dyn BufRead>> {
+    /// Open the MS1 or MS2 file at `path`, decompressing it if it's compressed.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(compression::open(path)?))
+    }
+}
+
+impl<R: BufRead> Ms2Reader<R> {
+    /// Create a new Ms2Reader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The MS1 or MS2 file to read.
+    ///
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            line_number: 0,
+            headers: Vec::new(),
+            next: None,
+        }
+    }
+
+    /// Returns the `H` header lines read so far as (key, value) pairs. They're all read with the
+    /// first scan.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Read the next scan, or `None` at the end of the file.
+    pub fn read(&mut self) -> std::io::Result<Option<Spectrum>> {
+        let mut spectrum = match self.next.take() {
+            Some(spectrum) => spectrum,
+            None => loop {
+                if !self.next_line()? {
+                    return Ok(None);
+                }
+
+                match self.line.split_whitespace().next() {
+                    None => continue,
+                    Some("H") => {
+                        let (key, value) = self.key_value()?;
+                        self.headers.push((key, value));
+                    }
+                    Some("S") => break self.scan()?,
+                    Some(_) => return Err(self.invalid("expected an H or S line")),
+                }
+            },
+        };
+
+        let mut charges = Vec::new();
+        let mut masses = Vec::new();
+
+        while self.next_line()? {
+            match self.line.split_whitespace().next() {
+                None | Some("D") => {}
+                Some("S") => {
+                    self.next = Some(self.scan()?);
+                    break;
+                }
+                Some("I") => {
+                    // Keys from the S line are replaced, other keys can repeat.
+                    let (key, value) = self.key_value()?;
+                    if matches!(key.as_str(), "SCANS" | "PEPMASS" | "MSLEVEL") {
+                        spectrum.metadata.insert(key, value);
+                    } else {
+                        spectrum.metadata.append(key, value);
+                    }
+                }
+                Some("Z") => {
+                    let fields: Vec<&str> = self.line.split_whitespace().collect();
+                    match fields.as_slice() {
+                        [_, charge, mass, ..] if charge.parse::<u32>().is_ok() => {
+                            charges.push(format!("{}+", charge));
+                            masses.push(mass.to_string());
+                        }
+                        _ => return Err(self.invalid("expected Z <charge> <[M+H]+ mass>")),
+                    }
+                }
+                Some(_) => {
+                    let (mz, intensity) = self.peak()?;
+                    spectrum.mz.push(mz);
+                    spectrum.intensities.push(intensity);
+                }
+            }
+        }
+
+        if !charges.is_empty() {
+            let metadata = &mut spectrum.metadata;
+            metadata.insert(String::from("CHARGE"), charges.join(" and "));
+            metadata.insert(String::from("MH"), masses.join(" "));
+        }
+
+        Ok(Some(spectrum))
+    }
+
+    /// Starts a spectrum from the `S` line in `self.line`.
+    fn scan(&self) -> std::io::Result<Spectrum> {
+        let fields: Vec<&str> = self.line.split_whitespace().collect();
+
+        let mut spectrum = Spectrum::empty();
+        let metadata = &mut spectrum.metadata;
+        match fields.as_slice() {
+            [_, low, high, rest @ ..] if rest.len() <= 1 => {
+                let scans = if low.trim_start_matches('0') == high.trim_start_matches('0') {
+                    low.to_string()
+                } else {
+                    format!("{}-{}", low, high)
+                };
+                metadata.insert(String::from("SCANS"), scans);
+
+                match rest.first() {
+                    Some(mz) => {
+                        mz.parse::<f64>()
+                            .map_err(|_| self.invalid(&format!("invalid precursor m/z {}", mz)))?;
+                        metadata.insert(String::from("PEPMASS"), mz.to_string());
+                        metadata.insert(String::from("MSLEVEL"), String::from("2"));
+                    }
+                    None => {
+                        metadata.insert(String::from("MSLEVEL"), String::from("1"));
+                    }
+                }
+            }
+            _ => {
+                return Err(self
+                    .invalid("expected S <first scan> <last scan> and, for MS2, <precursor m/z>"))
+            }
+        }
+
+        Ok(spectrum)
+    }
+
+    /// Parses the `H` or `I` line in `self.line`, whose value can contain spaces.
+    fn key_value(&self) -> std::io::Result<(String, String)> {
+        let line = self.line.trim_end_matches(['\r', '\n']);
+        let mut fields = if line.contains('\t') {
+            line.splitn(3, '\t').collect::<Vec<&str>>()
+        } else {
+            line.splitn(3, ' ').collect::<Vec<&str>>()
+        };
+
+        if fields.len() < 2 || fields[1].is_empty() {
+            return Err(self.invalid("expected a key and value"));
+        }
+        let value = if fields.len() == 3 {
+            fields.remove(2)
+        } else {
+            ""
+        };
+
+        Ok((fields[1].to_string(), value.trim().to_string()))
+    }
+
+    /// Parses the peak line in `self.line`. Columns after the intensity are ignored.
+    fn peak(&self) -> std::io::Result<(f64, f64)> {
+        let mut fields = self.line.split_whitespace();
+
+        let mut value = |name: &str| -> std::io::Result<f64> {
+            let field = fields.next().unwrap_or_default();
+            field
+                .parse()
+                .map_err(|_| self.invalid(&format!("invalid {} {}", name, field)))
+        };
+
+        Ok((value("m/z")?, value("intensity")?))
+    }
+
+    /// Reads the next line into `self.line`, returning false at the end of the input.
+    fn next_line(&mut self) -> std::io::Result<bool> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(false);
+        }
+        self.line_number += 1;
+
+        Ok(true)
+    }
+
+    fn invalid(&self, message: &str) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid MS2 on line {}: {}", self.line_number, message),
+        )
+    }
+}
+
+impl<R: BufRead> SpectrumReader for Ms2Reader<R> {
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        self.read()
+    }
+}
+
+/// Writes `Spectrum` objects as MS1 or MS2 scans.
+///
+/// Spectra with a `PEPMASS` are written as MS2 scans, with a `Z` line for each charge in
+/// `CHARGE`. The [M+H]+ masses are taken from `MH` if it has one per charge, and otherwise
+/// computed from the precursor m/z. Other metadata keys are written as `I` lines, in order.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::ms2_parser::Ms2Writer;
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let mut metadata = Metadata::new();
+/// metadata.insert(String::from("PEPMASS"), String::from("445.5"));
+/// metadata.insert(String::from("CHARGE"), String::from("2+"));
+///
+/// let mut writer = Ms2Writer::new(Vec::new()).with_header("Extractor", "msn-kit");
+/// writer.write(Spectrum::new(metadata, vec![200.5], vec![10.0])).unwrap();
+///
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "H\tExtractor\tmsn-kit\nS\t1\t1\t445.5\nZ\t2\t889.9927\n200.5 10\n");
+/// ```
+#[derive(Debug)]
+pub struct Ms2Writer<W: Write> {
+    writer: BufWriter<W>,
+    headers: Vec<(String, String)>,
+    n_written: usize,
+}
+
+impl<W: Write> Ms2Writer<W> {
+    /// Create a new Ms2Writer object.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - An object that can be written to.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            headers: Vec::new(),
+            n_written: 0,
+        }
+    }
+
+    /// Add an `H` line, written before the first scan.
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Write spectrum to the underlying buffer as an MS1 or MS2 scan.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        if spectrum.mz.len() != spectrum.intensities.len() {
+            return Err(Error::other(format!(
+                "Spectrum has {} m/z values but {} intensities.",
+                spectrum.mz.len(),
+                spectrum.intensities.len()
+            )));
+        }
+
+        let mut record = String::new();
+        if self.n_written == 0 {
+            for (key, value) in self.headers.iter() {
+                record.push_str(&format!("H\t{}\t{}\n", key, value));
+            }
+        }
+        self.n_written += 1;
+
+        let metadata = &spectrum.metadata;
+        let mut info: Vec<(&str, &str)> = Vec::new();
+
+        let (low, high) = match metadata.get("SCANS").map(|s| s.split_once('-')) {
+            Some(Some((low, high))) => (low.to_string(), high.to_string()),
+            Some(None) => (metadata["SCANS"].clone(), metadata["SCANS"].clone()),
+            None => (self.n_written.to_string(), self.n_written.to_string()),
+        };
+
+        let precursor_mz = match metadata.get("PEPMASS") {
+            Some(pepmass) => match parse_pepmass(pepmass) {
+                Ok((mz, _)) => Some(mz),
+                Err(_) => {
+                    info.push(("PEPMASS", pepmass));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        match precursor_mz {
+            // Written as it was given, so e.g. 500.0 isn't written as 500.
+            Some(_) => {
+                let mz = metadata["PEPMASS"]
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default();
+                record.push_str(&format!("S\t{}\t{}\t{}\n", low, high, mz))
+            }
+            None => record.push_str(&format!("S\t{}\t{}\n", low, high)),
+        }
+
+        // The MS level is implied by the S line, so only write one that doesn't match it.
+        let implied_level = if precursor_mz.is_some() { "2" } else { "1" };
+        for (k, v) in metadata.iter() {
+            let converted =
+                CONVERTED_KEYS.contains(&k.as_str()) || (k == "MSLEVEL" && v == implied_level);
+            if !converted {
+                info.push((k, v));
+            }
+        }
+
+        let charges = metadata.get("CHARGE").map(|c| parse_charges(c));
+        let masses: Vec<&str> = match metadata.get("MH") {
+            Some(mh) if mh.split_whitespace().all(|m| m.parse::<f64>().is_ok()) => {
+                mh.split_whitespace().collect()
+            }
+            _ => Vec::new(),
+        };
+
+        let mut z_lines = String::new();
+        match (charges, precursor_mz) {
+            // Z lines only hold positive charges.
+            (Some(Ok(charges)), Some(mz)) if charges.iter().all(|c| *c > 0) => {
+                for (i, charge) in charges.iter().enumerate() {
+                    let mass = if masses.len() == charges.len() {
+                        masses[i].to_string()
+                    } else {
+                        format!("{:.4}", (mz - PROTON_MASS) * *charge as f64 + PROTON_MASS)
+                    };
+                    z_lines.push_str(&format!("Z\t{}\t{}\n", charge, mass));
+                }
+            }
+            (Some(_), _) => info.push(("CHARGE", &metadata["CHARGE"])),
+            (None, _) => {}
+        }
+        if z_lines.is_empty() {
+            if let Some(mh) = metadata.get("MH") {
+                info.push(("MH", mh));
+            }
+        }
+
+        for (k, v) in info {
+            record.push_str(&format!("I\t{}\t{}\n", k, v.replace(['\r', '\n'], " ")));
+        }
+        record.push_str(&z_lines);
+
+        for (mz, intensity) in spectrum.mz.iter().zip(&spectrum.intensities) {
+            record.push_str(&format!("{} {}\n", mz, intensity));
+        }
+
+        self.writer.write_all(record.as_bytes())
+    }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> SpectrumWriter for Ms2Writer<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        Ms2Writer::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS2: &[u8] = b"H\tCreationDate\tMon Oct 17 10:00:00 2021
+H\tExtractor\tMakeMS2
+H\tComments\tMakeMS2 written by Michael J. MacCoss, 2004
+
+S\t000012\t000012\t445.34
+I\tRetTime\t0.5
+I\tPrecursorInt\t1200
+I\tEZ\t2\t889.67\t0.5\t1000
+I\tEZ\t3\t1334.0\t0.5\t200
+Z\t2\t889.67
+D\tseq\tPEPTIDE
+Z\t3\t1334.0
+200.1 100
+300.25\t20.5 0
+S\t13\t14\t500.0
+Z\t1\t500.0
+150 5
+";
+
+    #[test]
+    fn reader_test() {
+        let mut reader = Ms2Reader::new(MS2);
+        let first = reader.read().unwrap().unwrap();
+
+        assert_eq!(reader.headers().len(), 3);
+        assert_eq!(
+            reader.headers()[2],
+            (
+                String::from("Comments"),
+                String::from("MakeMS2 written by Michael J. MacCoss, 2004")
+            )
+        );
+
+        assert_eq!(first.metadata["SCANS"], "000012");
+        assert_eq!(first.metadata["PEPMASS"], "445.34");
+        assert_eq!(first.metadata["MSLEVEL"], "2");
+        assert_eq!(first.metadata["RetTime"], "0.5");
+        assert_eq!(first.metadata["PrecursorInt"], "1200");
+        let ez: Vec<&String> = first.metadata.get_all("EZ").collect();
+        assert_eq!(ez, vec!["2\t889.67\t0.5\t1000", "3\t1334.0\t0.5\t200"]);
+        assert_eq!(first.metadata["CHARGE"], "2+ and 3+");
+        assert_eq!(first.metadata["MH"], "889.67 1334.0");
+        assert_eq!(first.mz, vec![200.1, 300.25]);
+        assert_eq!(first.intensities, vec![100.0, 20.5]);
+
+        let second = reader.read().unwrap().unwrap();
+        assert_eq!(second.metadata["SCANS"], "13-14");
+        assert_eq!(second.metadata["CHARGE"], "1+");
+        assert_eq!(second.mz, vec![150.0]);
+
+        assert!(reader.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut reader = Ms2Reader::new(MS2);
+        let expected: Vec<Spectrum> = std::iter::from_fn(|| reader.read().unwrap()).collect();
+
+        let mut writer = Ms2Writer::new(Vec::new());
+        for (key, value) in reader.headers() {
+            writer = writer.with_header(key, value);
+        }
+        for spectrum in Ms2Reader::new(MS2).into_spectra() {
+            writer.write(spectrum.unwrap()).unwrap();
+        }
+        let output = writer.into_inner().unwrap();
+
+        let mut reader = Ms2Reader::new(&output[..]);
+        let actual: Vec<Spectrum> = std::iter::from_fn(|| reader.read().unwrap()).collect();
+        assert_eq!(expected, actual);
+        assert_eq!(reader.headers().len(), 3);
+    }
+
+    #[test]
+    fn ms1_test() {
+        let ms1 = b"S\t1\t1\nI\tRTime\t0.01\n200.1 100\nS\t2\t2\n";
+        let spectra: Vec<Spectrum> = Ms2Reader::new(&ms1[..])
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].metadata["MSLEVEL"], "1");
+        assert!(!spectra[0].metadata.contains_key("PEPMASS"));
+        assert!(spectra[1].mz.is_empty());
+    }
+
+    #[test]
+    fn invalid_test() {
+        let ms2 = b"S\t1\t1\t445.34\n200.1 abc\n";
+        let error = Ms2Reader::new(&ms2[..]).read().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid MS2 on line 2: invalid intensity abc"
+        );
+    }
+}
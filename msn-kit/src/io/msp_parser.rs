@@ -0,0 +1,387 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Module for reading and writing NIST MSP spectral libraries, as distributed by NIST, MoNA,
+//! GNPS and MassBank.
+//!
+//! The `Name`, `PrecursorMZ` and `Charge` headers are mapped to the MGF style `TITLE`, `PEPMASS`
+//! and `CHARGE` metadata keys, and back when writing, so libraries can be converted between MGF
+//! and MSP. Other headers are kept as they are, except `Num Peaks` which is implied by the peaks.
+//! Peak annotations are skipped.
+
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::io::{compression, SpectrumReader, SpectrumWriter};
+use crate::spectrum::Spectrum;
+
+/// MSP headers and the metadata keys they're read into.
+const MAPPED_HEADERS: [(&str, &str); 3] = [
+    ("Name", "TITLE"),
+    ("PrecursorMZ", "PEPMASS"),
+    ("Charge", "CHARGE"),
+];
+
+/// Reads `Spectrum` objects from an MSP library.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::msp_parser::MspReader;
+/// use msn_kit::io::SpectrumReader;
+///
+/// let msp = b"Name: Caffeine\nPrecursorMZ: 195.0877\nNum Peaks: 3\n110.07 12; 138.07 999 \"b\"; 195.09 80\n";
+/// let spectrum = MspReader::new(&msp[..]).next_spectrum().unwrap().unwrap();
+///
+/// assert_eq!(spectrum.metadata["TITLE"], "Caffeine");
+/// assert_eq!(spectrum.metadata["PEPMASS"], "195.0877");
+/// assert_eq!(spectrum.mz, vec![110.07, 138.07, 195.09]);
+/// ```
+pub struct MspReader<R: BufRead> {
+    reader: R,
+    line: String,
+    line_number: usize,
+}
+
+impl MspReader<Box<dyn BufRead>> {
+    /// Open the MSP file at `path`, decompressing it if it's compressed.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(compression::open(path)?))
+    }
+}
+
+impl<R: BufRead> MspReader<R> {
+    /// Create a new MspReader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The MSP library to read.
+    ///
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    /// Read the next spectrum, or `None` at the end of the library.
+    pub fn read(&mut self) -> std::io::Result<Option<Spectrum>> {
+        let mut spectrum = Spectrum::empty();
+
+        // The headers, up to and including Num Peaks.
+        let n_peaks = loop {
+            if !self.next_line()? {
+                if spectrum.metadata.is_empty() {
+                    return Ok(None);
+                }
+                return Err(self.invalid("the library ended before Num Peaks"));
+            }
+
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(':')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| {
+                    self.invalid(&format!("expected a Key: Value header, got {}", line))
+                })?;
+
+            if key.eq_ignore_ascii_case("Num Peaks") {
+                break value
+                    .parse::<usize>()
+                    .map_err(|_| self.invalid(&format!("invalid Num Peaks {}", value)))?;
+            }
+
+            let key = MAPPED_HEADERS
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(key))
+                .map_or(key, |(_, mapped)| mapped);
+            spectrum.metadata.append(key, value);
+        };
+
+        while spectrum.mz.len() < n_peaks {
+            if !self.next_line()? {
+                return Err(self.invalid(&format!(
+                    "the library ended after {} of {} peaks",
+                    spectrum.mz.len(),
+                    n_peaks
+                )));
+            }
+
+            let peaks = parse_peaks(&self.line).map_err(|e| self.invalid(&e))?;
+            for (mz, intensity) in peaks {
+                spectrum.mz.push(mz);
+                spectrum.intensities.push(intensity);
+            }
+        }
+
+        if spectrum.mz.len() != n_peaks {
+            return Err(self.invalid(&format!(
+                "expected {} peaks, got {}",
+                n_peaks,
+                spectrum.mz.len()
+            )));
+        }
+
+        Ok(Some(spectrum))
+    }
+
+    /// Reads the next line into `self.line`, returning false at the end of the input.
+    fn next_line(&mut self) -> std::io::Result<bool> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(false);
+        }
+        self.line_number += 1;
+
+        Ok(true)
+    }
+
+    fn invalid(&self, message: &str) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid MSP on line {}: {}", self.line_number, message),
+        )
+    }
+}
+
+impl<R: BufRead> SpectrumReader for MspReader<R> {
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        self.read()
+    }
+}
+
+/// Parses the m/z and intensity pairs of a peak line. Pairs can be separated by `;` or just
+/// whitespace, e.g. `41 12; 42 5` or `41 12 42 5`, and can be followed by a quoted annotation.
+fn parse_peaks(line: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut values = Vec::new();
+
+    for (i, segment) in line.split('"').enumerate() {
+        // Odd segments are inside quotes.
+        if i % 2 == 1 {
+            continue;
+        }
+
+        for value in segment.split(|c: char| c.is_whitespace() || c == ';' || c == ',') {
+            if value.is_empty() {
+                continue;
+            }
+
+            let value = value
+                .parse::<f64>()
+                .map_err(|_| format!("invalid peak value {}", value))?;
+            values.push(value);
+        }
+    }
+
+    if values.len() % 2 != 0 {
+        return Err(format!(
+            "expected m/z and intensity pairs, got {}",
+            line.trim()
+        ));
+    }
+
+    Ok(values.chunks_exact(2).map(|p| (p[0], p[1])).collect())
+}
+
+/// Writes `Spectrum` objects as an MSP library.
+///
+/// `TITLE` is written as `Name`, the m/z of `PEPMASS` as `PrecursorMZ` and `CHARGE` as `Charge`.
+/// `Name` is written first and the other metadata keys as headers after it, in order.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::msp_parser::MspWriter;
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let mut metadata = Metadata::new();
+/// metadata.insert(String::from("TITLE"), String::from("Caffeine"));
+///
+/// let mut writer = MspWriter::new(Vec::new());
+/// writer.write(Spectrum::new(metadata, vec![138.07], vec![999.0])).unwrap();
+///
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "Name: Caffeine\nNum Peaks: 1\n138.07 999\n\n");
+/// ```
+#[derive(Debug)]
+pub struct MspWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> MspWriter<W> {
+    /// Create a new MspWriter object.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - An object that can be written to.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    /// Write spectrum to the underlying buffer as an MSP record.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        if spectrum.mz.len() != spectrum.intensities.len() {
+            return Err(Error::other(format!(
+                "Spectrum has {} m/z values but {} intensities.",
+                spectrum.mz.len(),
+                spectrum.intensities.len()
+            )));
+        }
+
+        let mut record = String::new();
+        if let Some(title) = spectrum.metadata.get("TITLE") {
+            record.push_str(&format!("Name: {}\n", single_line(title)));
+        }
+
+        for (k, v) in spectrum.metadata.iter() {
+            let header = match MAPPED_HEADERS.iter().find(|(_, key)| key == k) {
+                // A record only has one name, and it has to come first.
+                Some((_, "TITLE")) => continue,
+                Some((header, _)) => header,
+                None => k.as_str(),
+            };
+            // A PEPMASS intensity has nowhere to go.
+            let value = if k == "PEPMASS" {
+                v.split_whitespace().next().unwrap_or_default()
+            } else {
+                v
+            };
+            record.push_str(&format!(
+                "{}: {}\n",
+                single_line(header),
+                single_line(value)
+            ));
+        }
+
+        record.push_str(&format!("Num Peaks: {}\n", spectrum.mz.len()));
+        for (mz, intensity) in spectrum.mz.iter().zip(&spectrum.intensities) {
+            record.push_str(&format!("{} {}\n", mz, intensity));
+        }
+        record.push('\n');
+
+        self.writer.write_all(record.as_bytes())
+    }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write> SpectrumWriter for MspWriter<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        MspWriter::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Replaces line breaks, which would end the header early.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSP: &[u8] = b"NAME: Caffeine
+Synon: 1,3,7-Trimethylxanthine
+Synon: Guaranine
+PRECURSORMZ: 195.0877
+Precursor_type: [M+H]+
+Comments: \"SMILES=CN1C=NC2=C1C(=O)N(C(=O)N2C)C\" \"computed\"
+Num Peaks: 4
+110.0713 12.5 \"p-C3H3NO\"
+138.0662\t999 \"b; y\"
+163.0612 40; 195.0877 80;
+
+Name: Adenine
+Num peaks: 5
+41 12 42 5 43 7
+44 3; 45 1
+
+Name: Empty
+Num Peaks: 0
+";
+
+    #[test]
+    fn reader_test() {
+        let spectra: Vec<Spectrum> = MspReader::new(MSP)
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(spectra.len(), 3);
+
+        let caffeine = &spectra[0];
+        assert_eq!(caffeine.metadata["TITLE"], "Caffeine");
+        assert_eq!(caffeine.metadata["PEPMASS"], "195.0877");
+        assert_eq!(caffeine.metadata["Precursor_type"], "[M+H]+");
+        let synonyms: Vec<&String> = caffeine.metadata.get_all("Synon").collect();
+        assert_eq!(synonyms, vec!["1,3,7-Trimethylxanthine", "Guaranine"]);
+        assert_eq!(
+            caffeine.metadata["Comments"],
+            "\"SMILES=CN1C=NC2=C1C(=O)N(C(=O)N2C)C\" \"computed\""
+        );
+        assert_eq!(caffeine.mz, vec![110.0713, 138.0662, 163.0612, 195.0877]);
+        assert_eq!(caffeine.intensities, vec![12.5, 999.0, 40.0, 80.0]);
+
+        assert_eq!(spectra[1].mz, vec![41.0, 42.0, 43.0, 44.0, 45.0]);
+        assert_eq!(spectra[1].intensities, vec![12.0, 5.0, 7.0, 3.0, 1.0]);
+
+        assert_eq!(spectra[2].metadata["TITLE"], "Empty");
+        assert!(spectra[2].mz.is_empty());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let expected: Vec<Spectrum> = MspReader::new(MSP)
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+
+        let mut writer = MspWriter::new(Vec::new());
+        for spectrum in MspReader::new(MSP).into_spectra() {
+            writer.write(spectrum.unwrap()).unwrap();
+        }
+        let output = writer.into_inner().unwrap();
+
+        let actual: Vec<Spectrum> = MspReader::new(&output[..])
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn invalid_test() {
+        let msp = b"Name: A\nNum Peaks: 2\n41 12\n42 five\n";
+        let error = MspReader::new(&msp[..]).read().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid MSP on line 4: invalid peak value five"
+        );
+
+        let msp = b"Name: A\nNum Peaks: 2\n41 12\n";
+        let error = MspReader::new(&msp[..]).read().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid MSP on line 3: the library ended after 1 of 2 peaks"
+        );
+    }
+}
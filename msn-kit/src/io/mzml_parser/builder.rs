@@ -0,0 +1,723 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Builds spectra and chromatograms directly from the XML event stream.
+//!
+//! Every event is read into one buffer that is reused for the whole file, and the base64
+//! content of binary arrays is kept as text, so arrays are only decoded when asked for with
+//! `BinaryDataArray::decode`.
+
+use quick_xml::events::{BytesStart, Event};
+
+use std::io::BufRead;
+
+use crate::io::mzml_parser::error::MzMLError;
+use crate::io::mzml_parser::parser::attribute_value;
+use crate::io::mzml_parser::types;
+
+/// The next event inside an element.
+enum Next<'b> {
+    /// A child element, and whether it's empty, i.e. `<x/>`.
+    Child(BytesStart<'b>, bool),
+
+    /// Text, comments, etc.
+    Other,
+
+    /// The end of the element.
+    End,
+}
+
+/// Reads the children of an element whose start tag has been read.
+pub(crate) struct Builder<'a, R: BufRead> {
+    reader: &'a mut quick_xml::Reader<R>,
+    buf: &'a mut Vec<u8>,
+    base_offset: u64,
+}
+
+impl<'a, R> Builder<'a, R>
+where
+    R: BufRead,
+{
+    pub(crate) fn new(
+        reader: &'a mut quick_xml::Reader<R>,
+        buf: &'a mut Vec<u8>,
+        base_offset: u64,
+    ) -> Self {
+        Self {
+            reader,
+            buf,
+            base_offset,
+        }
+    }
+
+    /// Reads the rest of a spectrum, given the attributes of its start tag.
+    pub(crate) fn spectrum(
+        &mut self,
+        id: String,
+        index: String,
+        default_array_length: String,
+    ) -> Result<types::Spectrum, MzMLError> {
+        let mut referenceable_param_group_ref = Vec::new();
+        let mut cv_param = Vec::new();
+        let mut user_param = Vec::new();
+        let mut scan_list = None;
+        let mut precursor_list = None;
+        let mut binary_data_array_list = None;
+
+        loop {
+            let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                Next::Child(e, empty) => (e, empty),
+                Next::Other => continue,
+                Next::End => break,
+            };
+
+            match e.name() {
+                b"cvParam" => {
+                    cv_param.push(cv_param_from(&e)?);
+                    self.skip(empty)?;
+                }
+                b"userParam" => {
+                    user_param.push(user_param_from(&e)?);
+                    self.skip(empty)?;
+                }
+                b"referenceableParamGroupRef" => {
+                    referenceable_param_group_ref.push(param_group_ref_from(&e)?);
+                    self.skip(empty)?;
+                }
+                b"scanList" => {
+                    let count = required(&e, b"count")?;
+                    scan_list = Some(self.scan_list(count, empty)?);
+                }
+                b"precursorList" => {
+                    let count = required(&e, b"count")?;
+                    precursor_list = Some(self.precursor_list(count, empty)?);
+                }
+                b"binaryDataArrayList" => {
+                    let count = required(&e, b"count")?;
+                    binary_data_array_list = Some(self.binary_data_array_list(count, empty)?);
+                }
+                _ => self.skip(empty)?,
+            }
+        }
+
+        Ok(types::Spectrum {
+            referenceable_param_group_ref,
+            cv_param,
+            user_param,
+            index,
+            id,
+            default_array_length,
+            scan_list,
+            precursor_list,
+            binary_data_array_list: binary_data_array_list.unwrap_or_default(),
+        })
+    }
+
+    /// Reads the rest of a chromatogram, given the attributes of its start tag.
+    pub(crate) fn chromatogram(
+        &mut self,
+        id: String,
+        index: String,
+        default_array_length: String,
+    ) -> Result<types::Chromatogram, MzMLError> {
+        let mut referenceable_param_group_ref = Vec::new();
+        let mut cv_param = Vec::new();
+        let mut precursor = None;
+        let mut product = None;
+        let mut binary_data_array_list = None;
+
+        loop {
+            let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                Next::Child(e, empty) => (e, empty),
+                Next::Other => continue,
+                Next::End => break,
+            };
+
+            match e.name() {
+                b"cvParam" => {
+                    cv_param.push(cv_param_from(&e)?);
+                    self.skip(empty)?;
+                }
+                b"referenceableParamGroupRef" => {
+                    referenceable_param_group_ref.push(param_group_ref_from(&e)?);
+                    self.skip(empty)?;
+                }
+                b"precursor" => {
+                    let spectrum_ref = attribute(&e, b"spectrumRef")?;
+                    precursor = Some(self.precursor(spectrum_ref, empty)?);
+                }
+                b"product" => {
+                    product = Some(types::Product {
+                        isolation_window: self.isolation_window(empty)?,
+                    });
+                }
+                b"binaryDataArrayList" => {
+                    let count = required(&e, b"count")?;
+                    binary_data_array_list = Some(self.binary_data_array_list(count, empty)?);
+                }
+                _ => self.skip(empty)?,
+            }
+        }
+
+        Ok(types::Chromatogram {
+            referenceable_param_group_ref,
+            cv_param,
+            index,
+            id,
+            default_array_length,
+            precursor,
+            product,
+            binary_data_array_list: binary_data_array_list
+                .ok_or_else(|| missing_element("binaryDataArrayList", "chromatogram"))?,
+        })
+    }
+
+    fn scan_list(&mut self, count: String, empty: bool) -> Result<types::ScanList, MzMLError> {
+        let mut cv_param = Vec::new();
+        let mut scan = Vec::new();
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"cvParam" => {
+                        cv_param.push(cv_param_from(&e)?);
+                        self.skip(empty)?;
+                    }
+                    b"scan" => scan.push(self.scan(empty)?),
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::ScanList {
+            count,
+            cv_param,
+            scan,
+        })
+    }
+
+    fn scan(&mut self, empty: bool) -> Result<types::Scan, MzMLError> {
+        let mut cv_param = Vec::new();
+        let mut scan_window_list = None;
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"cvParam" => {
+                        cv_param.push(cv_param_from(&e)?);
+                        self.skip(empty)?;
+                    }
+                    b"scanWindowList" => {
+                        let count = required(&e, b"count")?;
+                        let mut scan_window = Vec::new();
+
+                        if !empty {
+                            loop {
+                                match next(self.reader, self.buf, self.base_offset)? {
+                                    Next::Child(e, empty) if e.name() == b"scanWindow" => {
+                                        scan_window.push(types::ScanWindow {
+                                            cv_param: self.cv_params(empty)?,
+                                        })
+                                    }
+                                    Next::Child(_, empty) => self.skip(empty)?,
+                                    Next::Other => {}
+                                    Next::End => break,
+                                }
+                            }
+                        }
+
+                        scan_window_list = Some(types::ScanWindowList { scan_window, count });
+                    }
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::Scan {
+            cv_param,
+            scan_window_list,
+        })
+    }
+
+    fn precursor_list(
+        &mut self,
+        count: String,
+        empty: bool,
+    ) -> Result<types::PrecursorList, MzMLError> {
+        let mut precursor = Vec::new();
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"precursor" => {
+                        let spectrum_ref = attribute(&e, b"spectrumRef")?;
+                        precursor.push(self.precursor(spectrum_ref, empty)?);
+                    }
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::PrecursorList { count, precursor })
+    }
+
+    fn precursor(
+        &mut self,
+        spectrum_ref: Option<String>,
+        empty: bool,
+    ) -> Result<types::Precursor, MzMLError> {
+        let mut isolation_window = None;
+        let mut selected_ion_list = None;
+        let mut activation = None;
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"isolationWindow" => {
+                        isolation_window = Some(types::IsolationWindow {
+                            cv_param: self.cv_params(empty)?,
+                        })
+                    }
+                    b"selectedIonList" => {
+                        let count = required(&e, b"count")?;
+                        let mut selected_ion = Vec::new();
+
+                        if !empty {
+                            loop {
+                                match next(self.reader, self.buf, self.base_offset)? {
+                                    Next::Child(e, empty) if e.name() == b"selectedIon" => {
+                                        selected_ion.push(types::SelectedIon {
+                                            cv_param: self.cv_params(empty)?,
+                                        })
+                                    }
+                                    Next::Child(_, empty) => self.skip(empty)?,
+                                    Next::Other => {}
+                                    Next::End => break,
+                                }
+                            }
+                        }
+
+                        selected_ion_list = Some(types::SelectedIonList {
+                            count,
+                            selected_ion,
+                        });
+                    }
+                    b"activation" => {
+                        activation = Some(types::Activation {
+                            cv_param: self.cv_params(empty)?,
+                        })
+                    }
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::Precursor {
+            spectrum_ref,
+            isolation_window,
+            selected_ion_list,
+            activation: activation.ok_or_else(|| missing_element("activation", "precursor"))?,
+        })
+    }
+
+    /// Reads the isolationWindow of a product.
+    fn isolation_window(
+        &mut self,
+        empty: bool,
+    ) -> Result<Option<types::IsolationWindow>, MzMLError> {
+        let mut isolation_window = None;
+
+        if !empty {
+            loop {
+                match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) if e.name() == b"isolationWindow" => {
+                        isolation_window = Some(types::IsolationWindow {
+                            cv_param: self.cv_params(empty)?,
+                        })
+                    }
+                    Next::Child(_, empty) => self.skip(empty)?,
+                    Next::Other => {}
+                    Next::End => break,
+                }
+            }
+        }
+
+        Ok(isolation_window)
+    }
+
+    fn binary_data_array_list(
+        &mut self,
+        count: String,
+        empty: bool,
+    ) -> Result<types::BinaryDataArrayList, MzMLError> {
+        let mut binary_data_array = Vec::new();
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"binaryDataArray" => {
+                        let encoded_length = required(&e, b"encodedLength")?;
+                        let array_length = attribute(&e, b"arrayLength")?;
+                        binary_data_array.push(self.binary_data_array(
+                            encoded_length,
+                            array_length,
+                            empty,
+                        )?);
+                    }
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::BinaryDataArrayList {
+            binary_data_array,
+            count,
+        })
+    }
+
+    fn binary_data_array(
+        &mut self,
+        encoded_length: String,
+        array_length: Option<String>,
+        empty: bool,
+    ) -> Result<types::BinaryDataArray, MzMLError> {
+        let mut referenceable_param_group_ref = Vec::new();
+        let mut cv_param = Vec::new();
+        let mut binary = None;
+
+        if !empty {
+            loop {
+                let (e, empty) = match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) => (e, empty),
+                    Next::Other => continue,
+                    Next::End => break,
+                };
+
+                match e.name() {
+                    b"cvParam" => {
+                        cv_param.push(cv_param_from(&e)?);
+                        self.skip(empty)?;
+                    }
+                    b"referenceableParamGroupRef" => {
+                        referenceable_param_group_ref.push(param_group_ref_from(&e)?);
+                        self.skip(empty)?;
+                    }
+                    b"binary" => binary = Some(types::Binary::new(self.text(empty)?)),
+                    _ => self.skip(empty)?,
+                }
+            }
+        }
+
+        Ok(types::BinaryDataArray {
+            encoded_length,
+            array_length,
+            referenceable_param_group_ref,
+            cv_param,
+            binary: binary.ok_or_else(|| missing_element("binary", "binaryDataArray"))?,
+        })
+    }
+
+    /// Reads the cvParams of an element that only holds params, e.g. activation.
+    fn cv_params(&mut self, empty: bool) -> Result<Vec<types::CVParam>, MzMLError> {
+        let mut cv_param = Vec::new();
+
+        if !empty {
+            loop {
+                match next(self.reader, self.buf, self.base_offset)? {
+                    Next::Child(e, empty) if e.name() == b"cvParam" => {
+                        cv_param.push(cv_param_from(&e)?);
+                        self.skip(empty)?;
+                    }
+                    Next::Child(_, empty) => self.skip(empty)?,
+                    Next::Other => {}
+                    Next::End => break,
+                }
+            }
+        }
+
+        Ok(cv_param)
+    }
+
+    /// Reads the text up to the end of the current element.
+    fn text(&mut self, empty: bool) -> Result<String, MzMLError> {
+        let mut text = String::new();
+
+        if !empty {
+            loop {
+                self.buf.clear();
+                match self.reader.read_event(self.buf) {
+                    Ok(Event::Text(t)) | Ok(Event::CData(t)) => {
+                        text.push_str(&String::from_utf8_lossy(t.escaped()))
+                    }
+                    Ok(Event::Start(_)) => self.skip(false)?,
+                    Ok(Event::End(_)) => break,
+                    Ok(Event::Eof) => return Err(truncated(self.reader, self.base_offset)),
+                    Ok(_) => {}
+                    Err(source) => return Err(xml_error(source, self.reader, self.base_offset)),
+                }
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// Skips to the end of the current element, unless it's empty.
+    fn skip(&mut self, empty: bool) -> Result<(), MzMLError> {
+        let mut depth = if empty { 0 } else { 1 };
+
+        while depth > 0 {
+            match next(self.reader, self.buf, self.base_offset)? {
+                Next::Child(_, false) => depth += 1,
+                Next::End => depth -= 1,
+                Next::Child(_, true) | Next::Other => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the next event into `buf`.
+fn next<'b, R: BufRead>(
+    reader: &mut quick_xml::Reader<R>,
+    buf: &'b mut Vec<u8>,
+    base_offset: u64,
+) -> Result<Next<'b>, MzMLError> {
+    buf.clear();
+    match reader.read_event(buf) {
+        Ok(Event::Start(e)) => Ok(Next::Child(e, false)),
+        Ok(Event::Empty(e)) => Ok(Next::Child(e, true)),
+        Ok(Event::End(_)) => Ok(Next::End),
+        Ok(Event::Eof) => Err(truncated(reader, base_offset)),
+        Ok(_) => Ok(Next::Other),
+        Err(source) => Err(xml_error(source, reader, base_offset)),
+    }
+}
+
+fn truncated<R: BufRead>(reader: &quick_xml::Reader<R>, base_offset: u64) -> MzMLError {
+    MzMLError::Truncated {
+        id: None,
+        offset: base_offset + reader.buffer_position() as u64,
+    }
+}
+
+fn xml_error<R: BufRead>(
+    source: quick_xml::Error,
+    reader: &quick_xml::Reader<R>,
+    base_offset: u64,
+) -> MzMLError {
+    MzMLError::Xml {
+        source,
+        offset: base_offset + reader.buffer_position() as u64,
+    }
+}
+
+fn cv_param_from(e: &BytesStart) -> Result<types::CVParam, MzMLError> {
+    Ok(types::CVParam::new(
+        required(e, b"cvRef")?,
+        required(e, b"accession")?,
+        required(e, b"name")?,
+        attribute(e, b"value")?,
+        attribute(e, b"unitAccession")?,
+        attribute(e, b"unitName")?,
+        attribute(e, b"unitCvRef")?,
+    ))
+}
+
+fn user_param_from(e: &BytesStart) -> Result<types::UserParam, MzMLError> {
+    Ok(types::UserParam {
+        name: required(e, b"name")?,
+        value: any_attribute(e, b"value")?.unwrap_or_default(),
+    })
+}
+
+fn param_group_ref_from(e: &BytesStart) -> Result<types::ReferenceableParamGroupRef, MzMLError> {
+    Ok(types::ReferenceableParamGroupRef {
+        group_ref: required(e, b"ref")?,
+    })
+}
+
+/// Returns an optional attribute, with empty values read as missing like the serde types do.
+fn attribute(e: &BytesStart, key: &[u8]) -> Result<Option<String>, MzMLError> {
+    Ok(any_attribute(e, key)?.filter(|value| !value.is_empty()))
+}
+
+fn required(e: &BytesStart, key: &[u8]) -> Result<String, MzMLError> {
+    any_attribute(e, key)?.ok_or_else(|| MzMLError::Schema {
+        message: format!(
+            "missing attribute {} on {}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(e.name())
+        ),
+        id: None,
+        offset: None,
+    })
+}
+
+fn any_attribute(e: &BytesStart, key: &[u8]) -> Result<Option<String>, MzMLError> {
+    attribute_value(e, key).map_err(|source| MzMLError::Schema {
+        message: format!(
+            "invalid attributes on {}: {}",
+            String::from_utf8_lossy(e.name()),
+            source
+        ),
+        id: None,
+        offset: None,
+    })
+}
+
+fn missing_element(element: &str, parent: &str) -> MzMLError {
+    MzMLError::Schema {
+        message: format!("missing element {} in {}", element, parent),
+        id: None,
+        offset: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::mzml_parser::error::MzMLError;
+    use crate::io::mzml_parser::parser::MzMLReader;
+    use crate::io::mzml_parser::types;
+
+    const SPECTRUM: &str = r#"<spectrum index="0" id="scan=2" defaultArrayLength="2">
+        <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2"></cvParam>
+        <userParam name="note" value="skipped"/>
+        <!-- a comment -->
+        <scanList count="1">
+          <scan>
+            <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="1.5" unitCvRef="UO" unitAccession="UO:0000031" unitName="minute"/>
+            <scanWindowList count="1">
+              <scanWindow>
+                <cvParam cvRef="MS" accession="MS:1000501" name="scan window lower limit" value="100"/>
+              </scanWindow>
+            </scanWindowList>
+          </scan>
+        </scanList>
+        <precursorList count="1">
+          <precursor spectrumRef="scan=1">
+            <isolationWindow/>
+            <selectedIonList count="1">
+              <selectedIon>
+                <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="445.34"/>
+              </selectedIon>
+            </selectedIonList>
+            <activation>
+              <cvParam cvRef="MS" accession="MS:1000133" name="collision-induced dissociation" value=""/>
+            </activation>
+          </precursor>
+        </precursorList>
+        <binaryDataArrayList count="1">
+          <binaryDataArray encodedLength="24">
+            <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+            <cvParam cvRef="MS" accession="MS:1000576" name="no compression" value=""/>
+            <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+            <binary>AAAAAAAAWUAAAAAAAMByQA==</binary>
+          </binaryDataArray>
+        </binaryDataArrayList>
+      </spectrum>"#;
+
+    #[test]
+    fn spectrum_test() {
+        let mzml = format!(
+            "<mzML><run><spectrumList count=\"1\">{}</spectrumList></run></mzML>",
+            SPECTRUM
+        );
+        let mut mzml_reader = MzMLReader::from_reader(mzml.as_bytes());
+
+        let spectrum = mzml_reader.read_spectrum().unwrap().unwrap();
+        let expected: types::Spectrum = quick_xml::de::from_str(SPECTRUM).unwrap();
+        assert_eq!(spectrum, expected);
+        assert_eq!(spectrum.user_param[0].name, "note");
+        assert_eq!(spectrum.user_param[0].value, "skipped");
+
+        assert_eq!(spectrum.scan_start_time(), Some(90.0));
+        assert_eq!(
+            spectrum.precursors()[0].selected_ions()[0].mz(),
+            Some(445.34)
+        );
+
+        let mz: Vec<f64> = spectrum.binary_data_array_list.binary_data_array[0]
+            .decode(2)
+            .unwrap();
+        assert_eq!(mz, vec![100.0, 300.0]);
+
+        assert!(mzml_reader.read_spectrum().unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_arrays_test() {
+        let mzml = br#"<mzML><run><chromatogramList count="1">
+      <chromatogram index="0" id="TIC" defaultArrayLength="0">
+      </chromatogram>
+    </chromatogramList></run></mzML>"#;
+
+        // Unlike spectra, chromatograms must have a binaryDataArrayList.
+        let mut mzml_reader = MzMLReader::from_reader(&mzml[..]);
+        match mzml_reader.read_chromatogram() {
+            Err(MzMLError::Schema { message, .. }) => {
+                assert_eq!(
+                    message,
+                    "missing element binaryDataArrayList in chromatogram"
+                );
+            }
+            other => panic!("expected a schema error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_attribute_test() {
+        let mzml = br#"<mzML><run><spectrumList count="2">
+      <spectrum index="0" id="scan=1" defaultArrayLength="0">
+        <cvParam cvRef="MS" name="ms level" value="1"/>
+        <binaryDataArrayList count="0"/>
+      </spectrum>
+      <spectrum index="1" id="scan=2" defaultArrayLength="0">
+        <binaryDataArrayList count="0"/>
+      </spectrum>
+    </spectrumList></run></mzML>"#;
+
+        let mut mzml_reader = MzMLReader::from_reader(&mzml[..]);
+
+        match mzml_reader.read_spectrum() {
+            Err(MzMLError::Schema { message, id, .. }) => {
+                assert_eq!(message, "missing attribute accession on cvParam");
+                assert_eq!(id.as_deref(), Some("scan=1"));
+            }
+            other => panic!("expected a schema error, got {:?}", other),
+        }
+
+        // The rest of the bad spectrum is skipped.
+        let spectrum = mzml_reader.read_spectrum().unwrap().unwrap();
+        assert_eq!(spectrum.id, "scan=2");
+    }
+}
@@ -0,0 +1,344 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Conversion from mzML spectra and chromatograms into the format independent
+//! `spectrum::Spectrum` and `chromatogram::Chromatogram`.
+//!
+//! The mzML fields are mapped to the metadata keys MGF files use, so the two can be handled the
+//! same way downstream.
+//!
+//! | Key            | mzML source                                        |
+//! |----------------|----------------------------------------------------|
+//! | `TITLE`        | spectrum title (MS:1000796), else the spectrum id  |
+//! | `INDEX`        | spectrum index                                     |
+//! | `MSLEVEL`      | ms level (MS:1000511)                              |
+//! | `RTINSECONDS`  | scan start time (MS:1000016) of the first scan     |
+//! | `POLARITY`     | `+` or `-` from positive/negative scan             |
+//! | `PEPMASS`      | selected ion m/z, and peak intensity when present  |
+//! | `CHARGE`       | selected ion charge state, e.g. `2+`               |
+//! | `ACTIVATION`   | dissociation methods, e.g. `CID` or `ETD,HCD`      |
+//! | `COLLISIONENERGY` | collision energy (MS:1000045)                   |
+//!
+//! Spectrum level userParams are kept as metadata too, keyed by their name.
+
+use std::convert::TryFrom;
+
+use crate::chromatogram;
+use crate::io::mzml_parser::error::MzMLError;
+use crate::io::mzml_parser::types;
+use crate::metadata::Metadata;
+use crate::spectrum;
+
+/// The m/z array accession.
+pub const MZ_ARRAY: &str = "MS:1000514";
+
+/// The intensity array accession.
+pub const INTENSITY_ARRAY: &str = "MS:1000515";
+
+/// The time array accession.
+pub const TIME_ARRAY: &str = "MS:1000595";
+
+impl TryFrom<&types::Spectrum> for spectrum::Spectrum {
+    type Error = MzMLError;
+
+    fn try_from(value: &types::Spectrum) -> Result<Self, Self::Error> {
+        let (mz, intensities) = decode_peaks(value)?;
+
+        Ok(spectrum::Spectrum::new(metadata(value), mz, intensities))
+    }
+}
+
+impl TryFrom<types::Spectrum> for spectrum::Spectrum {
+    type Error = MzMLError;
+
+    fn try_from(value: types::Spectrum) -> Result<Self, Self::Error> {
+        spectrum::Spectrum::try_from(&value)
+    }
+}
+
+/// Keeps the arrays encoded, taking the number of peaks from the defaultArrayLength.
+impl TryFrom<types::Spectrum> for spectrum::LazySpectrum {
+    type Error = MzMLError;
+
+    fn try_from(value: types::Spectrum) -> Result<Self, Self::Error> {
+        let n_peaks = value
+            .array_length()
+            .map_err(|e| e.with_location(&value.id, None))?;
+
+        Ok(spectrum::LazySpectrum::from_mzml(
+            metadata(&value),
+            n_peaks,
+            value,
+        ))
+    }
+}
+
+/// Decodes the m/z and intensity arrays of a spectrum, which has no peaks if it has no arrays
+/// and a defaultArrayLength of zero.
+pub(crate) fn decode_peaks(value: &types::Spectrum) -> Result<(Vec<f64>, Vec<f64>), MzMLError> {
+    let array_length = value
+        .array_length()
+        .map_err(|e| e.with_location(&value.id, None))?;
+
+    if array_length == 0 && value.binary_data_array_list.binary_data_array.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    decode_pair(
+        &value.binary_data_array_list,
+        (MZ_ARRAY, "m/z array"),
+        (INTENSITY_ARRAY, "intensity array"),
+        array_length,
+    )
+    .map_err(|e| e.with_location(&value.id, None))
+}
+
+impl TryFrom<&types::Chromatogram> for chromatogram::Chromatogram {
+    type Error = MzMLError;
+
+    fn try_from(value: &types::Chromatogram) -> Result<Self, Self::Error> {
+        let arrays = &value.binary_data_array_list;
+        let array_length = value
+            .array_length()
+            .map_err(|e| e.with_location(&value.id, None))?;
+
+        let (times, intensities) = decode_pair(
+            arrays,
+            (TIME_ARRAY, "time array"),
+            (INTENSITY_ARRAY, "intensity array"),
+            array_length,
+        )
+        .map_err(|e| e.with_location(&value.id, None))?;
+
+        let unit = arrays
+            .find(TIME_ARRAY)
+            .and_then(|array| array.cv_param.iter().find(|p| p.accession == TIME_ARRAY))
+            .and_then(|p| p.unit_accession.as_deref());
+        let times = times.into_iter().map(|t| types::seconds(t, unit)).collect();
+
+        let mut converted = chromatogram::Chromatogram::new(value.id.clone(), times, intensities);
+        converted.precursor_mz = value.precursor_mz();
+        converted.product_mz = value.product_mz();
+
+        Ok(converted)
+    }
+}
+
+impl TryFrom<types::Chromatogram> for chromatogram::Chromatogram {
+    type Error = MzMLError;
+
+    fn try_from(value: types::Chromatogram) -> Result<Self, Self::Error> {
+        chromatogram::Chromatogram::try_from(&value)
+    }
+}
+
+/// Decodes the two arrays with the given (accession, name), which must be the same length.
+fn decode_pair(
+    arrays: &types::BinaryDataArrayList,
+    first: (&str, &'static str),
+    second: (&str, &'static str),
+    array_length: usize,
+) -> Result<(Vec<f64>, Vec<f64>), MzMLError> {
+    let decode = |(accession, term): (&str, &'static str)| match arrays.find(accession) {
+        Some(array) => array.decode(array_length),
+        None => Err(MzMLError::missing_cv_term(term)),
+    };
+
+    let first = decode(first)?;
+    let second = decode(second)?;
+
+    if first.len() != second.len() {
+        return Err(MzMLError::ArrayLengthMismatch {
+            expected: first.len(),
+            found: second.len(),
+            id: None,
+            offset: None,
+        });
+    }
+
+    Ok((first, second))
+}
+
+/// Builds the MGF style metadata for an mzML spectrum.
+fn metadata(value: &types::Spectrum) -> Metadata {
+    let mut metadata = Metadata::new();
+
+    let title = value.title().unwrap_or(&value.id);
+    metadata.insert(String::from("TITLE"), title.to_string());
+    metadata.insert(String::from("INDEX"), value.index.clone());
+
+    if let Some(ms_level) = value.ms_level() {
+        metadata.insert(String::from("MSLEVEL"), ms_level.to_string());
+    }
+
+    if let Some(rt) = value.scan_start_time() {
+        metadata.insert(String::from("RTINSECONDS"), rt.to_string());
+    }
+
+    let positive = value.is_positive();
+    match positive {
+        Some(true) => metadata.insert(String::from("POLARITY"), String::from("+")),
+        Some(false) => metadata.insert(String::from("POLARITY"), String::from("-")),
+        None => None,
+    };
+
+    if let Some(precursor) = value.precursors().first() {
+        if let Some(ion) = precursor.selected_ions().first() {
+            let mz = ion
+                .mz()
+                .or_else(|| precursor.isolation_window.as_ref()?.target_mz());
+
+            if let Some(mz) = mz {
+                let pepmass = match ion.intensity() {
+                    Some(intensity) => format!("{} {}", mz, intensity),
+                    None => mz.to_string(),
+                };
+                metadata.insert(String::from("PEPMASS"), pepmass);
+            }
+
+            let sign = if positive == Some(false) { "-" } else { "+" };
+            if let Some(charge) = ion.charge() {
+                metadata.insert(String::from("CHARGE"), format!("{}{}", charge, sign));
+            } else if !ion.possible_charges().is_empty() {
+                let charges: Vec<String> = ion
+                    .possible_charges()
+                    .iter()
+                    .map(|c| format!("{}{}", c, sign))
+                    .collect();
+                metadata.insert(String::from("CHARGE"), charges.join(" and "));
+            }
+        }
+
+        let methods: Vec<String> = precursor
+            .activation
+            .dissociation_methods()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        if !methods.is_empty() {
+            metadata.insert(String::from("ACTIVATION"), methods.join(","));
+        }
+
+        if let Some(energy) = precursor.activation.collision_energy() {
+            metadata.insert(String::from("COLLISIONENERGY"), energy.to_string());
+        }
+    }
+
+    for param in value.user_param.iter() {
+        metadata.append(param.name.clone(), param.value.clone());
+    }
+
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    use crate::io::mzml_parser::MzMLReader;
+
+    #[test]
+    fn convert_spectrum_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        let ms1 =
+            spectrum::Spectrum::try_from(mzml_reader.read_spectrum().unwrap().unwrap()).unwrap();
+        assert_eq!(ms1.mz, vec![200.1, 445.34, 500.25, 1000.5]);
+        assert_eq!(ms1.intensities, vec![100.0, 2000.0, 300.0, 40.0]);
+        assert_eq!(ms1.metadata["MSLEVEL"], "1");
+        assert_eq!(ms1.metadata["RTINSECONDS"], "30");
+        assert_eq!(ms1.metadata["POLARITY"], "+");
+        assert!(!ms1.metadata.contains_key("PEPMASS"));
+
+        let ms2 =
+            spectrum::Spectrum::try_from(mzml_reader.read_spectrum().unwrap().unwrap()).unwrap();
+        assert_eq!(ms2.mz, vec![150.0, 250.5, 329.2]);
+        assert_eq!(
+            ms2.metadata["TITLE"],
+            "controllerType=0 controllerNumber=1 scan=2"
+        );
+        assert_eq!(ms2.metadata["INDEX"], "1");
+        assert_eq!(ms2.metadata["PEPMASS"], "445.34 120053");
+        assert_eq!(ms2.metadata["CHARGE"], "2+");
+        assert_eq!(ms2.metadata["ACTIVATION"], "CID");
+        assert_eq!(ms2.metadata["COLLISIONENERGY"], "35");
+    }
+
+    #[test]
+    fn arrays_by_accession_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        let mut spectrum = mzml_reader.read_spectrum().unwrap().unwrap();
+        spectrum.binary_data_array_list.binary_data_array.reverse();
+
+        let converted = spectrum::Spectrum::try_from(&spectrum).unwrap();
+        assert_eq!(converted.mz, vec![200.1, 445.34, 500.25, 1000.5]);
+
+        spectrum.binary_data_array_list.binary_data_array.pop();
+        match spectrum::Spectrum::try_from(&spectrum) {
+            Err(MzMLError::MissingCvTerm { term, id, .. }) => {
+                assert_eq!(term, "m/z array");
+                assert_eq!(id.unwrap(), "controllerType=0 controllerNumber=1 scan=1");
+            }
+            other => panic!("expected a missing m/z array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn convert_chromatogram_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut chromatograms = MzMLReader::from_reader(BufReader::new(file)).chromatograms();
+
+        let tic =
+            chromatogram::Chromatogram::try_from(chromatograms.next().unwrap().unwrap()).unwrap();
+        assert_eq!(tic.id, "TIC");
+        assert_eq!(tic.times, vec![30.0, 36.0, 42.0]);
+        assert_eq!(tic.intensities, vec![2440.0, 60.0, 18.0]);
+        assert_eq!(tic.precursor_mz, None);
+
+        let srm =
+            chromatogram::Chromatogram::try_from(chromatograms.next().unwrap().unwrap()).unwrap();
+        assert_eq!(srm.precursor_mz, Some(445.34));
+        assert_eq!(srm.product_mz, Some(329.2));
+        assert_eq!(srm.intensities, vec![11.0, 30.0, 12.0]);
+    }
+
+    #[test]
+    fn lazy_spectrum_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(&d).expect("Couldn't open test file.");
+        let lazy: Vec<spectrum::LazySpectrum> = MzMLReader::from_reader(BufReader::new(file))
+            .lazy_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+
+        let file = File::open(&d).expect("Couldn't open test file.");
+        let eager: Vec<spectrum::Spectrum> = MzMLReader::from_reader(BufReader::new(file))
+            .spectra()
+            .map(|s| spectrum::Spectrum::try_from(s.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(lazy.len(), eager.len());
+        for (lazy, eager) in lazy.into_iter().zip(eager) {
+            assert_eq!(lazy.n_peaks(), eager.mz.len());
+            assert_eq!(lazy.metadata, eager.metadata);
+            assert_eq!(lazy.into_spectrum().unwrap(), eager);
+        }
+    }
+}
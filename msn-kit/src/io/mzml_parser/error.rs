@@ -0,0 +1,263 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! The error type returned when reading mzML.
+
+use std::error::Error;
+use std::fmt;
+
+/// An error encountered while reading or decoding mzML.
+///
+/// Where it is known, errors carry the id of the spectrum (or chromatogram) being read and the
+/// byte offset of its start tag, so a bad record can be found in a large file.
+#[derive(Debug)]
+pub enum MzMLError {
+    /// Reading from the underlying reader failed.
+    Io(std::io::Error),
+
+    /// The document isn't well formed XML.
+    Xml {
+        source: quick_xml::Error,
+        offset: u64,
+    },
+
+    /// An element couldn't be deserialized into the mzML types.
+    Schema {
+        message: String,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// A required controlled vocabulary term is missing, e.g. the binary data type.
+    MissingCvTerm {
+        term: &'static str,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// A referenceableParamGroupRef names a group that wasn't declared.
+    MissingParamGroup {
+        group: String,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// A binary array isn't valid base64.
+    Base64 {
+        source: base64::DecodeError,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// A binary array couldn't be decompressed.
+    Decompression {
+        source: std::io::Error,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// The document ended before the spectrum list did, e.g. an interrupted download. The
+    /// offset is the length of the document.
+    Truncated { id: Option<String>, offset: u64 },
+
+    /// The base64 content of an array doesn't have the length given by its encodedLength.
+    EncodedLengthMismatch {
+        expected: usize,
+        found: usize,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+
+    /// A decoded array doesn't have the expected number of values.
+    ArrayLengthMismatch {
+        expected: usize,
+        found: usize,
+        id: Option<String>,
+        offset: Option<u64>,
+    },
+}
+
+impl MzMLError {
+    pub(crate) fn missing_cv_term(term: &'static str) -> Self {
+        MzMLError::MissingCvTerm {
+            term,
+            id: None,
+            offset: None,
+        }
+    }
+
+    pub(crate) fn base64(source: base64::DecodeError) -> Self {
+        MzMLError::Base64 {
+            source,
+            id: None,
+            offset: None,
+        }
+    }
+
+    pub(crate) fn decompression(source: std::io::Error) -> Self {
+        MzMLError::Decompression {
+            source,
+            id: None,
+            offset: None,
+        }
+    }
+
+    /// Sets the id and offset of the record the error happened in, if they aren't already set.
+    pub fn with_location(mut self, record_id: &str, record_offset: Option<u64>) -> Self {
+        match &mut self {
+            MzMLError::Io(_) | MzMLError::Xml { .. } => {}
+            MzMLError::Truncated { id, .. } => {
+                if id.is_none() {
+                    *id = Some(record_id.to_string());
+                }
+            }
+            MzMLError::Schema { id, offset, .. }
+            | MzMLError::MissingCvTerm { id, offset, .. }
+            | MzMLError::MissingParamGroup { id, offset, .. }
+            | MzMLError::Base64 { id, offset, .. }
+            | MzMLError::Decompression { id, offset, .. }
+            | MzMLError::EncodedLengthMismatch { id, offset, .. }
+            | MzMLError::ArrayLengthMismatch { id, offset, .. } => {
+                if id.is_none() {
+                    *id = Some(record_id.to_string());
+                }
+                if offset.is_none() {
+                    *offset = record_offset;
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Returns the id of the record the error happened in, if known.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            MzMLError::Io(_) | MzMLError::Xml { .. } => None,
+            MzMLError::Truncated { id, .. }
+            | MzMLError::Schema { id, .. }
+            | MzMLError::MissingCvTerm { id, .. }
+            | MzMLError::MissingParamGroup { id, .. }
+            | MzMLError::Base64 { id, .. }
+            | MzMLError::Decompression { id, .. }
+            | MzMLError::EncodedLengthMismatch { id, .. }
+            | MzMLError::ArrayLengthMismatch { id, .. } => id.as_deref(),
+        }
+    }
+
+    /// Returns the byte offset of the record the error happened in, if known.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            MzMLError::Io(_) => None,
+            MzMLError::Xml { offset, .. } | MzMLError::Truncated { offset, .. } => Some(*offset),
+            MzMLError::Schema { offset, .. }
+            | MzMLError::MissingCvTerm { offset, .. }
+            | MzMLError::MissingParamGroup { offset, .. }
+            | MzMLError::Base64 { offset, .. }
+            | MzMLError::Decompression { offset, .. }
+            | MzMLError::EncodedLengthMismatch { offset, .. }
+            | MzMLError::ArrayLengthMismatch { offset, .. } => *offset,
+        }
+    }
+
+    fn fmt_location(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = self.id() {
+            write!(f, " in {}", id)?;
+        }
+        if let Some(offset) = self.offset() {
+            write!(f, " at byte {}", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for MzMLError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MzMLError::Io(e) => return write!(f, "io error reading mzML: {}", e),
+            MzMLError::Xml { source, .. } => write!(f, "invalid XML: {}", source)?,
+            MzMLError::Schema { message, .. } => write!(f, "invalid mzML: {}", message)?,
+            MzMLError::MissingCvTerm { term, .. } => write!(f, "missing cvParam for {}", term)?,
+            MzMLError::MissingParamGroup { group, .. } => write!(
+                f,
+                "reference to undeclared referenceableParamGroup {}",
+                group
+            )?,
+            MzMLError::Base64 { source, .. } => write!(f, "invalid base64 array: {}", source)?,
+            MzMLError::Decompression { source, .. } => {
+                write!(f, "unable to decompress array: {}", source)?
+            }
+            MzMLError::Truncated { .. } => write!(f, "unexpected end of document")?,
+            MzMLError::EncodedLengthMismatch {
+                expected, found, ..
+            } => write!(
+                f,
+                "expected {} base64 characters from encodedLength, found {}",
+                expected, found
+            )?,
+            MzMLError::ArrayLengthMismatch {
+                expected, found, ..
+            } => write!(f, "expected array of length {}, found {}", expected, found)?,
+        }
+
+        self.fmt_location(f)
+    }
+}
+
+impl Error for MzMLError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MzMLError::Io(e) => Some(e),
+            MzMLError::Xml { source, .. } => Some(source),
+            MzMLError::Base64 { source, .. } => Some(source),
+            MzMLError::Decompression { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MzMLError {
+    fn from(e: std::io::Error) -> Self {
+        MzMLError::Io(e)
+    }
+}
+
+impl From<MzMLError> for std::io::Error {
+    fn from(e: MzMLError) -> Self {
+        match e {
+            MzMLError::Io(e) => e,
+            e @ MzMLError::Truncated { .. } => {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e)
+            }
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_location_test() {
+        let e = MzMLError::MissingCvTerm {
+            term: "binary data type",
+            id: None,
+            offset: None,
+        }
+        .with_location("scan=1", Some(120));
+
+        assert_eq!(e.id(), Some("scan=1"));
+        assert_eq!(e.offset(), Some(120));
+        assert_eq!(
+            e.to_string(),
+            "missing cvParam for binary data type in scan=1 at byte 120"
+        );
+
+        let e = e.with_location("scan=2", Some(500));
+        assert_eq!(e.id(), Some("scan=1"));
+
+        let io_error = std::io::Error::from(e);
+        assert_eq!(io_error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
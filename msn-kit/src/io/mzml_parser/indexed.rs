@@ -0,0 +1,450 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Random access into mzML files through the indexedmzML offset index.
+
+use quick_xml::events::Event;
+
+use std::collections::HashMap;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::io::mzml_parser::error::MzMLError;
+use crate::io::mzml_parser::parser::{attribute_value, MzMLReader};
+use crate::io::mzml_parser::types;
+
+/// How many bytes from the end of the file to search for `<indexListOffset>`.
+const FOOTER_SEARCH_SIZE: u64 = 4096;
+
+/// The byte offset of a single spectrum or chromatogram element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub id: String,
+    pub offset: u64,
+}
+
+/// An ordered list of offsets, which can also be looked up by id.
+#[derive(Debug, Default, PartialEq)]
+pub struct OffsetIndex {
+    entries: Vec<IndexEntry>,
+    by_id: HashMap<String, usize>,
+}
+
+impl OffsetIndex {
+    /// Adds an entry to the end of the index.
+    pub fn push(&mut self, id: String, offset: u64) {
+        self.by_id.insert(id.clone(), self.entries.len());
+        self.entries.push(IndexEntry { id, offset });
+    }
+
+    /// Returns the entry at position `i`.
+    pub fn get(&self, i: usize) -> Option<&IndexEntry> {
+        self.entries.get(i)
+    }
+
+    /// Returns the position of the entry with `id`.
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.by_id.get(id).copied()
+    }
+
+    /// Returns the entries in document order.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Reads spectra out of an mzML file by position or id, without parsing the spectra before it.
+///
+/// The offsets come from the `<indexList>` footer of indexedmzML files. If the footer is missing
+/// or its offsets don't point at the elements they claim to, the offsets are rebuilt by scanning
+/// the file once. Every spectrum read by offset is checked against the id in the index, so an
+/// offset that is wrong anywhere in the footer also causes the offsets to be rebuilt.
+pub struct IndexedMzMLReader<R: Read + Seek> {
+    reader: R,
+    spectrum_index: OffsetIndex,
+    chromatogram_index: OffsetIndex,
+    param_groups: types::ParamGroups,
+    scanned: bool,
+}
+
+impl<R> IndexedMzMLReader<R>
+where
+    R: Read + Seek,
+{
+    /// Create a new IndexedMzMLReader, loading the offset index.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - An object that implements Read and Seek, e.g. a `File`.
+    ///
+    pub fn new(mut reader: R) -> Result<Self, MzMLError> {
+        let (spectrum_index, chromatogram_index, scanned) = match read_footer_index(&mut reader)? {
+            Some((spectra, chromatograms))
+                if is_valid_index(&mut reader, &spectra, b"spectrum")?
+                    && is_valid_index(&mut reader, &chromatograms, b"chromatogram")? =>
+            {
+                (spectra, chromatograms, false)
+            }
+            _ => {
+                let (spectra, chromatograms) = scan_index(&mut reader)?;
+                (spectra, chromatograms, true)
+            }
+        };
+
+        // The param groups are declared in the header, which is skipped when seeking to a spectrum.
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header_reader = MzMLReader::from_reader(BufReader::new(&mut reader));
+        header_reader.read_param_groups()?;
+        let param_groups = header_reader.into_param_groups();
+
+        Ok(Self {
+            reader,
+            spectrum_index,
+            chromatogram_index,
+            param_groups,
+            scanned,
+        })
+    }
+
+    /// Returns the offsets of the spectra.
+    pub fn spectrum_index(&self) -> &OffsetIndex {
+        &self.spectrum_index
+    }
+
+    /// Returns the offsets of the chromatograms.
+    pub fn chromatogram_index(&self) -> &OffsetIndex {
+        &self.chromatogram_index
+    }
+
+    /// Returns the number of spectra in the file.
+    pub fn len(&self) -> usize {
+        self.spectrum_index.len()
+    }
+
+    /// Returns true if the file has no spectra.
+    pub fn is_empty(&self) -> bool {
+        self.spectrum_index.is_empty()
+    }
+
+    /// Read the spectrum at position `i` in the spectrum list.
+    pub fn get_spectrum_by_index(&mut self, i: usize) -> Result<types::Spectrum, MzMLError> {
+        match self.read_entry(|index| index.get(i).cloned())? {
+            Some(spectrum) => Ok(spectrum),
+            None => Err(MzMLError::Io(Error::new(
+                ErrorKind::NotFound,
+                format!("No spectrum at index {}", i),
+            ))),
+        }
+    }
+
+    /// Read the spectrum with the native id `id`.
+    pub fn get_spectrum_by_id(&mut self, id: &str) -> Result<types::Spectrum, MzMLError> {
+        let lookup = |index: &OffsetIndex| index.position(id).and_then(|i| index.get(i)).cloned();
+
+        match self.read_entry(lookup)? {
+            Some(spectrum) => Ok(spectrum),
+            None => Err(MzMLError::Io(Error::new(
+                ErrorKind::NotFound,
+                format!("No spectrum with id {}", id),
+            ))),
+        }
+    }
+
+    /// Reads the spectrum of the entry found by `lookup`, or None if there is no entry.
+    ///
+    /// If the spectrum at the entry's offset doesn't have the entry's id, the footer offsets are
+    /// wrong, so the index is rebuilt by scanning the file and the lookup is tried again.
+    fn read_entry<F>(&mut self, lookup: F) -> Result<Option<types::Spectrum>, MzMLError>
+    where
+        F: Fn(&OffsetIndex) -> Option<IndexEntry>,
+    {
+        loop {
+            let entry = match lookup(&self.spectrum_index) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            match self.read_spectrum_at(entry.offset) {
+                Ok(spectrum) if spectrum.id == entry.id => return Ok(Some(spectrum)),
+                _ if !self.scanned => {
+                    let (spectra, chromatograms) = scan_index(&mut self.reader)?;
+                    self.spectrum_index = spectra;
+                    self.chromatogram_index = chromatograms;
+                    self.scanned = true;
+                }
+                Ok(spectrum) => {
+                    return Err(MzMLError::Io(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Expected spectrum {} at offset {}, found {}",
+                            entry.id, entry.offset, spectrum.id
+                        ),
+                    )))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_spectrum_at(&mut self, offset: u64) -> Result<types::Spectrum, MzMLError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(&mut self.reader))
+            .with_param_groups(std::mem::take(&mut self.param_groups))
+            .with_base_offset(offset);
+        let spectrum = mzml_reader.read_spectrum();
+        self.param_groups = mzml_reader.into_param_groups();
+
+        match spectrum? {
+            Some(spectrum) => Ok(spectrum),
+            None => Err(MzMLError::Io(Error::new(
+                ErrorKind::NotFound,
+                format!("No spectrum at offset {}", offset),
+            ))),
+        }
+    }
+}
+
+/// Reads the `<indexList>` pointed to by `<indexListOffset>`, if the file has one.
+fn read_footer_index<R: Read + Seek>(
+    reader: &mut R,
+) -> std::io::Result<Option<(OffsetIndex, OffsetIndex)>> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(
+        file_size.saturating_sub(FOOTER_SEARCH_SIZE),
+    ))?;
+
+    let mut footer = Vec::new();
+    reader.read_to_end(&mut footer)?;
+    let footer = String::from_utf8_lossy(&footer);
+
+    let index_offset = footer
+        .rfind("<indexListOffset>")
+        .map(|start| &footer[start + "<indexListOffset>".len()..])
+        .and_then(|rest| rest.split_once("</indexListOffset>"))
+        .and_then(|(offset, _)| offset.trim().parse::<u64>().ok());
+
+    let index_offset = match index_offset {
+        Some(offset) if offset < file_size => offset,
+        _ => return Ok(None),
+    };
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let mut xml_reader = quick_xml::Reader::from_reader(BufReader::new(reader));
+    xml_reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut text_buf = Vec::new();
+
+    let mut spectra = OffsetIndex::default();
+    let mut chromatograms = OffsetIndex::default();
+
+    let mut seen_index_list = false;
+    let mut index_name = Vec::new();
+
+    loop {
+        match xml_reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) if e.name() == b"indexList" => seen_index_list = true,
+            // The offset must point directly at the index list, otherwise it is stale.
+            Ok(Event::Start(_)) | Ok(Event::Empty(_)) if !seen_index_list => return Ok(None),
+            Ok(Event::Start(e)) if e.name() == b"index" => {
+                index_name = attribute_value(&e, b"name")
+                    .map_err(Error::other)?
+                    .unwrap_or_default()
+                    .into_bytes();
+            }
+            Ok(Event::Start(e)) if e.name() == b"offset" => {
+                let id = attribute_value(&e, b"idRef")
+                    .map_err(Error::other)?
+                    .unwrap_or_default();
+                let offset = xml_reader
+                    .read_text(b"offset", &mut text_buf)
+                    .map_err(Error::other)?;
+                text_buf.clear();
+
+                let offset = match offset.trim().parse::<u64>() {
+                    Ok(offset) => offset,
+                    Err(_) => return Ok(None),
+                };
+
+                match index_name.as_slice() {
+                    b"spectrum" => spectra.push(id, offset),
+                    b"chromatogram" => chromatograms.push(id, offset),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(e)) if e.name() == b"indexList" => break,
+            Ok(Event::Eof) | Err(_) => return Ok(None),
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(Some((spectra, chromatograms)))
+}
+
+/// Checks that the first and last offsets in `index` point at elements named `name` with the
+/// expected id. The other offsets are checked as their spectra are read.
+fn is_valid_index<R: Read + Seek>(
+    reader: &mut R,
+    index: &OffsetIndex,
+    name: &[u8],
+) -> std::io::Result<bool> {
+    let mut to_check = Vec::new();
+    to_check.extend(index.entries().first());
+    to_check.extend(index.entries().last());
+
+    for entry in to_check {
+        reader.seek(SeekFrom::Start(entry.offset))?;
+
+        let mut xml_reader = quick_xml::Reader::from_reader(BufReader::new(&mut *reader));
+        let mut buf = Vec::new();
+
+        match xml_reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) if e.name() == name => {
+                if attribute_value(&e, b"id").map_err(Error::other)?.as_deref()
+                    != Some(entry.id.as_str())
+                {
+                    return Ok(false);
+                }
+            }
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Builds the offset index by scanning the whole file for spectrum and chromatogram elements.
+fn scan_index<R: Read + Seek>(reader: &mut R) -> std::io::Result<(OffsetIndex, OffsetIndex)> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut xml_reader = quick_xml::Reader::from_reader(BufReader::new(reader));
+    xml_reader.trim_text(false);
+
+    let mut buf = Vec::new();
+
+    let mut spectra = OffsetIndex::default();
+    let mut chromatograms = OffsetIndex::default();
+
+    loop {
+        // With untrimmed text, the position before a start event is the position of its '<'.
+        let position = xml_reader.buffer_position() as u64;
+
+        match xml_reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) if e.name() == b"spectrum" => {
+                let id = attribute_value(&e, b"id")
+                    .map_err(Error::other)?
+                    .unwrap_or_default();
+                spectra.push(id, position);
+            }
+            Ok(Event::Start(e)) if e.name() == b"chromatogram" => {
+                let id = attribute_value(&e, b"id")
+                    .map_err(Error::other)?
+                    .unwrap_or_default();
+                chromatograms.push(id, position);
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(Error::other(e)),
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok((spectra, chromatograms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn test_file(name: &str) -> PathBuf {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push(name);
+        d
+    }
+
+    #[test]
+    fn footer_index_test() {
+        let file = File::open(test_file("test.indexed.mzml.xml")).unwrap();
+        let mut reader = IndexedMzMLReader::new(file).unwrap();
+
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.spectrum_index().get(1).unwrap().offset, 6236);
+        assert_eq!(reader.chromatogram_index().len(), 2);
+
+        let spectrum = reader.get_spectrum_by_index(1).unwrap();
+        assert_eq!(spectrum.id, "controllerType=0 controllerNumber=1 scan=2");
+
+        let spectrum = reader
+            .get_spectrum_by_id("controllerType=0 controllerNumber=1 scan=1")
+            .unwrap();
+        assert_eq!(spectrum.index, "0");
+
+        // The ms level of the third spectrum is declared in a referenceableParamGroup.
+        let spectrum = reader.get_spectrum_by_index(2).unwrap();
+        assert!(spectrum
+            .cv_param
+            .iter()
+            .any(|p| p.accession == "MS:1000511"));
+
+        assert!(reader.get_spectrum_by_index(3).is_err());
+        assert!(reader.get_spectrum_by_id("scan=4").is_err());
+    }
+
+    #[test]
+    fn stale_index_test() {
+        let contents = std::fs::read_to_string(test_file("test.indexed.mzml.xml")).unwrap();
+        let shifted = contents.replacen("<cvList", "<!-- shifted -->\n    <cvList", 1);
+
+        let mut reader = IndexedMzMLReader::new(Cursor::new(shifted.into_bytes())).unwrap();
+
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.chromatogram_index().len(), 2);
+
+        let spectrum = reader.get_spectrum_by_index(1).unwrap();
+        assert_eq!(spectrum.id, "controllerType=0 controllerNumber=1 scan=2");
+    }
+
+    #[test]
+    fn wrong_middle_offset_test() {
+        let contents = std::fs::read_to_string(test_file("test.indexed.mzml.xml")).unwrap();
+        // Point the second spectrum at the first, leaving the first and last offsets intact.
+        let corrupted = contents.replacen(">6236</offset>", ">3813</offset>", 1);
+
+        let mut reader = IndexedMzMLReader::new(Cursor::new(corrupted.into_bytes())).unwrap();
+
+        let spectrum = reader.get_spectrum_by_index(1).unwrap();
+        assert_eq!(spectrum.id, "controllerType=0 controllerNumber=1 scan=2");
+        assert_eq!(reader.spectrum_index().get(1).unwrap().offset, 6236);
+
+        let spectrum = reader
+            .get_spectrum_by_id("controllerType=0 controllerNumber=1 scan=2")
+            .unwrap();
+        assert_eq!(spectrum.index, "1");
+    }
+
+    #[test]
+    fn missing_index_test() {
+        let file = File::open(test_file("test.mzml.xml")).unwrap();
+        let mut reader = IndexedMzMLReader::new(file).unwrap();
+
+        assert_eq!(reader.len(), 1);
+
+        let spectrum = reader
+            .get_spectrum_by_id("declaration=0 collection=0 scan=0")
+            .unwrap();
+        assert_eq!(spectrum.default_array_length, "15");
+    }
+}
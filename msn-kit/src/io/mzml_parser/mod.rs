@@ -3,9 +3,20 @@
 //! Module for reading and writing mzml files.
 
 mod binary_conversion;
+mod builder;
+mod conversion;
+mod error;
+mod indexed;
+mod numpress;
 mod parser;
 mod types;
+mod writer;
 
 pub use crate::io::mzml_parser::binary_conversion::*;
+pub use crate::io::mzml_parser::conversion::*;
+pub use crate::io::mzml_parser::error::*;
+pub use crate::io::mzml_parser::indexed::*;
+pub use crate::io::mzml_parser::numpress::*;
 pub use crate::io::mzml_parser::parser::*;
 pub use crate::io::mzml_parser::types::*;
+pub use crate::io::mzml_parser::writer::*;
@@ -0,0 +1,390 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! MS-Numpress encoding of mzML arrays, following the reference implementation at
+//! <https://github.com/ms-numpress/ms-numpress>.
+//!
+//! * Linear prediction (MS:1002312) stores fixed point values as the difference from a linear
+//!   extrapolation of the previous two, which suits sorted arrays like m/z.
+//! * Positive integer (MS:1002313) rounds to integers, which suits ion counts.
+//! * Short logged float (MS:1002314) stores `log(x + 1)` as a 16 bit fixed point, which suits
+//!   intensities.
+//!
+//! Linear prediction and positive integer pack integers as variable length runs of half bytes.
+
+use std::convert::TryInto;
+
+use crate::io::mzml_parser::error::MzMLError;
+
+/// Returns the fixed point that gives the most precision for linear prediction of `data`,
+/// without overflowing.
+pub fn optimal_numpress_linear_fixed_point(data: &[f64]) -> f64 {
+    match data.len() {
+        0 => 0.0,
+        1 => (f64::from(i32::MAX) / data[0]).floor(),
+        _ => {
+            let mut max = data[0].max(data[1]);
+            for i in 2..data.len() {
+                let extrapolated = data[i - 1] + (data[i - 1] - data[i - 2]);
+                let diff = data[i] - extrapolated;
+                max = max.max((diff.abs() + 1.0).ceil());
+            }
+
+            (f64::from(i32::MAX) / max).floor()
+        }
+    }
+}
+
+/// Encodes `data` with linear prediction at `fixed_point`, see
+/// `optimal_numpress_linear_fixed_point`.
+pub fn encode_numpress_linear(data: &[f64], fixed_point: f64) -> Vec<u8> {
+    let mut result = Vec::with_capacity(8 + data.len() * 5);
+    result.extend_from_slice(&fixed_point.to_be_bytes());
+
+    if data.is_empty() {
+        return result;
+    }
+
+    let mut ints = [0i64; 3];
+
+    ints[1] = (data[0] * fixed_point + 0.5) as i64;
+    result.extend_from_slice(&(ints[1] as u32).to_le_bytes());
+
+    if data.len() == 1 {
+        return result;
+    }
+
+    ints[2] = (data[1] * fixed_point + 0.5) as i64;
+    result.extend_from_slice(&(ints[2] as u32).to_le_bytes());
+
+    let mut half_bytes = HalfBytes::new(&mut result);
+    for value in &data[2..] {
+        ints[0] = ints[1];
+        ints[1] = ints[2];
+        ints[2] = (value * fixed_point + 0.5) as i64;
+
+        let extrapolated = ints[1] + (ints[1] - ints[0]);
+        half_bytes.push_int((ints[2] - extrapolated) as i32 as u32);
+    }
+    half_bytes.finish();
+
+    result
+}
+
+/// Decodes linear prediction encoded bytes.
+pub fn decode_numpress_linear(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    if data.len() == 8 {
+        return Ok(Vec::new());
+    }
+    if data.len() < 12 || (data.len() > 12 && data.len() < 16) {
+        return Err(corrupt("linear prediction"));
+    }
+
+    let fixed_point = f64::from_be_bytes(data[0..8].try_into().unwrap());
+
+    let mut ints = [0i64; 3];
+    let mut result = Vec::new();
+
+    ints[1] = i64::from(u32::from_le_bytes(data[8..12].try_into().unwrap()));
+    result.push(ints[1] as f64 / fixed_point);
+
+    if data.len() == 12 {
+        return Ok(result);
+    }
+
+    ints[2] = i64::from(u32::from_le_bytes(data[12..16].try_into().unwrap()));
+    result.push(ints[2] as f64 / fixed_point);
+
+    let mut reader = HalfByteReader::new(&data[16..]);
+    while let Some(diff) = reader.next_int("linear prediction")? {
+        ints[0] = ints[1];
+        ints[1] = ints[2];
+
+        let extrapolated = ints[1] + (ints[1] - ints[0]);
+        ints[2] = extrapolated + i64::from(diff as i32);
+        result.push(ints[2] as f64 / fixed_point);
+    }
+
+    Ok(result)
+}
+
+/// Encodes `data`, rounded to non-negative integers, as positive integers.
+pub fn encode_numpress_pic(data: &[f64]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len() * 5);
+
+    let mut half_bytes = HalfBytes::new(&mut result);
+    for value in data {
+        half_bytes.push_int((value + 0.5) as u32);
+    }
+    half_bytes.finish();
+
+    result
+}
+
+/// Decodes positive integer encoded bytes.
+pub fn decode_numpress_pic(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    let mut result = Vec::new();
+
+    let mut reader = HalfByteReader::new(data);
+    while let Some(value) = reader.next_int("positive integer")? {
+        result.push(f64::from(value));
+    }
+
+    Ok(result)
+}
+
+/// Returns the fixed point that gives the most precision for short logged float encoding of
+/// `data`, without overflowing.
+pub fn optimal_numpress_slof_fixed_point(data: &[f64]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let max = data.iter().fold(1.0f64, |max, x| max.max((x + 1.0).ln()));
+
+    (f64::from(u16::MAX) / max).floor()
+}
+
+/// Encodes `data`, which must be non-negative, as short logged floats at `fixed_point`, see
+/// `optimal_numpress_slof_fixed_point`.
+pub fn encode_numpress_slof(data: &[f64], fixed_point: f64) -> Vec<u8> {
+    let mut result = Vec::with_capacity(8 + data.len() * 2);
+    result.extend_from_slice(&fixed_point.to_be_bytes());
+
+    for value in data {
+        let x = ((value + 1.0).ln() * fixed_point + 0.5) as u16;
+        result.extend_from_slice(&x.to_le_bytes());
+    }
+
+    result
+}
+
+/// Decodes short logged float encoded bytes.
+pub fn decode_numpress_slof(data: &[u8]) -> Result<Vec<f64>, MzMLError> {
+    if data.len() < 8 || !data.len().is_multiple_of(2) {
+        return Err(corrupt("short logged float"));
+    }
+
+    let fixed_point = f64::from_be_bytes(data[0..8].try_into().unwrap());
+
+    Ok(data[8..]
+        .chunks_exact(2)
+        .map(|x| (f64::from(u16::from_le_bytes([x[0], x[1]])) / fixed_point).exp() - 1.0)
+        .collect())
+}
+
+fn corrupt(encoding: &str) -> MzMLError {
+    MzMLError::decompression(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("corrupt MS-Numpress {} data", encoding),
+    ))
+}
+
+/// Packs integers into half bytes, two to a byte, high half first.
+struct HalfBytes<'a> {
+    result: &'a mut Vec<u8>,
+    pending: Option<u8>,
+}
+
+impl<'a> HalfBytes<'a> {
+    fn new(result: &'a mut Vec<u8>) -> Self {
+        Self {
+            result,
+            pending: None,
+        }
+    }
+
+    fn push(&mut self, half_byte: u8) {
+        match self.pending.take() {
+            Some(high) => self.result.push((high << 4) | (half_byte & 0xf)),
+            None => self.pending = Some(half_byte & 0xf),
+        }
+    }
+
+    /// Pushes a count of leading zero (0-8) or leading 0xf (9-15) half bytes, followed by the
+    /// remaining half bytes of `x`, least significant first.
+    fn push_int(&mut self, x: u32) {
+        let (head, skipped) = match x & 0xf000_0000 {
+            0 => {
+                let skipped = (0..8)
+                    .find(|i| x & (0xf000_0000 >> (4 * i)) != 0)
+                    .unwrap_or(8);
+                (skipped, skipped)
+            }
+            0xf000_0000 => {
+                let skipped = (0..8)
+                    .find(|i| {
+                        let m = 0xf000_0000 >> (4 * i);
+                        x & m != m
+                    })
+                    .unwrap_or(7);
+                (skipped + 8, skipped)
+            }
+            _ => (0, 0),
+        };
+
+        self.push(head as u8);
+        for i in 0..(8 - skipped) {
+            self.push((x >> (4 * i)) as u8);
+        }
+    }
+
+    /// Writes out a trailing half byte, padded with zero.
+    fn finish(&mut self) {
+        if let Some(high) = self.pending.take() {
+            self.result.push(high << 4);
+        }
+    }
+}
+
+/// Reads integers written by `HalfBytes`.
+struct HalfByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> HalfByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.data.get(self.position / 2)?;
+        let half_byte = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0xf
+        };
+        self.position += 1;
+
+        Some(half_byte)
+    }
+
+    fn next_int(&mut self, encoding: &str) -> Result<Option<u32>, MzMLError> {
+        // A zero half byte in the last byte is padding, a real zero head needs 8 more.
+        if self.position + 1 == self.data.len() * 2 && self.data[self.position / 2] & 0xf == 0 {
+            return Ok(None);
+        }
+
+        let head = match self.next() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+
+        let (mut x, skipped) = if head <= 8 {
+            (0u32, u32::from(head))
+        } else {
+            let skipped = u32::from(head - 8);
+            let leading = (0..skipped).fold(0u32, |x, i| x | (0xf000_0000 >> (4 * i)));
+            (leading, skipped)
+        };
+
+        for i in 0..(8 - skipped) {
+            match self.next() {
+                Some(half_byte) => x |= u32::from(half_byte) << (4 * i),
+                None => return Err(corrupt(encoding)),
+            }
+        }
+
+        Ok(Some(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f64], expected: &[f64], tolerance: f64) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() <= tolerance, "{} != {}", a, e);
+        }
+    }
+
+    #[test]
+    fn linear_reference_test() {
+        // The vector from the reference implementation's MSNumpressTest.cpp.
+        let mzs = [100.0, 200.0, 300.00005, 400.00010];
+        let encoded = encode_numpress_linear(&mzs, 100000.0);
+
+        assert_eq!(
+            encoded,
+            vec![
+                0x40, 0xf8, 0x6a, 0x00, 0x00, 0x00, 0x00, 0x00, // 100000.0
+                0x80, 0x96, 0x98, 0x00, // 10000000
+                0x00, 0x2d, 0x31, 0x01, // 20000000
+                0x75, 0x80, // +5, +0
+            ]
+        );
+
+        let decoded = decode_numpress_linear(&encoded).unwrap();
+        assert_close(&decoded, &mzs, 0.5 / 100000.0);
+    }
+
+    #[test]
+    fn linear_round_trip_test() {
+        let mzs = [
+            150.0612, 150.0615, 151.2, 200.114, 445.3402, 445.8417, 1000.5, 1999.9999,
+        ];
+        let fixed_point = optimal_numpress_linear_fixed_point(&mzs);
+
+        let decoded = decode_numpress_linear(&encode_numpress_linear(&mzs, fixed_point)).unwrap();
+        assert_close(&decoded, &mzs, 0.5 / fixed_point);
+
+        for short in [&mzs[..0], &mzs[..1], &mzs[..2]].iter() {
+            let encoded = encode_numpress_linear(short, 1000.0);
+            assert_eq!(decode_numpress_linear(&encoded).unwrap().len(), short.len());
+        }
+
+        assert!(decode_numpress_linear(&[0x40, 0x8f, 0x40, 0, 0, 0, 0, 0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn pic_test() {
+        assert_eq!(
+            encode_numpress_pic(&[1.0, 2.0, 3.0]),
+            vec![0x71, 0x72, 0x73]
+        );
+        assert_eq!(encode_numpress_pic(&[0.0]), vec![0x80]);
+
+        // 0x12345678 doesn't start with a zero half byte, so all 8 are stored.
+        let encoded = encode_numpress_pic(&[305419896.0, 16.4]);
+        assert_eq!(encoded, vec![0x08, 0x76, 0x54, 0x32, 0x16, 0x01]);
+        assert_eq!(
+            decode_numpress_pic(&encoded).unwrap(),
+            vec![305419896.0, 16.0]
+        );
+
+        let counts = [0.0, 1.0, 12.0, 130.0, 4096.0, 2000000.0, 7.6];
+        let decoded = decode_numpress_pic(&encode_numpress_pic(&counts)).unwrap();
+        assert_close(&decoded, &counts, 0.5);
+
+        assert!(decode_numpress_pic(&[0x21]).is_err());
+    }
+
+    #[test]
+    fn slof_test() {
+        let e_minus_one = 1.0f64.exp() - 1.0;
+        let encoded = encode_numpress_slof(&[0.0, e_minus_one], 10000.0);
+
+        assert_eq!(
+            encoded,
+            vec![0x40, 0xc3, 0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x27]
+        );
+        assert_eq!(
+            decode_numpress_slof(&encoded).unwrap(),
+            vec![0.0, e_minus_one]
+        );
+
+        let intensities = [0.0, 10.5, 1234.0, 100000.0, 3.2e7];
+        let fixed_point = optimal_numpress_slof_fixed_point(&intensities);
+        let decoded =
+            decode_numpress_slof(&encode_numpress_slof(&intensities, fixed_point)).unwrap();
+
+        // The relative error is bounded by the precision of the logged value.
+        for (d, i) in decoded.iter().zip(intensities.iter()) {
+            assert!((d - i).abs() <= (i + 1.0) * (0.5 / fixed_point).exp_m1() + 1e-9);
+        }
+
+        assert!(decode_numpress_slof(&encoded[..11]).is_err());
+    }
+}
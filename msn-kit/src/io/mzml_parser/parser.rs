@@ -2,15 +2,34 @@
 // All Rights Reserved
 
 use quick_xml;
-use quick_xml::events::Event;
+use quick_xml::events::{BytesStart, Event};
+use serde::de::DeserializeOwned;
 
 use std::io::BufRead;
 use std::io::Cursor;
+use std::path::Path;
 
+use crate::io::mzml_parser::builder::Builder;
+use crate::io::mzml_parser::error::MzMLError;
 use crate::io::mzml_parser::types;
+use crate::io::{compression, SpectrumReader};
+use crate::spectrum;
 
 pub struct MzMLReader<R: BufRead> {
     reader: quick_xml::Reader<R>,
+    param_groups: types::ParamGroups,
+    header: Option<types::MzMLHeader>,
+    buf: Vec<u8>,
+    base_offset: u64,
+    spectra_done: bool,
+    chromatograms_done: bool,
+}
+
+impl MzMLReader<Box<dyn BufRead>> {
+    /// Open the mzML file at `path`, decompressing it if it's compressed.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::from_reader(compression::open(path)?))
+    }
 }
 
 impl<R> MzMLReader<R>
@@ -19,7 +38,15 @@ where
 {
     // new creates a new MzMLReader from an quick_xml::Reader
     pub fn new(reader: quick_xml::Reader<R>) -> Self {
-        Self { reader }
+        Self {
+            reader,
+            param_groups: types::ParamGroups::new(),
+            header: None,
+            buf: Vec::new(),
+            base_offset: 0,
+            spectra_done: false,
+            chromatograms_done: false,
+        }
     }
 
     pub fn from_reader(buf_reader: R) -> Self {
@@ -29,69 +56,496 @@ where
         Self::new(xml_reader)
     }
 
-    pub fn read_spectrum(&mut self) -> std::io::Result<types::Spectrum> {
+    /// Use already parsed param groups, e.g. when starting to read in the middle of a file.
+    pub fn with_param_groups(mut self, param_groups: types::ParamGroups) -> Self {
+        self.param_groups = param_groups;
+        self
+    }
+
+    /// Set the byte offset the reader starts at, so errors report offsets into the whole file
+    /// when reading from the middle of it.
+    pub fn with_base_offset(mut self, base_offset: u64) -> Self {
+        self.base_offset = base_offset;
+        self
+    }
+
+    /// Returns the referenceableParamGroups read so far.
+    pub fn param_groups(&self) -> &types::ParamGroups {
+        &self.param_groups
+    }
+
+    /// Consumes the reader, returning the referenceableParamGroups read so far.
+    pub fn into_param_groups(self) -> types::ParamGroups {
+        self.param_groups
+    }
+
+    /// Read up to the end of the referenceableParamGroupList, or the start of the run if the file
+    /// doesn't have one.
+    pub fn read_param_groups(&mut self) -> Result<&types::ParamGroups, MzMLError> {
+        if self.header.is_some() {
+            return Ok(&self.param_groups);
+        }
+
         let mut buf = Vec::new();
 
         loop {
+            let offset = self.position();
             match self.reader.read_event(&mut buf) {
-                Ok(Event::Start(e)) if e.name() != b"spectrum" => {
-                    continue;
-                }
-                Ok(Event::Start(e)) => {
-                    let end = b"spectrum";
-
-                    let mut buf3 = Vec::new();
-                    let mut writer = quick_xml::Writer::new(Cursor::new(&mut buf3));
-
-                    writer.write_event(Event::Start(e)).unwrap();
-
-                    loop {
-                        match self.reader.read_event(&mut buf) {
-                            Ok(Event::Start(e)) => {
-                                writer.write_event(Event::Start(e)).unwrap();
-                            }
-                            Ok(Event::Empty(e)) => {
-                                writer.write_event(Event::Empty(e)).unwrap();
-                            }
-                            Ok(Event::Text(e)) => {
-                                writer.write_event(Event::Text(e)).unwrap();
-                            }
-                            Ok(Event::End(e)) => {
-                                if e.name() == end {
-                                    writer.write_event(Event::End(e)).unwrap();
-                                    break;
-                                } else {
-                                    writer.write_event(Event::End(e)).unwrap();
-                                }
-                            }
-                            Ok(Event::Eof) => {
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::Other,
-                                    "Unexpected Eof Event",
-                                ))
-                            }
-                            Err(_) => panic!("fuck"),
-                            Ok(e) => panic!("event: {:?}", e),
-                        }
-                    }
+                Ok(Event::Start(e)) if e.name() == b"referenceableParamGroupList" => {
+                    let e = e.into_owned();
+                    self.read_param_group_list(e, offset)?;
+                    break;
+                }
+                Ok(Event::Start(e)) if e.name() == b"run" => break,
+                Ok(Event::Eof) => break,
+                Err(source) => return Err(MzMLError::Xml { source, offset }),
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        Ok(&self.param_groups)
+    }
+
+    /// Read everything before the spectrum list, i.e. the cvList, fileDescription, samples,
+    /// software, instrument configurations, data processing and the run's attributes.
+    ///
+    /// This must be called before reading any spectra or chromatograms. Once read, the header is
+    /// kept and returned by later calls and by `header`.
+    pub fn read_header(&mut self) -> Result<&types::MzMLHeader, MzMLError> {
+        if self.header.is_none() {
+            let header = self.parse_header()?;
+            self.header = Some(header);
+        }
+
+        Ok(self.header.as_ref().unwrap())
+    }
+
+    /// Returns the header, if `read_header` has been called.
+    pub fn header(&self) -> Option<&types::MzMLHeader> {
+        self.header.as_ref()
+    }
+
+    fn parse_header(&mut self) -> Result<types::MzMLHeader, MzMLError> {
+        let mut header = types::MzMLHeader::default();
+        let mut buf = Vec::new();
+
+        loop {
+            let offset = self.position();
+            let attribute = |e: &BytesStart, key: &[u8]| {
+                attribute_value(e, key).map_err(|source| MzMLError::Xml { source, offset })
+            };
 
-                    let c = Cursor::new(buf3);
-                    let spectrum: types::Spectrum = quick_xml::de::from_reader(c).unwrap();
-                    return Ok(spectrum);
+            match self.reader.read_event(&mut buf) {
+                Ok(Event::Start(e)) if e.name() == b"mzML" => {
+                    header.id = attribute(&e, b"id")?;
+                    header.accession = attribute(&e, b"accession")?;
+                    header.version = attribute(&e, b"version")?;
+                }
+                Ok(Event::Start(e)) if e.name() == b"referenceableParamGroupList" => {
+                    let e = e.into_owned();
+                    self.read_param_group_list(e, offset)?;
+                }
+                Ok(Event::Start(e)) if e.name() == b"cvList" => {
+                    let list: types::CvList = self.read_header_element(e.into_owned(), offset)?;
+                    header.cvs = list.cv;
+                }
+                Ok(Event::Start(e)) if e.name() == b"fileDescription" => {
+                    header.file_description =
+                        Some(self.read_header_element(e.into_owned(), offset)?);
+                }
+                Ok(Event::Start(e)) if e.name() == b"sampleList" => {
+                    let list: types::SampleList =
+                        self.read_header_element(e.into_owned(), offset)?;
+                    header.samples = list.sample;
+                }
+                Ok(Event::Start(e)) if e.name() == b"softwareList" => {
+                    let list: types::SoftwareList =
+                        self.read_header_element(e.into_owned(), offset)?;
+                    header.software = list.software;
+                }
+                Ok(Event::Start(e)) if e.name() == b"instrumentConfigurationList" => {
+                    let list: types::InstrumentConfigurationList =
+                        self.read_header_element(e.into_owned(), offset)?;
+                    header.instrument_configurations = list.instrument_configuration;
+                }
+                Ok(Event::Start(e)) if e.name() == b"dataProcessingList" => {
+                    let list: types::DataProcessingList =
+                        self.read_header_element(e.into_owned(), offset)?;
+                    header.data_processing = list.data_processing;
+                }
+                Ok(Event::Start(e)) if e.name() == b"run" => {
+                    header.run = Some(types::RunHeader {
+                        id: attribute(&e, b"id")?.unwrap_or_default(),
+                        default_instrument_configuration_ref: attribute(
+                            &e,
+                            b"defaultInstrumentConfigurationRef",
+                        )?,
+                        default_source_file_ref: attribute(&e, b"defaultSourceFileRef")?,
+                        sample_ref: attribute(&e, b"sampleRef")?,
+                        start_time_stamp: attribute(&e, b"startTimeStamp")?,
+                    });
+                    break;
                 }
                 Ok(Event::Eof) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Unexpected Eof Event",
-                    ))
+                    return Err(MzMLError::Truncated {
+                        id: None,
+                        offset: self.position(),
+                    })
+                }
+                Err(source) => return Err(MzMLError::Xml { source, offset }),
+                _ => {}
+            }
+            buf.clear();
+        }
+
+        // Resolved once the whole header is read, so the order of the lists doesn't matter.
+        for configuration in header.instrument_configurations.iter_mut() {
+            configuration.resolve_param_groups(&self.param_groups)?;
+        }
+
+        Ok(header)
+    }
+
+    fn read_header_element<T: DeserializeOwned>(
+        &mut self,
+        start: BytesStart<'static>,
+        offset: u64,
+    ) -> Result<T, MzMLError> {
+        let element = self.read_element(start)?;
+
+        quick_xml::de::from_reader(Cursor::new(element)).map_err(|e| MzMLError::Schema {
+            message: e.to_string(),
+            id: None,
+            offset: Some(offset),
+        })
+    }
+
+    /// Returns an iterator over the remaining spectra.
+    pub fn spectra(self) -> Spectra<R> {
+        Spectra { reader: self }
+    }
+
+    /// Returns an iterator over the remaining spectra with their metadata converted and their
+    /// arrays left encoded until `LazySpectrum::into_spectrum` is called.
+    pub fn lazy_spectra(self) -> LazySpectra<R> {
+        LazySpectra { reader: self }
+    }
+
+    /// Returns an iterator over the remaining chromatograms, skipping any spectra before them.
+    pub fn chromatograms(self) -> Chromatograms<R> {
+        Chromatograms { reader: self }
+    }
+
+    /// Read the next spectrum, or `None` once the end of the spectrum list is reached.
+    ///
+    /// Errors carry the spectrum's id and the byte offset of its start tag where they are known.
+    /// If the document ends before the spectrum list does, a `MzMLError::Truncated` is returned.
+    /// After an XML, io or truncation error the reader can't continue, and returns `None`.
+    pub fn read_spectrum(&mut self) -> Result<Option<types::Spectrum>, MzMLError> {
+        if self.spectra_done {
+            return Ok(None);
+        }
+
+        let result = match self.next_element(b"spectrum", b"spectrumList") {
+            Ok(Some(start)) => {
+                let (id, offset) = (start.id.clone(), start.offset);
+                self.build_spectrum(start)
+                    .map(Some)
+                    .map_err(|e| e.with_location(&id, Some(offset)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        self.spectra_done = is_final(&result);
+
+        result
+    }
+
+    /// Read the next chromatogram, or `None` once the end of the chromatogram list is reached.
+    ///
+    /// Spectra before the chromatogram list are skipped. Errors are as for `read_spectrum`.
+    pub fn read_chromatogram(&mut self) -> Result<Option<types::Chromatogram>, MzMLError> {
+        if self.chromatograms_done {
+            return Ok(None);
+        }
+
+        let result = match self.next_element(b"chromatogram", b"chromatogramList") {
+            Ok(Some(start)) => {
+                let (id, offset) = (start.id.clone(), start.offset);
+                self.build_chromatogram(start)
+                    .map(Some)
+                    .map_err(|e| e.with_location(&id, Some(offset)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        self.chromatograms_done = is_final(&result);
+
+        result
+    }
+
+    /// Builds the spectrum that starts with `start` from the events up to its end tag.
+    fn build_spectrum(&mut self, start: Start) -> Result<types::Spectrum, MzMLError> {
+        let index = required(start.index, "index")?;
+        let default_array_length = required(start.default_array_length, "defaultArrayLength")?;
+
+        let mut spectrum = Builder::new(&mut self.reader, &mut self.buf, self.base_offset)
+            .spectrum(start.id, index, default_array_length)?;
+        spectrum.resolve_param_groups(&self.param_groups)?;
+
+        Ok(spectrum)
+    }
+
+    /// Builds the chromatogram that starts with `start` from the events up to its end tag.
+    fn build_chromatogram(&mut self, start: Start) -> Result<types::Chromatogram, MzMLError> {
+        let index = required(start.index, "index")?;
+        let default_array_length = required(start.default_array_length, "defaultArrayLength")?;
+
+        let mut chromatogram = Builder::new(&mut self.reader, &mut self.buf, self.base_offset)
+            .chromatogram(start.id, index, default_array_length)?;
+        chromatogram.resolve_param_groups(&self.param_groups)?;
+
+        Ok(chromatogram)
+    }
+
+    /// Reads up to the start tag of the next `name` element, or `None` at the end of `list`.
+    ///
+    /// Other spectra or chromatograms on the way are skipped.
+    fn next_element(&mut self, name: &[u8], list: &[u8]) -> Result<Option<Start>, MzMLError> {
+        loop {
+            // With untrimmed text, the position before a start event is the position of its '<'.
+            let offset = self.position();
+            self.buf.clear();
+            match self.reader.read_event(&mut self.buf) {
+                Ok(Event::Start(e)) if e.name() == b"referenceableParamGroupList" => {
+                    let e = e.into_owned();
+                    self.read_param_group_list(e, offset)?;
+                }
+                Ok(Event::Start(e)) if e.name() == name => {
+                    let attribute = |key| {
+                        attribute_value(&e, key).map_err(|source| MzMLError::Xml { source, offset })
+                    };
+
+                    return Ok(Some(Start {
+                        id: attribute(b"id")?.unwrap_or_default(),
+                        offset,
+                        index: attribute(b"index")?,
+                        default_array_length: attribute(b"defaultArrayLength")?,
+                    }));
+                }
+                Ok(Event::Start(e)) if matches!(e.name(), b"spectrum" | b"chromatogram") => {
+                    let end = e.name().to_vec();
+                    let mut skip_buf = Vec::new();
+                    match self.reader.read_to_end(&end, &mut skip_buf) {
+                        Ok(()) => {}
+                        Err(quick_xml::Error::UnexpectedEof(_)) => {
+                            return Err(MzMLError::Truncated {
+                                id: None,
+                                offset: self.position(),
+                            })
+                        }
+                        Err(source) => return Err(MzMLError::Xml { source, offset }),
+                    }
                 }
-                Err(e) => println!("{:?}", e),
-                _ => {
-                    buf.clear();
+                Ok(Event::Empty(e)) if e.name() == list => return Ok(None),
+                Ok(Event::End(e)) if e.name() == list || matches!(e.name(), b"run" | b"mzML") => {
+                    return Ok(None)
                 }
+                Ok(Event::Eof) => {
+                    return Err(MzMLError::Truncated {
+                        id: None,
+                        offset: self.position(),
+                    })
+                }
+                Err(source) => return Err(MzMLError::Xml { source, offset }),
+                _ => {}
             }
         }
     }
+
+    fn read_param_group_list(
+        &mut self,
+        start: BytesStart<'static>,
+        offset: u64,
+    ) -> Result<(), MzMLError> {
+        let list: types::ReferenceableParamGroupList = self.read_header_element(start, offset)?;
+        self.param_groups.extend(list.into_param_groups());
+
+        Ok(())
+    }
+
+    /// Re-serializes the element that starts with `start`, up to its end tag.
+    fn read_element(&mut self, start: BytesStart<'static>) -> Result<Vec<u8>, MzMLError> {
+        let end = start.name().to_vec();
+
+        let mut buf = Vec::new();
+        let mut element = Vec::new();
+        let mut writer = quick_xml::Writer::new(Cursor::new(&mut element));
+
+        let mut event = Event::Start(start);
+        loop {
+            let done = matches!(&event, Event::End(e) if e.name() == end.as_slice());
+
+            match event {
+                // Comments and processing instructions carry no data.
+                Event::Comment(_) | Event::PI(_) => {}
+                e => writer.write_event(e).map_err(|source| MzMLError::Xml {
+                    source,
+                    offset: self.position(),
+                })?,
+            }
+
+            if done {
+                break;
+            }
+
+            buf.clear();
+            event = match self.reader.read_event(&mut buf) {
+                Ok(Event::Eof) => {
+                    return Err(MzMLError::Truncated {
+                        id: None,
+                        offset: self.position(),
+                    })
+                }
+                Ok(e) => e,
+                Err(source) => {
+                    return Err(MzMLError::Xml {
+                        source,
+                        offset: self.position(),
+                    })
+                }
+            };
+        }
+
+        Ok(element)
+    }
+
+    /// The current byte offset into the file.
+    fn position(&self) -> u64 {
+        self.base_offset + self.reader.buffer_position() as u64
+    }
+}
+
+/// The attributes of a spectrum or chromatogram start tag.
+struct Start {
+    id: String,
+    offset: u64,
+    index: Option<String>,
+    default_array_length: Option<String>,
+}
+
+/// Returns `value`, or a schema error if the required `attribute` is missing.
+fn required(value: Option<String>, attribute: &str) -> Result<String, MzMLError> {
+    value.ok_or_else(|| MzMLError::Schema {
+        message: format!("missing attribute {}", attribute),
+        id: None,
+        offset: None,
+    })
+}
+
+/// Returns true if the reader can't continue after `result`.
+fn is_final<T>(result: &Result<Option<T>, MzMLError>) -> bool {
+    matches!(
+        result,
+        Ok(None)
+            | Err(MzMLError::Io(_))
+            | Err(MzMLError::Xml { .. })
+            | Err(MzMLError::Truncated { .. })
+    )
+}
+
+/// Converts the spectra as they're read, leaving their arrays encoded when read lazily.
+impl<R> SpectrumReader for MzMLReader<R>
+where
+    R: BufRead,
+{
+    fn next_spectrum(&mut self) -> std::io::Result<Option<spectrum::Spectrum>> {
+        match self.read_spectrum()? {
+            Some(s) => Ok(Some(spectrum::Spectrum::try_from(s)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn next_lazy_spectrum(&mut self) -> std::io::Result<Option<spectrum::LazySpectrum>> {
+        match self.read_spectrum()? {
+            Some(s) => Ok(Some(spectrum::LazySpectrum::try_from(s)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// An iterator over the spectra of an mzML file.
+pub struct Spectra<R>
+where
+    R: BufRead,
+{
+    reader: MzMLReader<R>,
+}
+
+impl<R> Iterator for Spectra<R>
+where
+    R: BufRead,
+{
+    type Item = Result<types::Spectrum, MzMLError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_spectrum().transpose()
+    }
+}
+
+/// An iterator over the spectra of an mzML file, without decoding their arrays.
+pub struct LazySpectra<R>
+where
+    R: BufRead,
+{
+    reader: MzMLReader<R>,
+}
+
+impl<R> Iterator for LazySpectra<R>
+where
+    R: BufRead,
+{
+    type Item = Result<spectrum::LazySpectrum, MzMLError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader
+            .read_spectrum()
+            .transpose()
+            .map(|s| s.and_then(spectrum::LazySpectrum::try_from))
+    }
+}
+
+/// An iterator over the chromatograms of an mzML file.
+pub struct Chromatograms<R>
+where
+    R: BufRead,
+{
+    reader: MzMLReader<R>,
+}
+
+impl<R> Iterator for Chromatograms<R>
+where
+    R: BufRead,
+{
+    type Item = Result<types::Chromatogram, MzMLError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_chromatogram().transpose()
+    }
+}
+
+/// Returns the unescaped value of the attribute `key`.
+pub(crate) fn attribute_value(e: &BytesStart, key: &[u8]) -> quick_xml::Result<Option<String>> {
+    for attribute in e.attributes() {
+        let attribute = attribute?;
+        if attribute.key == key {
+            let value = attribute.unescaped_value()?;
+            return Ok(Some(String::from_utf8_lossy(&value).into_owned()));
+        }
+    }
+
+    Ok(None)
 }
 
 #[cfg(test)]
@@ -118,7 +572,7 @@ mod tests {
         let spectrum = mzml_reader.read_spectrum();
         assert!(spectrum.is_ok());
 
-        let spectrum = spectrum.unwrap();
+        let spectrum = spectrum.unwrap().unwrap();
 
         let data = spectrum.binary_data_array_list.binary_data_array[0]
             .binary_array_to_vector()
@@ -130,6 +584,303 @@ mod tests {
 
         assert_eq!(expected, data);
 
+        let lowest_wavelength = spectrum
+            .cv_param
+            .iter()
+            .filter(|p| p.accession == "MS:1000619")
+            .count();
+        assert_eq!(lowest_wavelength, 2);
+
         Ok(())
     }
+
+    #[test]
+    fn precursor_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        let ms1 = mzml_reader.read_spectrum().unwrap().unwrap();
+        assert_eq!(ms1.ms_level(), Some(1));
+        assert!(ms1.precursors().is_empty());
+
+        let ms2 = mzml_reader.read_spectrum().unwrap().unwrap();
+        assert_eq!(ms2.ms_level(), Some(2));
+
+        let precursor = &ms2.precursors()[0];
+        assert_eq!(
+            precursor.spectrum_ref.as_deref(),
+            Some("controllerType=0 controllerNumber=1 scan=1")
+        );
+
+        let window = precursor.isolation_window.as_ref().unwrap();
+        assert_eq!(window.target_mz(), Some(445.34));
+        assert_eq!(window.lower_offset(), Some(1.0));
+        assert_eq!(window.upper_offset(), Some(1.0));
+
+        let ion = &precursor.selected_ions()[0];
+        assert_eq!(ion.mz(), Some(445.34));
+        assert_eq!(ion.charge(), Some(2));
+        assert_eq!(ion.intensity(), Some(120053.0));
+
+        assert_eq!(
+            precursor.activation.dissociation_methods(),
+            vec![types::DissociationMethod::CID]
+        );
+        assert_eq!(precursor.activation.collision_energy(), Some(35.0));
+
+        let ms2 = mzml_reader.read_spectrum().unwrap().unwrap();
+        let precursor = &ms2.precursors()[0];
+        assert_eq!(
+            precursor.activation.dissociation_methods(),
+            vec![types::DissociationMethod::HCD]
+        );
+        assert_eq!(precursor.selected_ions()[0].charge(), Some(3));
+    }
+
+    #[test]
+    fn chromatogram_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(&d).expect("Couldn't open test file.");
+        let ids: Vec<String> = MzMLReader::from_reader(BufReader::new(file))
+            .chromatograms()
+            .map(|c| c.unwrap().id)
+            .collect();
+        assert_eq!(ids, vec!["TIC", "SRM SIC Q1=445.34 Q3=329.2"]);
+
+        let file = File::open(&d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+        for _ in 0..3 {
+            assert!(mzml_reader.read_spectrum().unwrap().is_some());
+        }
+        assert!(mzml_reader.read_spectrum().unwrap().is_none());
+
+        let tic = mzml_reader.read_chromatogram().unwrap().unwrap();
+        assert_eq!(tic.id, "TIC");
+        assert!(tic.precursor_mz().is_none());
+
+        let srm = mzml_reader.read_chromatogram().unwrap().unwrap();
+        assert_eq!(srm.precursor_mz(), Some(445.34));
+        assert_eq!(srm.product_mz(), Some(329.2));
+        assert!(mzml_reader.read_chromatogram().unwrap().is_none());
+        assert!(mzml_reader.read_chromatogram().unwrap().is_none());
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+        let chromatogram = mzml_reader.read_chromatogram().unwrap().unwrap();
+        assert_eq!(chromatogram.id, "242nm");
+        assert!(chromatogram.precursor_mz().is_none());
+    }
+
+    #[test]
+    fn header_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        let header = mzml_reader.read_header().unwrap();
+        assert_eq!(header.id.as_deref(), Some("handcraftedpda"));
+        assert_eq!(header.accession.as_deref(), Some("0815"));
+        assert_eq!(header.cvs.len(), 2);
+        assert_eq!(header.cvs[1].id, "UO");
+
+        let source_files = header.source_files();
+        assert_eq!(source_files.len(), 2);
+        assert_eq!(source_files[1].name, "analysis.baf");
+        assert_eq!(
+            source_files[1].sha1(),
+            Some("71be39fb2700ab2f3c8b2234b91274968b6899b1")
+        );
+
+        let contacts = &header.file_description.as_ref().unwrap().contact;
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].email(), Some("wpennington@higglesworth.edu"));
+        assert_eq!(contacts[1].organization(), Some("da"));
+
+        assert_eq!(header.samples.len(), 2);
+        assert_eq!(header.samples[0].name.as_deref(), Some("Sample1"));
+        assert_eq!(header.software("CompassXport").unwrap().version, "2.4.9");
+
+        let instrument = header.default_instrument_configuration().unwrap();
+        assert_eq!(instrument.model(), Some("micrOTOF-Q"));
+        assert_eq!(instrument.serial_number(), Some("23433"));
+        assert_eq!(
+            instrument.sources()[0].names(),
+            vec!["electrospray inlet", "electrospray ionization"]
+        );
+        assert_eq!(instrument.analyzers().len(), 2);
+        assert_eq!(instrument.detectors().len(), 2);
+
+        let run = header.run.as_ref().unwrap();
+        assert_eq!(run.id, "Exp01-PDA");
+        assert_eq!(run.sample_ref.as_deref(), Some("sample1"));
+
+        // Spectra and chromatograms can still be read after the header.
+        assert!(mzml_reader.read_spectrum().unwrap().is_some());
+        assert!(mzml_reader.read_spectrum().unwrap().is_none());
+        assert!(mzml_reader.read_chromatogram().unwrap().is_some());
+    }
+
+    #[test]
+    fn header_param_group_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        let header = mzml_reader.read_header().unwrap();
+        let instrument = header.default_instrument_configuration().unwrap();
+        assert_eq!(instrument.model(), Some("LTQ Orbitrap Velos"));
+        assert_eq!(instrument.serial_number(), Some("SN06061F"));
+        assert_eq!(instrument.analyzers()[0].names(), vec!["orbitrap"]);
+        assert_eq!(header.data_processing[0].id, "pwiz_Reader_conversion");
+        assert_eq!(header.software[0].name(), Some("ProteoWizard software"));
+
+        assert_eq!(mzml_reader.param_groups().len(), 2);
+        assert_eq!(mzml_reader.spectra().count(), 3);
+    }
+
+    #[test]
+    fn param_group_ref_test() {
+        let mzml = br#"<mzML>
+  <referenceableParamGroupList count="2">
+    <referenceableParamGroup id="ms2">
+      <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2"/>
+    </referenceableParamGroup>
+    <referenceableParamGroup id="f64">
+      <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" value=""/>
+      <cvParam cvRef="MS" accession="MS:1000576" name="no compression" value=""/>
+    </referenceableParamGroup>
+  </referenceableParamGroupList>
+  <run id="r">
+    <spectrumList count="1">
+      <spectrum index="0" id="scan=1" defaultArrayLength="2">
+        <referenceableParamGroupRef ref="ms2"/>
+        <binaryDataArrayList count="1">
+          <binaryDataArray encodedLength="24">
+            <referenceableParamGroupRef ref="f64"/>
+            <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" value=""/>
+            <binary>AAAAAAAAWUAAAAAAAABpQA==</binary>
+          </binaryDataArray>
+        </binaryDataArrayList>
+      </spectrum>
+    </spectrumList>
+  </run>
+</mzML>"#;
+
+        let mut mzml_reader = MzMLReader::from_reader(&mzml[..]);
+        let spectrum = mzml_reader.read_spectrum().unwrap().unwrap();
+
+        assert_eq!(mzml_reader.param_groups().len(), 2);
+        assert_eq!(spectrum.cv_param[0].accession, "MS:1000511");
+
+        let data = spectrum.binary_data_array_list.binary_data_array[0]
+            .binary_array_to_vector()
+            .unwrap();
+        assert_eq!(data, vec![100.0, 200.0]);
+    }
+
+    #[test]
+    fn error_location_test() {
+        let mzml = br#"<mzML>
+  <run id="r">
+    <spectrumList count="3">
+      <spectrum index="0" id="scan=1" defaultArrayLength="0">
+      </spectrum>
+      <spectrum index="1" id="scan=2" defaultArrayLength="0">
+        <cvParam cvRef="MS" name="ms level" value="1"/>
+      </spectrum>
+      <spectrum index="2" id="scan=3" defaultArrayLength="0">
+        <binaryDataArrayList count="0">
+      </spectrum>
+    </spectrumList>
+  </run>
+</mzML>"#;
+
+        let mut mzml_reader = MzMLReader::from_reader(&mzml[..]);
+
+        // The binaryDataArrayList is optional for spectra.
+        let empty = mzml_reader.read_spectrum().unwrap().unwrap();
+        assert!(empty.binary_data_array_list.binary_data_array.is_empty());
+        assert!(spectrum::Spectrum::try_from(empty).unwrap().mz.is_empty());
+
+        match mzml_reader.read_spectrum() {
+            Err(MzMLError::Schema { id, offset, .. }) => {
+                assert_eq!(id.as_deref(), Some("scan=2"));
+                assert_eq!(offset, Some(137));
+            }
+            other => panic!("expected a schema error, got {:?}", other),
+        }
+
+        assert!(matches!(
+            mzml_reader.read_spectrum(),
+            Err(MzMLError::Xml { .. })
+        ));
+    }
+
+    #[test]
+    fn spectra_end_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+
+        let file = File::open(d).expect("Couldn't open test file.");
+        let mut mzml_reader = MzMLReader::from_reader(BufReader::new(file));
+
+        for _ in 0..3 {
+            assert!(mzml_reader.read_spectrum().unwrap().is_some());
+        }
+        assert!(mzml_reader.read_spectrum().unwrap().is_none());
+        assert!(mzml_reader.read_spectrum().unwrap().is_none());
+
+        let d = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/test/test.mzml.xml");
+        let file = File::open(d).expect("Couldn't open test file.");
+        let spectra: Vec<_> = MzMLReader::from_reader(BufReader::new(file))
+            .spectra()
+            .collect();
+        assert_eq!(spectra.len(), 1);
+        assert!(spectra[0].is_ok());
+    }
+
+    #[test]
+    fn truncated_test() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.indexed.mzml.xml");
+        let contents = std::fs::read(d).unwrap();
+
+        // Cut off in the middle of the second spectrum.
+        let truncated = &contents[..7000];
+        let mut spectra = MzMLReader::from_reader(truncated).spectra();
+
+        assert!(spectra.next().unwrap().is_ok());
+        match spectra.next() {
+            Some(Err(MzMLError::Truncated { id, offset })) => {
+                assert_eq!(
+                    id.as_deref(),
+                    Some("controllerType=0 controllerNumber=1 scan=2")
+                );
+                assert_eq!(offset, 7000);
+            }
+            other => panic!("expected a truncation error, got {:?}", other),
+        }
+        assert!(spectra.next().is_none());
+
+        // Cut off between spectra.
+        let truncated = &contents[..6236];
+        let spectra: Vec<_> = MzMLReader::from_reader(truncated).spectra().collect();
+        assert_eq!(spectra.len(), 2);
+        assert!(matches!(
+            spectra[1],
+            Err(MzMLError::Truncated { id: None, .. })
+        ));
+    }
 }
@@ -5,14 +5,16 @@ use serde::{Deserialize, Serialize};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::error::Error;
 use std::fmt;
 use std::io::Cursor;
+use std::str::FromStr;
 
-use crate::io::mzml_parser::binary_conversion;
+use crate::io::mzml_parser::binary_conversion::{self, ArrayValue};
+use crate::io::mzml_parser::error::MzMLError;
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct CVParam {
     pub cv_ref: String,
@@ -25,7 +27,7 @@ pub struct CVParam {
 }
 
 impl CVParam {
-    pub fn get_data_type(&self) -> Result<DataType, MissingDataTypeError> {
+    pub fn get_data_type(&self) -> Result<DataType, MzMLError> {
         let dt = DataType::try_from(self)?;
         Ok(dt)
     }
@@ -55,6 +57,7 @@ impl CVParam {
 #[serde(rename_all = "camelCase")]
 pub struct UserParam {
     pub name: String,
+    #[serde(default)]
     pub value: String,
 }
 
@@ -74,15 +77,235 @@ pub struct ScanWindowList {
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Scan {
+    #[serde(default)]
     pub cv_param: CVVector,
-    pub scan_window_list: ScanWindowList,
+    pub scan_window_list: Option<ScanWindowList>,
+}
+
+impl Scan {
+    /// The scan start time (MS:1000016) in seconds, converting from minutes if needed.
+    pub fn start_time_seconds(&self) -> Option<f64> {
+        let param = self.cv_param.iter().find(|p| p.accession == "MS:1000016")?;
+        let value: f64 = param.value.as_ref()?.trim().parse().ok()?;
+
+        Some(seconds(value, param.unit_accession.as_deref()))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ScanList {
+    pub count: String,
+    #[serde(default)]
+    pub cv_param: CVVector,
+    pub scan: Vec<Scan>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IsolationWindow {
+    #[serde(default)]
+    pub cv_param: CVVector,
+}
+
+impl IsolationWindow {
+    /// The isolation window target m/z (MS:1000827).
+    pub fn target_mz(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000827")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The isolation window lower offset (MS:1000828).
+    pub fn lower_offset(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000828")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The isolation window upper offset (MS:1000829).
+    pub fn upper_offset(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000829")?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedIon {
+    #[serde(default)]
     pub cv_param: CVVector,
-    pub scans: Vec<Scan>,
+}
+
+impl SelectedIon {
+    /// The selected ion m/z (MS:1000744).
+    pub fn mz(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000744")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The charge state (MS:1000041).
+    pub fn charge(&self) -> Option<i32> {
+        cv_param_value(&self.cv_param, "MS:1000041")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The possible charge states (MS:1000633), used when the charge is ambiguous.
+    pub fn possible_charges(&self) -> Vec<i32> {
+        self.cv_param
+            .iter()
+            .filter(|p| p.accession == "MS:1000633")
+            .filter_map(|p| p.value.as_ref()?.trim().parse().ok())
+            .collect()
+    }
+
+    /// The peak intensity (MS:1000042).
+    pub fn intensity(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000042")?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedIonList {
+    pub count: String,
+    pub selected_ion: Vec<SelectedIon>,
+}
+
+/// Fragmentation methods, from the children of dissociation method (MS:1000044).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum DissociationMethod {
+    /// collision-induced dissociation (MS:1000133)
+    CID,
+    /// beam-type collision-induced dissociation (MS:1000422) or higher energy beam-type
+    /// collision-induced dissociation (MS:1002481)
+    HCD,
+    /// electron transfer dissociation (MS:1000598)
+    ETD,
+    /// electron capture dissociation (MS:1000250)
+    ECD,
+    /// infrared multiphoton dissociation (MS:1000262)
+    IRMPD,
+    /// photodissociation (MS:1000435)
+    PD,
+}
+
+impl fmt::Display for DissociationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for DissociationMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CID" => Ok(DissociationMethod::CID),
+            "HCD" => Ok(DissociationMethod::HCD),
+            "ETD" => Ok(DissociationMethod::ETD),
+            "ECD" => Ok(DissociationMethod::ECD),
+            "IRMPD" => Ok(DissociationMethod::IRMPD),
+            "PD" => Ok(DissociationMethod::PD),
+            _ => Err("Cannot parse dissociation method."),
+        }
+    }
+}
+
+impl DissociationMethod {
+    /// Returns the (accession, name) of the method's cvParam.
+    pub fn cv_term(&self) -> (&'static str, &'static str) {
+        match self {
+            DissociationMethod::CID => ("MS:1000133", "collision-induced dissociation"),
+            DissociationMethod::HCD => ("MS:1000422", "beam-type collision-induced dissociation"),
+            DissociationMethod::ETD => ("MS:1000598", "electron transfer dissociation"),
+            DissociationMethod::ECD => ("MS:1000250", "electron capture dissociation"),
+            DissociationMethod::IRMPD => ("MS:1000262", "infrared multiphoton dissociation"),
+            DissociationMethod::PD => ("MS:1000435", "photodissociation"),
+        }
+    }
+
+    /// Returns the dissociation method for a cvParam accession, if it is one.
+    pub fn from_accession(accession: &str) -> Option<Self> {
+        match accession {
+            "MS:1000133" => Some(DissociationMethod::CID),
+            "MS:1000422" | "MS:1002481" => Some(DissociationMethod::HCD),
+            "MS:1000598" => Some(DissociationMethod::ETD),
+            "MS:1000250" => Some(DissociationMethod::ECD),
+            "MS:1000262" => Some(DissociationMethod::IRMPD),
+            "MS:1000435" => Some(DissociationMethod::PD),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Activation {
+    #[serde(default)]
+    pub cv_param: CVVector,
+}
+
+impl Activation {
+    /// The dissociation methods used, more than one for e.g. EThcD.
+    pub fn dissociation_methods(&self) -> Vec<DissociationMethod> {
+        self.cv_param
+            .iter()
+            .filter_map(|p| DissociationMethod::from_accession(&p.accession))
+            .collect()
+    }
+
+    /// The collision energy (MS:1000045).
+    pub fn collision_energy(&self) -> Option<f64> {
+        cv_param_value(&self.cv_param, "MS:1000045")?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Precursor {
+    pub spectrum_ref: Option<String>,
+    pub isolation_window: Option<IsolationWindow>,
+    pub selected_ion_list: Option<SelectedIonList>,
+    pub activation: Activation,
+}
+
+impl Precursor {
+    /// Returns the selected ions, empty if the precursor has no selectedIonList.
+    pub fn selected_ions(&self) -> &[SelectedIon] {
+        match &self.selected_ion_list {
+            Some(list) => &list.selected_ion,
+            None => &[],
+        }
+    }
+}
+
+/// The product ion of an SRM transition.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Product {
+    pub isolation_window: Option<IsolationWindow>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PrecursorList {
+    pub count: String,
+    pub precursor: Vec<Precursor>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -101,126 +324,339 @@ impl Binary {
 #[serde(rename_all = "camelCase")]
 pub struct BinaryDataArray {
     pub encoded_length: String,
+    /// The number of values, when it differs from the spectrum's defaultArrayLength.
+    pub array_length: Option<String>,
+    #[serde(default)]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     pub cv_param: CVVector,
     pub binary: Binary,
 }
 
 impl BinaryDataArray {
-    pub fn binary_array_to_vector(&self) -> Result<Vec<f64>, std::io::Error> {
-        let data_type = DataType::try_from(&self.cv_param).unwrap();
-        let compression_type = CompressionType::try_from(&self.cv_param).unwrap();
+    /// Returns true if the array has the cvParam `accession`, e.g. MS:1000514 for m/z arrays.
+    pub fn has_cv_param(&self, accession: &str) -> bool {
+        self.cv_param.iter().any(|p| p.accession == accession)
+    }
+
+    /// Merge the cvParams of the referenced param groups into the array's cvParams.
+    pub fn resolve_param_groups(&mut self, groups: &ParamGroups) -> Result<(), MzMLError> {
+        resolve_param_groups(
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            groups,
+        )
+    }
+
+    /// Decodes the array into floats, using the data type and compression from its cvParams.
+    ///
+    /// The lengths aren't checked, see `decode` for that.
+    pub fn binary_array_to_vector(&self) -> Result<Vec<f64>, MzMLError> {
+        let data_type = DataType::try_from(&self.cv_param)?;
+        let compression_type = CompressionType::try_from(&self.cv_param)?;
+
+        binary_conversion::decode_binary_array(&self.binary, &compression_type, &data_type)
+    }
 
-        let result =
-            binary_conversion::decode_binary_array(&self.binary, &compression_type, &data_type);
+    /// Decodes the array into `T`, checking the base64 content against the encodedLength and the
+    /// number of values against the arrayLength, or `default_array_length` if it has none.
+    ///
+    /// # Arguments
+    ///
+    /// * `default_array_length` - The defaultArrayLength of the enclosing spectrum or
+    ///   chromatogram.
+    ///
+    pub fn decode<T: ArrayValue>(&self, default_array_length: usize) -> Result<Vec<T>, MzMLError> {
+        let encoded_length = parse_length(&self.encoded_length, "encodedLength")?;
+        let found = self.binary.content.trim().len();
+        if found != encoded_length {
+            return Err(MzMLError::EncodedLengthMismatch {
+                expected: encoded_length,
+                found,
+                id: None,
+                offset: None,
+            });
+        }
+
+        let expected = match &self.array_length {
+            Some(array_length) => parse_length(array_length, "arrayLength")?,
+            None => default_array_length,
+        };
 
-        Ok(result)
+        let data_type = DataType::try_from(&self.cv_param)?;
+        let compression_type = CompressionType::try_from(&self.cv_param)?;
+        let values =
+            binary_conversion::decode_binary_array_as(&self.binary, &compression_type, &data_type)?;
+
+        if values.len() != expected {
+            return Err(MzMLError::ArrayLengthMismatch {
+                expected,
+                found: values.len(),
+                id: None,
+                offset: None,
+            });
+        }
+
+        Ok(values)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+/// The binary data types, from the children of binary data type (MS:1000518).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
 pub enum DataType {
     Float64Bit,
     Float32Bit,
+    Float16Bit,
+    Integer64Bit,
+    Integer32Bit,
 }
 
-impl TryFrom<&CVVector> for DataType {
-    type Error = MissingDataTypeError;
+impl DataType {
+    /// The number of bytes each value takes.
+    pub fn size(&self) -> usize {
+        match self {
+            DataType::Float16Bit => 2,
+            DataType::Float32Bit | DataType::Integer32Bit => 4,
+            DataType::Float64Bit | DataType::Integer64Bit => 8,
+        }
+    }
 
-    fn try_from(value: &CVVector) -> Result<Self, Self::Error> {
-        for cv_param in value.iter() {
-            let data_type = DataType::try_from(cv_param);
-            if !data_type.is_err() {
-                return Ok(data_type.unwrap());
-            }
+    /// Returns the (accession, name) of the data type's cvParam.
+    pub fn cv_term(&self) -> (&'static str, &'static str) {
+        match self {
+            DataType::Float64Bit => ("MS:1000523", "64-bit float"),
+            DataType::Float32Bit => ("MS:1000521", "32-bit float"),
+            DataType::Float16Bit => ("MS:1000520", "16-bit float"),
+            DataType::Integer64Bit => ("MS:1000522", "64-bit integer"),
+            DataType::Integer32Bit => ("MS:1000519", "32-bit integer"),
         }
-        Err(MissingDataTypeError)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct MissingDataTypeError;
+impl TryFrom<&CVVector> for DataType {
+    type Error = MzMLError;
 
-impl fmt::Display for MissingDataTypeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "invalid first item to double")
+    fn try_from(value: &CVVector) -> Result<Self, Self::Error> {
+        for cv_param in value.iter() {
+            if let Ok(data_type) = DataType::try_from(cv_param) {
+                return Ok(data_type);
+            }
+        }
+        Err(MzMLError::missing_cv_term("binary data type"))
     }
 }
 
-impl Error for MissingDataTypeError {}
-
 impl TryFrom<&CVParam> for DataType {
-    type Error = MissingDataTypeError;
+    type Error = MzMLError;
 
     fn try_from(value: &CVParam) -> Result<Self, Self::Error> {
         match value.accession.as_str() {
             "MS:1000521" => Ok(DataType::Float32Bit),
             "MS:1000523" => Ok(DataType::Float64Bit),
-            //"MS:1000520" => Ok(DataType::Float16Bit),
-            //"MS:1000522" => Ok(DataType::Integer64Bit),
-            _ => Err(MissingDataTypeError),
+            "MS:1000520" => Ok(DataType::Float16Bit),
+            "MS:1000522" => Ok(DataType::Integer64Bit),
+            "MS:1000519" => Ok(DataType::Integer32Bit),
+            _ => Err(MzMLError::missing_cv_term("binary data type")),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+/// The binary data compression types, from the children of binary data compression type
+/// (MS:1000572).
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
 pub enum CompressionType {
     NoCompression,
     ZlibCompression,
+    /// MS-Numpress linear prediction, for m/z and retention time arrays.
+    NumpressLinear,
+    /// MS-Numpress positive integer, for ion count arrays.
+    NumpressPic,
+    /// MS-Numpress short logged float, for intensity arrays.
+    NumpressSlof,
+    NumpressLinearZlib,
+    NumpressPicZlib,
+    NumpressSlofZlib,
 }
 
-#[derive(Debug, Clone)]
-pub struct MissingCompressionError;
+impl CompressionType {
+    /// Returns the (accession, name) of the compression type's cvParam.
+    pub fn cv_term(&self) -> (&'static str, &'static str) {
+        match self {
+            CompressionType::NoCompression => ("MS:1000576", "no compression"),
+            CompressionType::ZlibCompression => ("MS:1000574", "zlib compression"),
+            CompressionType::NumpressLinear => {
+                ("MS:1002312", "MS-Numpress linear prediction compression")
+            }
+            CompressionType::NumpressPic => {
+                ("MS:1002313", "MS-Numpress positive integer compression")
+            }
+            CompressionType::NumpressSlof => {
+                ("MS:1002314", "MS-Numpress short logged float compression")
+            }
+            CompressionType::NumpressLinearZlib => (
+                "MS:1002746",
+                "MS-Numpress linear prediction compression followed by zlib compression",
+            ),
+            CompressionType::NumpressPicZlib => (
+                "MS:1002747",
+                "MS-Numpress positive integer compression followed by zlib compression",
+            ),
+            CompressionType::NumpressSlofZlib => (
+                "MS:1002748",
+                "MS-Numpress short logged float compression followed by zlib compression",
+            ),
+        }
+    }
+
+    /// Returns true if the bytes are zlib compressed, after any Numpress encoding.
+    pub fn is_zlib(&self) -> bool {
+        matches!(
+            self,
+            CompressionType::ZlibCompression
+                | CompressionType::NumpressLinearZlib
+                | CompressionType::NumpressPicZlib
+                | CompressionType::NumpressSlofZlib
+        )
+    }
 
-impl fmt::Display for MissingCompressionError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "missing compression in controlled vocabulary parameters")
+    /// Combines two compression cvParams on the same array, as older files list Numpress and
+    /// zlib separately.
+    fn combine(self, other: CompressionType) -> CompressionType {
+        use CompressionType::*;
+
+        match (self, other) {
+            (NoCompression, other) | (other, NoCompression) => other,
+            (NumpressLinear, ZlibCompression) | (ZlibCompression, NumpressLinear) => {
+                NumpressLinearZlib
+            }
+            (NumpressPic, ZlibCompression) | (ZlibCompression, NumpressPic) => NumpressPicZlib,
+            (NumpressSlof, ZlibCompression) | (ZlibCompression, NumpressSlof) => NumpressSlofZlib,
+            (first, _) => first,
+        }
     }
 }
 
-impl Error for MissingCompressionError {}
-
 type CVVector = Vec<CVParam>;
 
-trait CVVectorMethods {}
+/// The cvParams of each referenceableParamGroup, keyed by the group id.
+pub type ParamGroups = HashMap<String, CVVector>;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReferenceableParamGroupRef {
+    #[serde(rename = "ref")]
+    pub group_ref: String,
+}
 
-impl CVVectorMethods for CVVector {}
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceableParamGroup {
+    pub id: String,
+    #[serde(default)]
+    pub cv_param: CVVector,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferenceableParamGroupList {
+    pub count: String,
+    pub referenceable_param_group: Vec<ReferenceableParamGroup>,
+}
+
+impl ReferenceableParamGroupList {
+    /// Convert the list into a map from group id to the group's cvParams.
+    pub fn into_param_groups(self) -> ParamGroups {
+        self.referenceable_param_group
+            .into_iter()
+            .map(|group| (group.id, group.cv_param))
+            .collect()
+    }
+}
+
+/// Returns the value of the cvParam with `accession`, if present.
+pub fn cv_param_value<'a>(cv_params: &'a [CVParam], accession: &str) -> Option<&'a str> {
+    cv_params
+        .iter()
+        .find(|p| p.accession == accession)
+        .and_then(|p| p.value.as_deref())
+}
+
+/// Converts a time in minutes (UO:0000031) or hours (UO:0000032) to seconds, other units are
+/// assumed to be seconds already.
+pub fn seconds(value: f64, unit_accession: Option<&str>) -> f64 {
+    match unit_accession {
+        Some("UO:0000031") => value * 60.0,
+        Some("UO:0000032") => value * 3600.0,
+        _ => value,
+    }
+}
+
+/// Parses a length attribute such as defaultArrayLength.
+fn parse_length(value: &str, attribute: &str) -> Result<usize, MzMLError> {
+    value.trim().parse().map_err(|_| MzMLError::Schema {
+        message: format!("invalid {} {:?}", attribute, value),
+        id: None,
+        offset: None,
+    })
+}
+
+/// Moves the referenced groups' cvParams into `cv_params`, emptying `refs`.
+fn resolve_param_groups(
+    refs: &mut Vec<ReferenceableParamGroupRef>,
+    cv_params: &mut CVVector,
+    groups: &ParamGroups,
+) -> Result<(), MzMLError> {
+    for group_ref in refs.drain(..) {
+        match groups.get(&group_ref.group_ref) {
+            Some(group) => cv_params.extend(group.iter().cloned()),
+            None => {
+                return Err(MzMLError::MissingParamGroup {
+                    group: group_ref.group_ref,
+                    id: None,
+                    offset: None,
+                })
+            }
+        }
+    }
+
+    Ok(())
+}
 
 impl TryFrom<&CVVector> for CompressionType {
-    type Error = MissingCompressionError;
+    type Error = MzMLError;
 
     fn try_from(value: &CVVector) -> Result<Self, Self::Error> {
+        let mut found: Option<CompressionType> = None;
         for cv_param in value.iter() {
-            let compression_type = CompressionType::try_from(cv_param);
-            if !compression_type.is_err() {
-                return Ok(compression_type.unwrap());
+            if let Ok(compression_type) = CompressionType::try_from(cv_param) {
+                found = Some(match found {
+                    Some(previous) => previous.combine(compression_type),
+                    None => compression_type,
+                });
             }
         }
-        Err(MissingCompressionError)
+
+        found.ok_or_else(|| MzMLError::missing_cv_term("binary data compression type"))
     }
 }
 
 impl TryFrom<&CVParam> for CompressionType {
-    type Error = MissingDataTypeError;
+    type Error = MzMLError;
 
     fn try_from(value: &CVParam) -> Result<Self, Self::Error> {
         match value.accession.as_str() {
             "MS:1000576" => Ok(CompressionType::NoCompression),
             "MS:1000574" => Ok(CompressionType::ZlibCompression),
-            _ => Err(MissingDataTypeError),
+            "MS:1002312" => Ok(CompressionType::NumpressLinear),
+            "MS:1002313" => Ok(CompressionType::NumpressPic),
+            "MS:1002314" => Ok(CompressionType::NumpressSlof),
+            "MS:1002746" => Ok(CompressionType::NumpressLinearZlib),
+            "MS:1002747" => Ok(CompressionType::NumpressPicZlib),
+            "MS:1002748" => Ok(CompressionType::NumpressSlofZlib),
+            _ => Err(MzMLError::missing_cv_term("binary data compression type")),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct DataArrayDecodingError;
-
-impl fmt::Display for DataArrayDecodingError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "invalid first item to double")
-    }
-}
-
-impl Error for DataArrayDecodingError {}
 //https://docs.rs/fastobo/0.13.1/fastobo/ast/struct.OboDoc.html
 //https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo
 
@@ -232,26 +668,39 @@ pub struct BinaryDataArrayList {
     pub count: String,
 }
 
-type DecodeArrayError = &'static str;
-type DecodedArrayResult<T> = Result<T, DecodeArrayError>;
+/// An empty list, for spectra without arrays.
+impl Default for BinaryDataArrayList {
+    fn default() -> Self {
+        Self {
+            binary_data_array: Vec::new(),
+            count: String::from("0"),
+        }
+    }
+}
+
+impl BinaryDataArrayList {
+    /// Returns the first array with the array type `accession`.
+    pub fn find(&self, accession: &str) -> Option<&BinaryDataArray> {
+        self.binary_data_array
+            .iter()
+            .find(|a| a.has_cv_param(accession))
+    }
+}
+
+type DecodedArrayResult<T> = Result<T, MzMLError>;
 
 pub trait DecodedArray {
     fn decode_array(&self, i: usize) -> DecodedArrayResult<Vec<f64>> {
         let de = self.decompress_binary_string(i);
-        let decoded = base64::decode(de);
+        let v = base64::decode(de).map_err(MzMLError::base64)?;
 
-        if decoded.is_ok() {
-            let v = decoded.unwrap();
+        let mut rdr = Cursor::new(v);
 
-            let mut rdr = Cursor::new(v);
-
-            let mut peaks = Vec::<f64>::new();
-            while let Ok(fl) = rdr.read_f64::<LittleEndian>() {
-                peaks.push(fl);
-            }
-            return Ok(peaks);
-        };
-        Err("error")
+        let mut peaks = Vec::<f64>::new();
+        while let Ok(fl) = rdr.read_f64::<LittleEndian>() {
+            peaks.push(fl);
+        }
+        Ok(peaks)
     }
 
     fn decompress_binary_string(&self, i: usize) -> &String;
@@ -260,13 +709,85 @@ pub trait DecodedArray {
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Spectrum {
+    #[serde(default)]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     pub cv_param: CVVector,
+    #[serde(default)]
+    pub user_param: Vec<UserParam>,
     pub index: String,
     pub id: String,
     pub default_array_length: String,
+    pub scan_list: Option<ScanList>,
+    pub precursor_list: Option<PrecursorList>,
+    #[serde(default)]
     pub binary_data_array_list: BinaryDataArrayList,
 }
 
+impl Spectrum {
+    /// The ms level (MS:1000511).
+    pub fn ms_level(&self) -> Option<u32> {
+        cv_param_value(&self.cv_param, "MS:1000511")?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The spectrum title (MS:1000796).
+    pub fn title(&self) -> Option<&str> {
+        self.cv_param
+            .iter()
+            .find(|p| p.accession == "MS:1000796")
+            .and_then(|p| p.value.as_deref())
+    }
+
+    /// The polarity, true for positive scans (MS:1000130) and false for negative (MS:1000129).
+    pub fn is_positive(&self) -> Option<bool> {
+        for p in self.cv_param.iter() {
+            match p.accession.as_str() {
+                "MS:1000130" => return Some(true),
+                "MS:1000129" => return Some(false),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// The parsed defaultArrayLength.
+    pub fn array_length(&self) -> Result<usize, MzMLError> {
+        parse_length(&self.default_array_length, "defaultArrayLength")
+    }
+
+    /// The start time of the first scan in seconds.
+    pub fn scan_start_time(&self) -> Option<f64> {
+        self.scan_list.as_ref()?.scan.first()?.start_time_seconds()
+    }
+
+    /// Returns the precursors, empty if the spectrum has no precursorList.
+    pub fn precursors(&self) -> &[Precursor] {
+        match &self.precursor_list {
+            Some(list) => &list.precursor,
+            None => &[],
+        }
+    }
+
+    /// Merge the cvParams of the referenced param groups into the spectrum and its arrays.
+    pub fn resolve_param_groups(&mut self, groups: &ParamGroups) -> Result<(), MzMLError> {
+        resolve_param_groups(
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            groups,
+        )?;
+
+        for array in self.binary_data_array_list.binary_data_array.iter_mut() {
+            array.resolve_param_groups(groups)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl DecodedArray for Spectrum {
     fn decompress_binary_string(&self, i: usize) -> &String {
         &self.binary_data_array_list.binary_data_array[i]
@@ -285,13 +806,60 @@ pub struct SpectrumList {
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Chromatogram {
+    #[serde(default)]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
     pub cv_param: CVVector,
     pub index: String,
     pub id: String,
     pub default_array_length: String,
+    pub precursor: Option<Precursor>,
+    pub product: Option<Product>,
     pub binary_data_array_list: BinaryDataArrayList,
 }
 
+impl Chromatogram {
+    /// The precursor m/z of an SRM transition, from the selected ion or isolation window target.
+    pub fn precursor_mz(&self) -> Option<f64> {
+        let precursor = self.precursor.as_ref()?;
+
+        precursor
+            .selected_ions()
+            .first()
+            .and_then(|ion| ion.mz())
+            .or_else(|| precursor.isolation_window.as_ref()?.target_mz())
+    }
+
+    /// The product m/z of an SRM transition, from the isolation window target.
+    pub fn product_mz(&self) -> Option<f64> {
+        self.product
+            .as_ref()?
+            .isolation_window
+            .as_ref()?
+            .target_mz()
+    }
+
+    /// The parsed defaultArrayLength.
+    pub fn array_length(&self) -> Result<usize, MzMLError> {
+        parse_length(&self.default_array_length, "defaultArrayLength")
+    }
+
+    /// Merge the cvParams of the referenced param groups into the chromatogram and its arrays.
+    pub fn resolve_param_groups(&mut self, groups: &ParamGroups) -> Result<(), MzMLError> {
+        resolve_param_groups(
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            groups,
+        )?;
+
+        for array in self.binary_data_array_list.binary_data_array.iter_mut() {
+            array.resolve_param_groups(groups)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ChromatogramList {
@@ -310,8 +878,11 @@ pub struct Run {
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ProcessingMethod {
+    pub order: Option<String>,
     pub software_ref: String,
+    #[serde(default)]
     pub cv_param: CVVector,
+    #[serde(default)]
     pub user_param: Vec<UserParam>,
 }
 
@@ -328,10 +899,28 @@ pub struct DataProcessingList {
     pub data_processing: Vec<DataProcessing>,
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Cv {
+    pub id: String,
+    pub full_name: String,
+    pub version: Option<String>,
+    #[serde(rename = "URI")]
+    pub uri: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CvList {
+    #[serde(default)]
+    pub cv: Vec<Cv>,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FileContent {
-    cv_param: CVVector,
+    #[serde(default)]
+    pub cv_param: CVVector,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -340,30 +929,297 @@ pub struct SourceFile {
     pub id: String,
     pub name: String,
     pub location: String,
+    #[serde(default)]
+    pub cv_param: CVVector,
+}
+
+impl SourceFile {
+    /// Returns the SHA-1 checksum of the file (MS:1000569), if given.
+    pub fn sha1(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000569")
+    }
+
+    /// Returns the MD5 checksum of the file (MS:1000568), if given.
+    pub fn md5(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000568")
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SourceFileList {
-    source_file: SourceFile,
+    #[serde(default)]
+    pub source_file: Vec<SourceFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    #[serde(default)]
+    pub cv_param: CVVector,
+    #[serde(default)]
+    pub user_param: Vec<UserParam>,
+}
+
+impl Contact {
+    /// Returns the contact name (MS:1000586).
+    pub fn name(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000586")
+    }
+
+    /// Returns the contact organization (MS:1000590).
+    pub fn organization(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000590")
+    }
+
+    /// Returns the contact email (MS:1000589).
+    pub fn email(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000589")
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FileDescription {
     pub file_content: FileContent,
+    pub source_file_list: Option<SourceFileList>,
+    #[serde(default)]
+    pub contact: Vec<Contact>,
+}
+
+impl FileDescription {
+    /// Returns the source files the mzML was converted from.
+    pub fn source_files(&self) -> &[SourceFile] {
+        self.source_file_list
+            .as_ref()
+            .map(|list| list.source_file.as_slice())
+            .unwrap_or_default()
+    }
+}
 
-    pub source_file_list: SourceFileList,
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(default)]
     pub cv_param: CVVector,
+    #[serde(default)]
+    pub user_param: Vec<UserParam>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleList {
+    #[serde(default)]
+    pub sample: Vec<Sample>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Software {
+    pub id: String,
+    pub version: String,
+    #[serde(default)]
+    pub cv_param: CVVector,
+}
+
+impl Software {
+    /// Returns the name of the software's cvParam, e.g. "ProteoWizard software".
+    pub fn name(&self) -> Option<&str> {
+        self.cv_param.first().map(|p| p.name.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SoftwareList {
+    #[serde(default)]
+    pub software: Vec<Software>,
+}
+
+/// An ion source, analyzer or detector of an instrument configuration.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    pub order: String,
+    #[serde(default)]
+    pub cv_param: CVVector,
+    #[serde(default)]
+    pub user_param: Vec<UserParam>,
+}
+
+impl Component {
+    /// Returns the names of the component's cvParams, e.g. "electrospray ionization".
+    pub fn names(&self) -> Vec<&str> {
+        self.cv_param.iter().map(|p| p.name.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentList {
+    #[serde(default)]
+    pub source: Vec<Component>,
+    #[serde(default)]
+    pub analyzer: Vec<Component>,
+    #[serde(default)]
+    pub detector: Vec<Component>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SoftwareRef {
+    #[serde(rename = "ref")]
+    pub software_ref: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstrumentConfiguration {
+    pub id: String,
+    #[serde(default)]
+    pub referenceable_param_group_ref: Vec<ReferenceableParamGroupRef>,
+    #[serde(default)]
+    pub cv_param: CVVector,
+    #[serde(default)]
+    pub user_param: Vec<UserParam>,
+    pub component_list: Option<ComponentList>,
+    pub software_ref: Option<SoftwareRef>,
+}
+
+impl InstrumentConfiguration {
+    /// Merge the cvParams of the referenced param groups into the configuration's cvParams.
+    pub fn resolve_param_groups(&mut self, groups: &ParamGroups) -> Result<(), MzMLError> {
+        resolve_param_groups(
+            &mut self.referenceable_param_group_ref,
+            &mut self.cv_param,
+            groups,
+        )
+        .map_err(|e| e.with_location(&self.id, None))
+    }
+
+    /// Returns the name of the instrument model, e.g. "LTQ Orbitrap Velos".
+    ///
+    /// Without the ontology the model can't be told apart from other valueless terms, so this is
+    /// the first cvParam that isn't the serial number (MS:1000529) and has no value, which is
+    /// where converters put it.
+    pub fn model(&self) -> Option<&str> {
+        self.cv_param
+            .iter()
+            .find(|p| {
+                p.accession != "MS:1000529" && p.value.as_deref().unwrap_or("").trim().is_empty()
+            })
+            .map(|p| p.name.as_str())
+    }
+
+    /// Returns the instrument serial number (MS:1000529).
+    pub fn serial_number(&self) -> Option<&str> {
+        cv_param_value(&self.cv_param, "MS:1000529")
+    }
+
+    /// Returns the ion sources.
+    pub fn sources(&self) -> &[Component] {
+        self.component_list
+            .as_ref()
+            .map(|c| c.source.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns the mass analyzers.
+    pub fn analyzers(&self) -> &[Component] {
+        self.component_list
+            .as_ref()
+            .map(|c| c.analyzer.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns the detectors.
+    pub fn detectors(&self) -> &[Component] {
+        self.component_list
+            .as_ref()
+            .map(|c| c.detector.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstrumentConfigurationList {
+    #[serde(default)]
+    pub instrument_configuration: Vec<InstrumentConfiguration>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MzML {
+    pub id: Option<String>,
+    pub version: Option<String>,
+    pub cv_list: Option<CvList>,
+    pub file_description: Option<FileDescription>,
+    pub sample_list: Option<SampleList>,
+    pub software_list: Option<SoftwareList>,
+    pub instrument_configuration_list: Option<InstrumentConfigurationList>,
     pub run: Run,
     pub data_processing_list: DataProcessingList,
 }
 
+/// The attributes of the run element.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RunHeader {
+    pub id: String,
+    pub default_instrument_configuration_ref: Option<String>,
+    pub default_source_file_ref: Option<String>,
+    pub sample_ref: Option<String>,
+    pub start_time_stamp: Option<String>,
+}
+
+/// Everything in an mzML file before the first spectrum, i.e. the provenance of the run.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct MzMLHeader {
+    pub id: Option<String>,
+    pub accession: Option<String>,
+    pub version: Option<String>,
+    pub cvs: Vec<Cv>,
+    pub file_description: Option<FileDescription>,
+    pub samples: Vec<Sample>,
+    pub software: Vec<Software>,
+    pub instrument_configurations: Vec<InstrumentConfiguration>,
+    pub data_processing: Vec<DataProcessing>,
+    pub run: Option<RunHeader>,
+}
+
+impl MzMLHeader {
+    /// Returns the source files, empty if the file description doesn't list any.
+    pub fn source_files(&self) -> &[SourceFile] {
+        self.file_description
+            .as_ref()
+            .map(|d| d.source_files())
+            .unwrap_or_default()
+    }
+
+    /// Returns the instrument configuration with `id`.
+    pub fn instrument_configuration(&self, id: &str) -> Option<&InstrumentConfiguration> {
+        self.instrument_configurations.iter().find(|c| c.id == id)
+    }
+
+    /// Returns the run's default instrument configuration, or the only one if there's no run.
+    pub fn default_instrument_configuration(&self) -> Option<&InstrumentConfiguration> {
+        match self
+            .run
+            .as_ref()
+            .and_then(|r| r.default_instrument_configuration_ref.as_deref())
+        {
+            Some(id) => self.instrument_configuration(id),
+            None => self.instrument_configurations.first(),
+        }
+    }
+
+    /// Returns the software with `id`.
+    pub fn software(&self, id: &str) -> Option<&Software> {
+        self.software.iter().find(|s| s.id == id)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -387,5 +1243,116 @@ mod tests {
 
         let new_ctype = CompressionType::try_from(&cv_params).unwrap();
         assert_eq!(new_ctype, CompressionType::NoCompression);
+
+        let numpress = CVParam::new(
+            String::from("MS"),
+            String::from("MS:1002312"),
+            String::from("MS-Numpress linear prediction compression"),
+            None,
+            None,
+            None,
+            None,
+        );
+        let zlib = CVParam::new(
+            String::from("MS"),
+            String::from("MS:1000574"),
+            String::from("zlib compression"),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let cv_params = vec![zlib, numpress];
+        assert_eq!(
+            CompressionType::try_from(&cv_params).unwrap(),
+            CompressionType::NumpressLinearZlib
+        );
+    }
+
+    #[test]
+    fn resolve_param_groups_test() {
+        let cv_param = CVParam::new(
+            String::from("MS"),
+            String::from("MS:1000523"),
+            String::from("64-bit float"),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let mut groups = ParamGroups::new();
+        groups.insert(String::from("precision"), vec![cv_param]);
+
+        let mut array = BinaryDataArray {
+            encoded_length: String::from("0"),
+            array_length: None,
+            referenceable_param_group_ref: vec![ReferenceableParamGroupRef {
+                group_ref: String::from("precision"),
+            }],
+            cv_param: vec![],
+            binary: Binary::new(String::new()),
+        };
+
+        array.resolve_param_groups(&groups).unwrap();
+        assert!(array.referenceable_param_group_ref.is_empty());
+        assert_eq!(
+            DataType::try_from(&array.cv_param).unwrap(),
+            DataType::Float64Bit
+        );
+
+        array.referenceable_param_group_ref = vec![ReferenceableParamGroupRef {
+            group_ref: String::from("missing"),
+        }];
+        assert!(array.resolve_param_groups(&groups).is_err());
+    }
+
+    #[test]
+    fn decode_length_test() {
+        let cv_param = |accession: &str| {
+            CVParam::new(
+                String::from("MS"),
+                String::from(accession),
+                String::new(),
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        // [1, -2, 3] as 32-bit integers.
+        let mut array = BinaryDataArray {
+            encoded_length: String::from("16"),
+            array_length: None,
+            referenceable_param_group_ref: vec![],
+            cv_param: vec![cv_param("MS:1000519"), cv_param("MS:1000576")],
+            binary: Binary::new(String::from("AQAAAP7///8DAAAA")),
+        };
+
+        assert_eq!(array.decode::<i32>(3).unwrap(), vec![1, -2, 3]);
+        assert_eq!(array.decode::<f64>(3).unwrap(), vec![1.0, -2.0, 3.0]);
+        assert!(matches!(
+            array.decode::<i32>(4),
+            Err(MzMLError::ArrayLengthMismatch {
+                expected: 4,
+                found: 3,
+                ..
+            })
+        ));
+
+        array.array_length = Some(String::from("3"));
+        assert!(array.decode::<i32>(4).is_ok());
+
+        array.encoded_length = String::from("12");
+        assert!(matches!(
+            array.decode::<i32>(3),
+            Err(MzMLError::EncodedLengthMismatch {
+                expected: 12,
+                found: 16,
+                ..
+            })
+        ));
     }
 }
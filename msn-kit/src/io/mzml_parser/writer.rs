@@ -0,0 +1,942 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Writing spectra as mzML 1.1, optionally wrapped in an indexedmzML footer.
+
+use sha1::{Digest, Sha1};
+
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::io::mzml_parser::binary_conversion::encode_binary_array;
+use crate::io::mzml_parser::indexed::IndexEntry;
+use crate::io::mzml_parser::types::{CompressionType, DataType, DissociationMethod};
+use crate::io::SpectrumWriter;
+use crate::metadata::{parse_charges, parse_pepmass};
+use crate::spectrum::Spectrum;
+
+const SOFTWARE_ID: &str = "msn-kit";
+const DATA_PROCESSING_ID: &str = "msn_kit_processing";
+const INSTRUMENT_CONFIGURATION_ID: &str = "IC1";
+
+/// Metadata keys that are written as cvParams rather than userParams.
+const CONVERTED_KEYS: [&str; 9] = [
+    "TITLE",
+    "INDEX",
+    "MSLEVEL",
+    "POLARITY",
+    "RTINSECONDS",
+    "PEPMASS",
+    "CHARGE",
+    "ACTIVATION",
+    "COLLISIONENERGY",
+];
+
+/// Wraps a writer, counting the bytes written and hashing them for the file checksum.
+struct HashingWriter<W: Write> {
+    inner: W,
+    position: u64,
+    hasher: Sha1,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.position += n as u64;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A temporary file holding the spectra written before the number of spectra is known. The file
+/// is removed when the spool is dropped.
+struct Spool {
+    file: BufWriter<File>,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Spool {
+    fn new() -> std::io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "msn-kit-{}-{}.mzML.spool",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    return Ok(Self {
+                        file: BufWriter::new(file),
+                        path,
+                        len: 0,
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Copies everything written to the spool to `writer`.
+    fn copy_to<W: Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(file, writer)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum WriterState {
+    Initial,
+    Spectra,
+    Finished,
+}
+
+/// Writes `Spectrum` objects as an mzML document.
+///
+/// The `spectrumList` element needs the number of spectra up front. If it isn't given with
+/// `with_spectrum_count`, spectra are spooled to a temporary file until `finish` is called.
+///
+/// Spectrum ids are `scan=N` when `SCANS` holds a single scan number, and `index=N` otherwise, so
+/// they stay unique even when titles repeat. The title is written as the spectrum title cvParam.
+///
+/// Well known metadata keys (`TITLE`, `MSLEVEL`, `POLARITY`, `RTINSECONDS`, `PEPMASS`, `CHARGE`,
+/// `ACTIVATION` and `COLLISIONENERGY`) are converted to their controlled vocabulary terms, other keys
+/// are written as userParams.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::mzml_parser::MzMLWriter;
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let mut writer = MzMLWriter::new(Vec::new()).with_index(true);
+/// writer.write(Spectrum::new(Metadata::new(), vec![100.0], vec![1.0])).unwrap();
+/// writer.finish().unwrap();
+///
+/// let output = writer.into_inner().unwrap();
+/// assert!(String::from_utf8(output).unwrap().contains("<fileChecksum>"));
+/// ```
+pub struct MzMLWriter<W: Write> {
+    writer: HashingWriter<BufWriter<W>>,
+    indexed: bool,
+    spectrum_count: Option<usize>,
+    compression_type: CompressionType,
+    intensity_type: DataType,
+    run_id: String,
+    pending: Option<Spool>,
+    offsets: Vec<IndexEntry>,
+    ids: HashSet<String>,
+    n_written: usize,
+    state: WriterState,
+}
+
+impl<W: Write> MzMLWriter<W> {
+    /// Create a new MzMLWriter object.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - An object that can be written to.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: HashingWriter {
+                inner: BufWriter::new(writer),
+                position: 0,
+                hasher: Sha1::new(),
+            },
+            indexed: false,
+            spectrum_count: None,
+            compression_type: CompressionType::ZlibCompression,
+            intensity_type: DataType::Float64Bit,
+            run_id: String::from("run"),
+            pending: None,
+            offsets: Vec::new(),
+            ids: HashSet::new(),
+            n_written: 0,
+            state: WriterState::Initial,
+        }
+    }
+
+    /// Wrap the document in indexedmzML, with the spectrum offsets and a SHA-1 file checksum.
+    pub fn with_index(mut self, indexed: bool) -> Self {
+        self.indexed = indexed;
+        self
+    }
+
+    /// Set how many spectra will be written, so they can be streamed rather than spooled to a
+    /// temporary file.
+    pub fn with_spectrum_count(mut self, spectrum_count: usize) -> Self {
+        self.spectrum_count = Some(spectrum_count);
+        self
+    }
+
+    /// Set the compression used for the binary data arrays, zlib by default.
+    pub fn with_compression(mut self, compression_type: CompressionType) -> Self {
+        self.compression_type = compression_type;
+        self
+    }
+
+    /// Set the data type of the intensity arrays, 64-bit floats by default. `Float32Bit` halves
+    /// the size of the arrays at the cost of precision.
+    pub fn with_intensity_precision(mut self, intensity_type: DataType) -> Self {
+        self.intensity_type = intensity_type;
+        self
+    }
+
+    /// Set the id of the run element.
+    pub fn with_run_id(mut self, run_id: String) -> Self {
+        self.run_id = run_id;
+        self
+    }
+
+    /// Write spectrum to the underlying buffer in mzML format.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        if self.state == WriterState::Finished {
+            return Err(Error::other("Cannot write a spectrum after finish."));
+        }
+
+        if spectrum.mz.len() != spectrum.intensities.len() {
+            return Err(Error::other(format!(
+                "Spectrum has {} m/z values but {} intensities.",
+                spectrum.mz.len(),
+                spectrum.intensities.len()
+            )));
+        }
+
+        let id = self.spectrum_id(&spectrum);
+        let xml = spectrum_xml(
+            &spectrum,
+            &id,
+            self.n_written,
+            (&self.compression_type, &self.intensity_type),
+        );
+
+        match self.spectrum_count {
+            Some(count) => {
+                if self.state == WriterState::Initial {
+                    self.write_header(count)?;
+                }
+
+                self.offsets.push(IndexEntry {
+                    id,
+                    offset: self.writer.position,
+                });
+                self.writer.write_all(xml.as_bytes())?;
+            }
+            None => {
+                let pending = match self.pending.as_mut() {
+                    Some(pending) => pending,
+                    None => self.pending.insert(Spool::new()?),
+                };
+
+                self.offsets.push(IndexEntry {
+                    id,
+                    offset: pending.len,
+                });
+                pending.write_all(xml.as_bytes())?;
+            }
+        }
+
+        self.n_written += 1;
+        Ok(())
+    }
+
+    /// Write the end of the document, along with the index if requested, and flush the writer.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.state == WriterState::Finished {
+            return Ok(());
+        }
+
+        match self.spectrum_count {
+            Some(count) if count != self.n_written => {
+                return Err(Error::other(format!(
+                    "Expected {} spectra, but {} were written.",
+                    count, self.n_written
+                )));
+            }
+            Some(count) => {
+                if self.state == WriterState::Initial {
+                    self.write_header(count)?;
+                }
+            }
+            None => {
+                self.write_header(self.n_written)?;
+
+                let base = self.writer.position;
+                for entry in self.offsets.iter_mut() {
+                    entry.offset += base;
+                }
+
+                if let Some(mut pending) = self.pending.take() {
+                    pending.copy_to(&mut self.writer)?;
+                }
+            }
+        }
+
+        self.writer
+            .write_all(b"      </spectrumList>\n    </run>\n  </mzML>\n")?;
+
+        if self.indexed {
+            self.write_index()?;
+        }
+
+        self.writer.flush()?;
+        self.state = WriterState::Finished;
+
+        Ok(())
+    }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.writer.inner.into_inner().map_err(|e| e.into_error())
+    }
+
+    /// Picks a unique id for the next spectrum, from its scan number if it has one.
+    fn spectrum_id(&mut self, spectrum: &Spectrum) -> String {
+        let id = match spectrum
+            .metadata
+            .get("SCANS")
+            .map(|s| s.trim().parse::<u64>())
+        {
+            Some(Ok(scan)) if !self.ids.contains(&format!("scan={}", scan)) => {
+                format!("scan={}", scan)
+            }
+            _ => format!("index={}", self.n_written),
+        };
+        self.ids.insert(id.clone());
+
+        id
+    }
+
+    fn write_header(&mut self, spectrum_count: usize) -> std::io::Result<()> {
+        let mut header = String::new();
+
+        header.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        if self.indexed {
+            header.push_str("<indexedmzML xmlns=\"http://psi.hupo.org/ms/mzml\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.2_idx.xsd\">\n");
+        }
+        header.push_str("  <mzML xmlns=\"http://psi.hupo.org/ms/mzml\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://psi.hupo.org/ms/mzml http://psidev.info/files/ms/mzML/xsd/mzML1.1.0.xsd\" version=\"1.1.0\">\n");
+
+        header.push_str("    <cvList count=\"2\">\n");
+        header.push_str("      <cv id=\"MS\" fullName=\"Proteomics Standards Initiative Mass Spectrometry Ontology\" URI=\"https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo\"/>\n");
+        header.push_str("      <cv id=\"UO\" fullName=\"Unit Ontology\" URI=\"https://raw.githubusercontent.com/bio-ontology-research-group/unit-ontology/master/unit.obo\"/>\n");
+        header.push_str("    </cvList>\n");
+
+        header.push_str("    <fileDescription>\n      <fileContent>\n");
+        push_cv_param(&mut header, 8, "MS:1000294", "mass spectrum", "");
+        header.push_str("      </fileContent>\n    </fileDescription>\n");
+
+        header.push_str("    <softwareList count=\"1\">\n");
+        let _ = writeln!(
+            header,
+            "      <software id=\"{}\" version=\"{}\">",
+            SOFTWARE_ID,
+            env!("CARGO_PKG_VERSION")
+        );
+        push_cv_param(
+            &mut header,
+            8,
+            "MS:1000799",
+            "custom unreleased software tool",
+            SOFTWARE_ID,
+        );
+        header.push_str("      </software>\n    </softwareList>\n");
+
+        header.push_str("    <instrumentConfigurationList count=\"1\">\n");
+        let _ = writeln!(
+            header,
+            "      <instrumentConfiguration id=\"{}\">",
+            INSTRUMENT_CONFIGURATION_ID
+        );
+        push_cv_param(&mut header, 8, "MS:1000031", "instrument model", "");
+        header.push_str("      </instrumentConfiguration>\n    </instrumentConfigurationList>\n");
+
+        header.push_str("    <dataProcessingList count=\"1\">\n");
+        let _ = writeln!(
+            header,
+            "      <dataProcessing id=\"{}\">",
+            DATA_PROCESSING_ID
+        );
+        let _ = writeln!(
+            header,
+            "        <processingMethod order=\"0\" softwareRef=\"{}\">",
+            SOFTWARE_ID
+        );
+        push_cv_param(&mut header, 10, "MS:1000544", "Conversion to mzML", "");
+        header.push_str(
+            "        </processingMethod>\n      </dataProcessing>\n    </dataProcessingList>\n",
+        );
+
+        let _ = writeln!(
+            header,
+            "    <run id=\"{}\" defaultInstrumentConfigurationRef=\"{}\">",
+            escape(&self.run_id),
+            INSTRUMENT_CONFIGURATION_ID
+        );
+        let _ = writeln!(
+            header,
+            "      <spectrumList count=\"{}\" defaultDataProcessingRef=\"{}\">",
+            spectrum_count, DATA_PROCESSING_ID
+        );
+
+        self.writer.write_all(header.as_bytes())?;
+        self.state = WriterState::Spectra;
+
+        Ok(())
+    }
+
+    fn write_index(&mut self) -> std::io::Result<()> {
+        let index_offset = self.writer.position + 2;
+
+        let mut index = String::from("  <indexList count=\"1\">\n    <index name=\"spectrum\">\n");
+        for entry in self.offsets.iter() {
+            let _ = writeln!(
+                index,
+                "      <offset idRef=\"{}\">{}</offset>",
+                escape(&entry.id),
+                entry.offset
+            );
+        }
+        index.push_str("    </index>\n  </indexList>\n");
+        let _ = write!(
+            index,
+            "  <indexListOffset>{}</indexListOffset>\n  <fileChecksum>",
+            index_offset
+        );
+        self.writer.write_all(index.as_bytes())?;
+
+        // The checksum covers everything up to and including the opening fileChecksum tag.
+        let checksum = self.writer.hasher.clone().finalize();
+        let footer = format!("{:x}</fileChecksum>\n</indexedmzML>\n", checksum);
+        self.writer.write_all(footer.as_bytes())
+    }
+}
+
+impl<W: Write> SpectrumWriter for MzMLWriter<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        MzMLWriter::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        MzMLWriter::finish(self)
+    }
+}
+
+/// Builds the spectrum element for `spectrum`, encoding the arrays with the given
+/// (compression, intensity data type).
+fn spectrum_xml(
+    spectrum: &Spectrum,
+    id: &str,
+    index: usize,
+    (ct, intensity_type): (&CompressionType, &DataType),
+) -> String {
+    let metadata = &spectrum.metadata;
+    let mut xml = String::new();
+
+    let _ = writeln!(
+        xml,
+        "        <spectrum index=\"{}\" id=\"{}\" defaultArrayLength=\"{}\">",
+        index,
+        escape(id),
+        spectrum.mz.len()
+    );
+
+    let ms_level = match metadata.get("MSLEVEL") {
+        Some(level) => level.clone(),
+        None if metadata.contains_key("PEPMASS") => String::from("2"),
+        None => String::from("1"),
+    };
+    push_cv_param(&mut xml, 10, "MS:1000511", "ms level", &ms_level);
+    if ms_level == "1" {
+        push_cv_param(&mut xml, 10, "MS:1000579", "MS1 spectrum", "");
+    } else {
+        push_cv_param(&mut xml, 10, "MS:1000580", "MSn spectrum", "");
+    }
+
+    let charges = metadata.get("CHARGE").map(|c| parse_charges(c));
+
+    // Charge states are written as magnitudes, so negative charges imply a negative scan.
+    let negative_charges = matches!(&charges, Some(Ok(c)) if c.iter().all(|c| *c < 0));
+    match metadata.get("POLARITY").map(|p| p.as_str()) {
+        Some("+") | Some("positive") => {
+            push_cv_param(&mut xml, 10, "MS:1000130", "positive scan", "")
+        }
+        Some("-") | Some("negative") => {
+            push_cv_param(&mut xml, 10, "MS:1000129", "negative scan", "")
+        }
+        None if negative_charges => push_cv_param(&mut xml, 10, "MS:1000129", "negative scan", ""),
+        _ => {}
+    }
+    push_cv_param(&mut xml, 10, "MS:1000127", "centroid spectrum", "");
+    if let Some(title) = metadata.get("TITLE") {
+        push_cv_param(&mut xml, 10, "MS:1000796", "spectrum title", title);
+    }
+
+    // Converted keys whose values can't be converted, which are kept as userParams.
+    let mut kept = Vec::new();
+
+    let retention_time = metadata
+        .get("RTINSECONDS")
+        .map(|rt| rt.trim().parse::<f64>());
+    if let Some(Err(_)) = retention_time {
+        kept.push("RTINSECONDS");
+    }
+
+    let precursor = metadata.get("PEPMASS").map(|pm| parse_pepmass(pm));
+    if let Some(Err(_)) = precursor {
+        kept.push("PEPMASS");
+    }
+
+    // Charges and activation are only written as part of a precursor.
+    let has_precursor = matches!(precursor, Some(Ok(_)));
+
+    match charges {
+        Some(Ok(_)) if has_precursor => {}
+        Some(_) => kept.push("CHARGE"),
+        None => {}
+    }
+
+    let mut methods = Vec::new();
+    if let Some(activation) = metadata.get("ACTIVATION") {
+        match parse_activation(activation) {
+            Some(parsed) if has_precursor => methods = parsed,
+            _ => kept.push("ACTIVATION"),
+        }
+    }
+
+    let mut collision_energy = None;
+    if let Some(energy) = metadata.get("COLLISIONENERGY") {
+        match energy.trim().parse::<f64>() {
+            Ok(parsed) if has_precursor => collision_energy = Some(parsed),
+            _ => kept.push("COLLISIONENERGY"),
+        }
+    }
+
+    for (k, v) in metadata.iter() {
+        if CONVERTED_KEYS.contains(&k.as_str()) && !kept.contains(&k.as_str()) {
+            continue;
+        }
+        let _ = writeln!(
+            xml,
+            "          <userParam name=\"{}\" value=\"{}\"/>",
+            escape(k),
+            escape(v)
+        );
+    }
+
+    if let Some(Ok(rt)) = retention_time {
+        xml.push_str("          <scanList count=\"1\">\n");
+        push_cv_param(&mut xml, 12, "MS:1000795", "no combination", "");
+        let _ = writeln!(
+            xml,
+            "            <scan instrumentConfigurationRef=\"{}\">",
+            INSTRUMENT_CONFIGURATION_ID
+        );
+        push_cv_param_with_unit(
+            &mut xml,
+            14,
+            ("MS:1000016", "scan start time", &rt.to_string()),
+            ("UO", "UO:0000010", "second"),
+        );
+        xml.push_str("            </scan>\n          </scanList>\n");
+    }
+
+    if let Some(Ok((mz, intensity))) = precursor {
+        xml.push_str("          <precursorList count=\"1\">\n            <precursor>\n");
+        xml.push_str(
+            "              <selectedIonList count=\"1\">\n                <selectedIon>\n",
+        );
+        push_cv_param_with_unit(
+            &mut xml,
+            18,
+            ("MS:1000744", "selected ion m/z", &mz.to_string()),
+            ("MS", "MS:1000040", "m/z"),
+        );
+        match charges {
+            Some(Ok(ref charges)) if charges.len() == 1 => {
+                push_cv_param(
+                    &mut xml,
+                    18,
+                    "MS:1000041",
+                    "charge state",
+                    &charges[0].unsigned_abs().to_string(),
+                );
+            }
+            Some(Ok(ref charges)) => {
+                for charge in charges {
+                    push_cv_param(
+                        &mut xml,
+                        18,
+                        "MS:1000633",
+                        "possible charge state",
+                        &charge.unsigned_abs().to_string(),
+                    );
+                }
+            }
+            _ => {}
+        }
+        if let Some(intensity) = intensity {
+            push_cv_param_with_unit(
+                &mut xml,
+                18,
+                ("MS:1000042", "peak intensity", &intensity.to_string()),
+                ("MS", "MS:1000131", "number of detector counts"),
+            );
+        }
+        xml.push_str("                </selectedIon>\n              </selectedIonList>\n");
+        if methods.is_empty() && collision_energy.is_none() {
+            xml.push_str("              <activation/>\n");
+        } else {
+            xml.push_str("              <activation>\n");
+            for method in methods.iter() {
+                let (accession, name) = method.cv_term();
+                push_cv_param(&mut xml, 16, accession, name, "");
+            }
+            if let Some(energy) = collision_energy {
+                push_cv_param_with_unit(
+                    &mut xml,
+                    16,
+                    ("MS:1000045", "collision energy", &energy.to_string()),
+                    ("UO", "UO:0000266", "electronvolt"),
+                );
+            }
+            xml.push_str("              </activation>\n");
+        }
+        xml.push_str("            </precursor>\n          </precursorList>\n");
+    }
+
+    xml.push_str("          <binaryDataArrayList count=\"2\">\n");
+    push_binary_data_array(
+        &mut xml,
+        &spectrum.mz,
+        ct,
+        &DataType::Float64Bit,
+        ("MS:1000514", "m/z array"),
+        ("MS", "MS:1000040", "m/z"),
+    );
+    push_binary_data_array(
+        &mut xml,
+        &spectrum.intensities,
+        ct,
+        intensity_type,
+        ("MS:1000515", "intensity array"),
+        ("MS", "MS:1000131", "number of detector counts"),
+    );
+    xml.push_str("          </binaryDataArrayList>\n        </spectrum>\n");
+
+    xml
+}
+
+fn push_binary_data_array(
+    xml: &mut String,
+    values: &[f64],
+    ct: &CompressionType,
+    dt: &DataType,
+    array_type: (&str, &str),
+    unit: (&str, &str, &str),
+) {
+    let binary = encode_binary_array(values, ct, dt);
+
+    let _ = writeln!(
+        xml,
+        "            <binaryDataArray encodedLength=\"{}\">",
+        binary.content.len()
+    );
+    let (accession, name) = dt.cv_term();
+    push_cv_param(xml, 14, accession, name, "");
+    let (accession, name) = ct.cv_term();
+    push_cv_param(xml, 14, accession, name, "");
+    push_cv_param_with_unit(xml, 14, (array_type.0, array_type.1, ""), unit);
+    let _ = writeln!(xml, "              <binary>{}</binary>", binary.content);
+    xml.push_str("            </binaryDataArray>\n");
+}
+
+fn push_cv_param(xml: &mut String, indent: usize, accession: &str, name: &str, value: &str) {
+    let _ = writeln!(
+        xml,
+        "{:indent$}<cvParam cvRef=\"MS\" accession=\"{}\" name=\"{}\" value=\"{}\"/>",
+        "",
+        accession,
+        name,
+        escape(value),
+        indent = indent
+    );
+}
+
+/// Writes a cvParam given as (accession, name, value) with a unit given as (cv, accession, name).
+fn push_cv_param_with_unit(
+    xml: &mut String,
+    indent: usize,
+    param: (&str, &str, &str),
+    unit: (&str, &str, &str),
+) {
+    let _ = writeln!(
+        xml,
+        "{:indent$}<cvParam cvRef=\"MS\" accession=\"{}\" name=\"{}\" value=\"{}\" unitCvRef=\"{}\" unitAccession=\"{}\" unitName=\"{}\"/>",
+        "",
+        param.0,
+        param.1,
+        escape(param.2),
+        unit.0,
+        unit.1,
+        unit.2,
+        indent = indent
+    );
+}
+
+/// Parses an ACTIVATION value like `CID` or `ETD,HCD`.
+fn parse_activation(value: &str) -> Option<Vec<DissociationMethod>> {
+    value
+        .split(',')
+        .map(|m| m.trim().parse::<DissociationMethod>().ok())
+        .collect()
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    String::from_utf8_lossy(&quick_xml::escape::escape(value.as_bytes())).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use std::convert::TryFrom;
+
+    use crate::metadata::Metadata;
+
+    use crate::io::mzml_parser::indexed::IndexedMzMLReader;
+    use crate::io::mzml_parser::parser::MzMLReader;
+
+    fn test_spectra() -> Vec<Spectrum> {
+        let mut ms1 = Spectrum::new(Metadata::new(), vec![100.0, 200.5], vec![10.0, 20.0]);
+        ms1.add_metadata_field(String::from("TITLE"), String::from("scan=1"))
+            .add_metadata_field(String::from("SCANS"), String::from("1"))
+            .add_metadata_field(String::from("RTINSECONDS"), String::from("30.5"));
+
+        let mut ms2 = Spectrum::new(Metadata::new(), vec![150.25], vec![5.0]);
+        ms2.add_metadata_field(String::from("TITLE"), String::from("scan=2"))
+            .add_metadata_field(String::from("SCANS"), String::from("2"))
+            .add_metadata_field(String::from("PEPMASS"), String::from("445.34 1200"))
+            .add_metadata_field(String::from("CHARGE"), String::from("2+"))
+            .add_metadata_field(String::from("SOURCE"), String::from("a & b"));
+
+        vec![ms1, ms2]
+    }
+
+    #[test]
+    fn write_indexed_test() {
+        let mut writer = MzMLWriter::new(Vec::new()).with_index(true);
+        for spectrum in test_spectra() {
+            writer.write(spectrum).unwrap();
+        }
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let text = String::from_utf8(output.clone()).unwrap();
+        assert!(text.contains("<spectrumList count=\"2\""));
+        assert!(text.contains("name=\"charge state\" value=\"2\""));
+        assert!(text.contains("<userParam name=\"SOURCE\" value=\"a &amp; b\"/>"));
+
+        // The checksum covers the document up to the opening fileChecksum tag.
+        let checksum_start = text.find("<fileChecksum>").unwrap() + "<fileChecksum>".len();
+        let expected = format!("{:x}", Sha1::digest(&output[..checksum_start]));
+        assert_eq!(&text[checksum_start..checksum_start + 40], expected);
+
+        let mut reader = IndexedMzMLReader::new(Cursor::new(output)).unwrap();
+        assert_eq!(reader.len(), 2);
+
+        let spectrum = reader.get_spectrum_by_id("scan=2").unwrap();
+        let mz = spectrum.binary_data_array_list.binary_data_array[0]
+            .binary_array_to_vector()
+            .unwrap();
+        assert_eq!(mz, vec![150.25]);
+    }
+
+    #[test]
+    fn unique_id_test() {
+        let mut writer = MzMLWriter::new(Vec::new()).with_index(true);
+        for scans in ["7", "7", "100-105"] {
+            let mut spectrum = Spectrum::new(Metadata::new(), vec![100.0], vec![1.0]);
+            spectrum
+                .add_metadata_field(String::from("TITLE"), String::from("same"))
+                .add_metadata_field(String::from("SCANS"), String::from(scans));
+            writer.write(spectrum).unwrap();
+        }
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let text = String::from_utf8(output.clone()).unwrap();
+        assert!(text.contains("<offset idRef=\"scan=7\">"));
+        assert!(text.contains("<offset idRef=\"index=1\">"));
+        assert!(text.contains("<offset idRef=\"index=2\">"));
+        assert!(text.contains("name=\"spectrum title\" value=\"same\""));
+
+        let mut reader = IndexedMzMLReader::new(Cursor::new(output)).unwrap();
+        assert_eq!(reader.get_spectrum_by_id("index=1").unwrap().index, "1");
+
+        let mut reader = MzMLReader::from_reader(text.as_bytes());
+        for _ in 0..3 {
+            let spectrum = Spectrum::try_from(reader.read_spectrum().unwrap().unwrap()).unwrap();
+            assert_eq!(spectrum.metadata["TITLE"], "same");
+        }
+    }
+
+    #[test]
+    fn write_with_count_test() {
+        let mut writer = MzMLWriter::new(Vec::new())
+            .with_spectrum_count(2)
+            .with_compression(CompressionType::NoCompression);
+        for spectrum in test_spectra() {
+            writer.write(spectrum).unwrap();
+        }
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.starts_with("<?xml"));
+        assert!(!text.contains("indexedmzML"));
+        assert!(text.ends_with("  </mzML>\n"));
+        assert!(text.contains("name=\"no compression\""));
+    }
+
+    #[test]
+    fn intensity_precision_test() {
+        let write = |writer: MzMLWriter<Vec<u8>>| {
+            let mut writer = writer;
+            let spectrum = Spectrum::new(Metadata::new(), vec![100.0, 200.0], vec![0.1, 1e-9]);
+            writer.write(spectrum).unwrap();
+            writer.finish().unwrap();
+
+            let output = writer.into_inner().unwrap();
+            let mut reader = MzMLReader::from_reader(&output[..]);
+            Spectrum::try_from(reader.read_spectrum().unwrap().unwrap()).unwrap()
+        };
+
+        let spectrum = write(MzMLWriter::new(Vec::new()));
+        assert_eq!(spectrum.intensities, vec![0.1, 1e-9]);
+
+        let spectrum =
+            write(MzMLWriter::new(Vec::new()).with_intensity_precision(DataType::Float32Bit));
+        assert_eq!(spectrum.intensities, vec![0.1f32 as f64, 1e-9f32 as f64]);
+        assert_ne!(spectrum.intensities[0], 0.1);
+    }
+
+    #[test]
+    fn user_param_order_test() {
+        let mut spectrum = Spectrum::new(Metadata::new(), vec![100.0], vec![1.0]);
+        spectrum
+            .add_metadata_field(String::from("ZETA"), String::from("1"))
+            .add_metadata_field(String::from("RTINSECONDS"), String::from("n/a"))
+            .add_metadata_field(String::from("ALPHA"), String::from("2"));
+
+        let mut writer = MzMLWriter::new(Vec::new());
+        writer.write(spectrum).unwrap();
+        writer.finish().unwrap();
+        let text = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        let names: Vec<&str> = text
+            .split("<userParam name=\"")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["ZETA", "RTINSECONDS", "ALPHA"]);
+    }
+
+    #[test]
+    fn spool_test() {
+        let mut spool = Spool::new().unwrap();
+        spool.write_all(b"<spectrum/>").unwrap();
+        assert_eq!(spool.len, 11);
+
+        let mut copied = Vec::new();
+        spool.copy_to(&mut copied).unwrap();
+        assert_eq!(copied, b"<spectrum/>");
+
+        let path = spool.path.clone();
+        assert!(path.exists());
+        drop(spool);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn write_count_mismatch_test() {
+        let mut writer = MzMLWriter::new(Vec::new()).with_spectrum_count(3);
+        for spectrum in test_spectra() {
+            writer.write(spectrum).unwrap();
+        }
+
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut writer = MzMLWriter::new(Vec::new());
+        for spectrum in test_spectra() {
+            let mut spectrum = spectrum;
+            if spectrum.metadata.contains_key("PEPMASS") {
+                spectrum
+                    .add_metadata_field(String::from("ACTIVATION"), String::from("HCD"))
+                    .add_metadata_field(String::from("COLLISIONENERGY"), String::from("30"));
+            }
+            writer.write(spectrum).unwrap();
+        }
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let mut reader = MzMLReader::from_reader(&output[..]);
+        reader.read_spectrum().unwrap().unwrap();
+        let spectrum = Spectrum::try_from(reader.read_spectrum().unwrap().unwrap()).unwrap();
+
+        assert_eq!(spectrum.mz, vec![150.25]);
+        assert_eq!(spectrum.intensities, vec![5.0]);
+        assert_eq!(spectrum.metadata["TITLE"], "scan=2");
+        assert_eq!(spectrum.metadata["PEPMASS"], "445.34 1200");
+        assert_eq!(spectrum.metadata["CHARGE"], "2+");
+        assert_eq!(spectrum.metadata["ACTIVATION"], "HCD");
+        assert_eq!(spectrum.metadata["COLLISIONENERGY"], "30");
+        assert_eq!(spectrum.metadata["SCANS"], "2");
+        assert_eq!(spectrum.metadata["SOURCE"], "a & b");
+    }
+}
@@ -0,0 +1,850 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! Module for reading and writing mzXML 2.x and 3.x files.
+//!
+//! Scans are converted to the same MGF style metadata as mzML spectra (`TITLE`, `INDEX`,
+//! `MSLEVEL`, `RTINSECONDS`, `POLARITY`, `PEPMASS`, `CHARGE`, `ACTIVATION` and
+//! `COLLISIONENERGY`), with the scan number as `SCANS` and `nameValue` elements as their own keys.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::io::mzml_parser::escape;
+use crate::io::{compression, SpectrumReader, SpectrumWriter};
+use crate::metadata::{parse_charges, parse_pepmass};
+use crate::spectrum::Spectrum;
+
+/// Metadata keys that are written as scan attributes rather than nameValues.
+const CONVERTED_KEYS: [&str; 7] = [
+    "INDEX",
+    "MSLEVEL",
+    "POLARITY",
+    "RTINSECONDS",
+    "PEPMASS",
+    "CHARGE",
+    "COLLISIONENERGY",
+];
+
+/// Metadata keys that are read from scan and precursor attributes.
+const ATTRIBUTE_KEYS: [&str; 10] = [
+    "TITLE",
+    "SCANS",
+    "INDEX",
+    "MSLEVEL",
+    "POLARITY",
+    "RTINSECONDS",
+    "PEPMASS",
+    "CHARGE",
+    "ACTIVATION",
+    "COLLISIONENERGY",
+];
+
+/// Reads `Spectrum` objects from the scans of an mzXML file.
+///
+/// Nested scans, as written by mzXML 2.x converters, are returned in document order, i.e. each
+/// MS1 scan before the MS2 scans within it.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::mzxml_parser::MzXMLReader;
+/// use msn_kit::io::SpectrumReader;
+///
+/// let mzxml = br#"<mzXML><msRun scanCount="1">
+///   <scan num="7" msLevel="1" peaksCount="1" retentionTime="PT1M0.5S">
+///     <peaks precision="32" byteOrder="network" pairOrder="m/z-int">Q8gAAEEgAAA=</peaks>
+///   </scan>
+/// </msRun></mzXML>"#;
+///
+/// let mut reader = MzXMLReader::new(&mzxml[..]);
+/// let spectrum = reader.next_spectrum().unwrap().unwrap();
+///
+/// assert_eq!(spectrum.metadata["SCANS"], "7");
+/// assert_eq!(spectrum.metadata["RTINSECONDS"], "60.5");
+/// assert_eq!((spectrum.mz, spectrum.intensities), (vec![400.0], vec![10.0]));
+/// ```
+pub struct MzXMLReader<R: BufRead> {
+    reader: quick_xml::Reader<R>,
+    buf: Vec<u8>,
+    text_buf: Vec<u8>,
+    current: Option<Spectrum>,
+    ready: VecDeque<Spectrum>,
+    n_read: usize,
+    done: bool,
+}
+
+impl MzXMLReader<Box<dyn BufRead>> {
+    /// Open the mzXML file at `path`, decompressing it if it's compressed.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(compression::open(path)?))
+    }
+}
+
+impl<R: BufRead> MzXMLReader<R> {
+    /// Create a new MzXMLReader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The mzXML to read.
+    ///
+    pub fn new(reader: R) -> Self {
+        let mut xml_reader = quick_xml::Reader::from_reader(reader);
+        xml_reader.trim_text(true);
+
+        Self {
+            reader: xml_reader,
+            buf: Vec::new(),
+            text_buf: Vec::new(),
+            current: None,
+            ready: VecDeque::new(),
+            n_read: 0,
+            done: false,
+        }
+    }
+
+    /// Read the next scan, or `None` once the end of the run is reached.
+    pub fn read(&mut self) -> std::io::Result<Option<Spectrum>> {
+        loop {
+            if let Some(spectrum) = self.ready.pop_front() {
+                return Ok(Some(spectrum));
+            }
+            if self.done {
+                return Ok(None);
+            }
+
+            self.buf.clear();
+            let (name, attributes, empty) = match self.reader.read_event(&mut self.buf) {
+                Ok(Event::Start(e)) => (e.name().to_vec(), attributes(&e)?, false),
+                Ok(Event::Empty(e)) => (e.name().to_vec(), attributes(&e)?, true),
+                Ok(Event::End(e)) => {
+                    match e.name() {
+                        b"scan" => {
+                            if let Some(spectrum) = self.current.take() {
+                                return Ok(Some(spectrum));
+                            }
+                        }
+                        b"msRun" => self.done = true,
+                        _ => {}
+                    }
+                    continue;
+                }
+                Ok(Event::Eof) => {
+                    self.done = true;
+                    if self.current.is_some() || self.n_read > 0 {
+                        return Err(self.invalid("the document ended inside the run"));
+                    }
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Err(self.invalid(&e.to_string()));
+                }
+            };
+
+            match name.as_slice() {
+                b"scan" => {
+                    if let Some(parent) = self.current.take() {
+                        self.ready.push_back(parent);
+                    }
+                    self.current = Some(self.scan(&attributes)?);
+                    if empty {
+                        self.ready.extend(self.current.take());
+                    }
+                }
+                b"precursorMz" if !empty => {
+                    let mz = self.text(b"precursorMz")?;
+                    self.precursor(&attributes, &mz)?;
+                }
+                b"peaks" if !empty => {
+                    let content = self.text(b"peaks")?;
+                    self.peaks(&attributes, &content)?;
+                }
+                b"nameValue" => {
+                    if let (Some(k), Some(v)) = (attributes.get("name"), attributes.get("value")) {
+                        // Keys read from the scan's attributes are replaced, other keys can repeat.
+                        let metadata = &mut self.current_scan()?.metadata;
+                        if ATTRIBUTE_KEYS.contains(&k.as_str()) {
+                            metadata.insert(k.clone(), v.clone());
+                        } else {
+                            metadata.append(k.clone(), v.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn scan(&mut self, attributes: &HashMap<String, String>) -> std::io::Result<Spectrum> {
+        let num = attributes
+            .get("num")
+            .ok_or_else(|| self.invalid("missing attribute num on scan"))?;
+
+        let mut spectrum = Spectrum::empty();
+        let metadata = &mut spectrum.metadata;
+
+        metadata.insert(String::from("TITLE"), format!("scan={}", num));
+        metadata.insert(String::from("SCANS"), num.clone());
+        metadata.insert(String::from("INDEX"), self.n_read.to_string());
+        self.n_read += 1;
+
+        if let Some(ms_level) = attributes.get("msLevel") {
+            metadata.insert(String::from("MSLEVEL"), ms_level.clone());
+        }
+        if let Some(rt) = attributes.get("retentionTime").and_then(|d| duration(d)) {
+            metadata.insert(String::from("RTINSECONDS"), rt.to_string());
+        }
+        if let Some(polarity @ ("+" | "-")) = attributes.get("polarity").map(|p| p.as_str()) {
+            metadata.insert(String::from("POLARITY"), String::from(polarity));
+        }
+        if let Some(energy) = attributes.get("collisionEnergy") {
+            metadata.insert(String::from("COLLISIONENERGY"), energy.clone());
+        }
+
+        Ok(spectrum)
+    }
+
+    /// Sets the precursor metadata of the current scan, if it hasn't got one already.
+    fn precursor(&mut self, attributes: &HashMap<String, String>, mz: &str) -> std::io::Result<()> {
+        let metadata = &mut self.current_scan()?.metadata;
+        if metadata.contains_key("PEPMASS") {
+            return Ok(());
+        }
+
+        let intensity = attributes
+            .get("precursorIntensity")
+            .filter(|i| i.parse::<f64>().is_ok_and(|i| i != 0.0));
+        let pepmass = match intensity {
+            Some(intensity) => format!("{} {}", mz, intensity),
+            None => mz.to_string(),
+        };
+        metadata.insert(String::from("PEPMASS"), pepmass);
+
+        let sign = match metadata.get("POLARITY").map(|p| p.as_str()) {
+            Some("-") => "-",
+            _ => "+",
+        };
+        let charges = match (
+            attributes.get("precursorCharge"),
+            attributes.get("possibleCharges"),
+        ) {
+            (Some(charge), _) => vec![charge.as_str()],
+            (None, Some(charges)) => charges.split(',').map(|c| c.trim()).collect(),
+            (None, None) => Vec::new(),
+        };
+        if !charges.is_empty() {
+            let charges: Vec<String> = charges.iter().map(|c| format!("{}{}", c, sign)).collect();
+            metadata.insert(String::from("CHARGE"), charges.join(" and "));
+        }
+
+        if let Some(activation) = attributes.get("activationMethod") {
+            metadata.insert(String::from("ACTIVATION"), activation.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the interleaved m/z and intensity pairs of a peaks element into the current scan.
+    fn peaks(
+        &mut self,
+        attributes: &HashMap<String, String>,
+        content: &str,
+    ) -> std::io::Result<()> {
+        let get = |key: &str, default: &'static str| {
+            attributes.get(key).map(|v| v.as_str()).unwrap_or(default)
+        };
+
+        let width = match get("precision", "32") {
+            "32" => 4,
+            "64" => 8,
+            p => return Err(self.invalid(&format!("unsupported peaks precision {}", p))),
+        };
+        if get("byteOrder", "network") != "network" {
+            return Err(self.invalid("peaks must be in network byte order"));
+        }
+        let content_type = attributes
+            .get("contentType")
+            .or_else(|| attributes.get("pairOrder"))
+            .map(|c| c.as_str())
+            .unwrap_or("m/z-int");
+        if content_type != "m/z-int" {
+            return Err(self.invalid(&format!("unsupported peaks content {}", content_type)));
+        }
+
+        let compact: String = content.split_whitespace().collect();
+        let mut bytes = base64::decode(&compact).map_err(|e| self.invalid(&e.to_string()))?;
+        match get("compressionType", "none") {
+            "none" => {}
+            "zlib" => {
+                let mut decompressed = Vec::new();
+                ZlibDecoder::new(&bytes[..])
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| self.invalid(&e.to_string()))?;
+                bytes = decompressed;
+            }
+            c => return Err(self.invalid(&format!("unsupported peaks compression {}", c))),
+        }
+
+        if bytes.len() % (2 * width) != 0 {
+            return Err(self.invalid(&format!(
+                "{} bytes of peaks aren't a whole number of {} bit pairs",
+                bytes.len(),
+                width * 8
+            )));
+        }
+
+        let spectrum = self.current_scan()?;
+        for pair in bytes.chunks_exact(2 * width) {
+            let (mz, intensity) = pair.split_at(width);
+            if width == 4 {
+                spectrum.mz.push(BigEndian::read_f32(mz) as f64);
+                spectrum
+                    .intensities
+                    .push(BigEndian::read_f32(intensity) as f64);
+            } else {
+                spectrum.mz.push(BigEndian::read_f64(mz));
+                spectrum.intensities.push(BigEndian::read_f64(intensity));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn text(&mut self, end: &[u8]) -> std::io::Result<String> {
+        self.text_buf.clear();
+        let text = self.reader.read_text(end, &mut self.text_buf);
+
+        text.map_err(|e| self.invalid(&e.to_string()))
+    }
+
+    fn current_scan(&mut self) -> std::io::Result<&mut Spectrum> {
+        if self.current.is_none() {
+            return Err(self.invalid("element outside of a scan"));
+        }
+
+        Ok(self.current.as_mut().unwrap())
+    }
+
+    /// Returns an error about the scan being read, or the position in the document if there's
+    /// no scan.
+    fn invalid(&self, message: &str) -> Error {
+        let location = match self.current.as_ref().and_then(|s| s.metadata.get("SCANS")) {
+            Some(num) => format!("scan {}", num),
+            None => format!("byte {}", self.reader.buffer_position()),
+        };
+
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid mzXML at {}: {}", location, message),
+        )
+    }
+}
+
+impl<R: BufRead> SpectrumReader for MzXMLReader<R> {
+    fn next_spectrum(&mut self) -> std::io::Result<Option<Spectrum>> {
+        self.read()
+    }
+}
+
+fn attributes(e: &BytesStart) -> std::io::Result<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+
+    for attribute in e.attributes() {
+        let attribute = attribute.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let value = attribute
+            .unescaped_value()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+        attributes.insert(
+            String::from_utf8_lossy(attribute.key).into_owned(),
+            String::from_utf8_lossy(&value).into_owned(),
+        );
+    }
+
+    Ok(attributes)
+}
+
+/// Parses an xs:duration like `PT1M30.5S` into seconds.
+fn duration(value: &str) -> Option<f64> {
+    let value = value.trim().strip_prefix('P')?;
+    let (date, time) = value.split_once('T').unwrap_or((value, ""));
+
+    let mut seconds = 0.0;
+    for (part, units) in [
+        (date, &[('D', 86400.0)][..]),
+        (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..]),
+    ] {
+        let mut rest = part;
+        while !rest.is_empty() {
+            let end = rest.find(|c: char| c.is_ascii_alphabetic())?;
+            let unit = rest[end..].chars().next()?;
+            let (_, multiplier) = units.iter().find(|(u, _)| *u == unit)?;
+
+            seconds += rest[..end].parse::<f64>().ok()? * multiplier;
+            rest = &rest[end + 1..];
+        }
+    }
+
+    Some(seconds)
+}
+
+/// Writes `Spectrum` objects as an mzXML 3.2 document, with a scan index.
+///
+/// Well known metadata keys are written as scan and precursor attributes, `SCANS` as the scan
+/// number if it is one that hasn't been used yet, and other keys as nameValue elements. Scans
+/// without a usable `SCANS` are numbered with the next unused number from their position.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+/// use msn_kit::io::mzxml_parser::MzXMLWriter;
+/// use msn_kit::spectrum::Spectrum;
+///
+/// let mut writer = MzXMLWriter::new(Vec::new()).with_compression(true);
+/// writer.write(Spectrum::new(Metadata::new(), vec![100.0], vec![1.0])).unwrap();
+/// writer.finish().unwrap();
+///
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert!(output.contains("<offset id=\"1\">"));
+/// ```
+pub struct MzXMLWriter<W: Write> {
+    writer: BufWriter<W>,
+    compression: bool,
+    position: u64,
+    offsets: Vec<(String, u64)>,
+    nums: HashSet<u64>,
+    started: bool,
+    finished: bool,
+}
+
+impl<W: Write> MzXMLWriter<W> {
+    /// Create a new MzXMLWriter object.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - An object that can be written to.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            compression: false,
+            position: 0,
+            offsets: Vec::new(),
+            nums: HashSet::new(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Zlib compress the peaks before base64 encoding them.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write spectrum to the underlying buffer as an mzXML scan.
+    ///
+    /// # Arguments
+    ///
+    /// * `spectrum` - The spectrum to write.
+    ///
+    pub fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        if self.finished {
+            return Err(Error::other("Cannot write a spectrum after finish."));
+        }
+        if spectrum.mz.len() != spectrum.intensities.len() {
+            return Err(Error::other(format!(
+                "Spectrum has {} m/z values but {} intensities.",
+                spectrum.mz.len(),
+                spectrum.intensities.len()
+            )));
+        }
+
+        self.start()?;
+
+        let scans = spectrum
+            .metadata
+            .get("SCANS")
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .filter(|s| *s > 0);
+        let num = match scans {
+            Some(scan) if !self.nums.contains(&scan) => scan,
+            _ => {
+                let mut num = self.offsets.len() as u64 + 1;
+                while self.nums.contains(&num) {
+                    num += 1;
+                }
+                num
+            }
+        };
+        self.nums.insert(num);
+        self.offsets.push((num.to_string(), self.position + 4));
+
+        let xml = self.scan_xml(&spectrum, num, scans == Some(num))?;
+        self.write_str(&xml)
+    }
+
+    /// Write the end of the document and the scan index, and flush the writer.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.start()?;
+
+        self.write_str("  </msRun>\n")?;
+
+        let index_offset = self.position + 2;
+        let mut index = String::from("  <index name=\"scan\">\n");
+        for (num, offset) in self.offsets.iter() {
+            index.push_str(&format!("    <offset id=\"{}\">{}</offset>\n", num, offset));
+        }
+        index.push_str("  </index>\n");
+        index.push_str(&format!("  <indexOffset>{}</indexOffset>\n", index_offset));
+        index.push_str("</mzXML>\n");
+        self.write_str(&index)?;
+
+        self.writer.flush()?;
+        self.finished = true;
+
+        Ok(())
+    }
+
+    /// Returns the underlying writer, flushing any buffered output.
+    pub fn into_inner(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+
+    fn start(&mut self) -> std::io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+
+        let mut header = String::new();
+        header.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        header.push_str("<mzXML xmlns=\"http://sashimi.sourceforge.net/schema_revision/mzXML_3.2\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://sashimi.sourceforge.net/schema_revision/mzXML_3.2 http://sashimi.sourceforge.net/schema_revision/mzXML_3.2/mzXML_idx_3.2.xsd\">\n");
+        header.push_str("  <msRun>\n");
+        header.push_str("    <dataProcessing centroided=\"1\">\n");
+        header.push_str(&format!(
+            "      <software type=\"conversion\" name=\"msn-kit\" version=\"{}\"/>\n",
+            env!("CARGO_PKG_VERSION")
+        ));
+        header.push_str("    </dataProcessing>\n");
+
+        self.write_str(&header)
+    }
+
+    /// Builds the scan element, with `SCANS` as a nameValue unless it's `num`.
+    fn scan_xml(&self, spectrum: &Spectrum, num: u64, is_scans: bool) -> std::io::Result<String> {
+        let metadata = &spectrum.metadata;
+        // Converted keys whose values can't be converted, which are kept as nameValues.
+        let mut kept = Vec::new();
+        let mut activation_written = false;
+
+        let ms_level = match metadata.get("MSLEVEL") {
+            Some(level) => level.clone(),
+            None if metadata.contains_key("PEPMASS") => String::from("2"),
+            None => String::from("1"),
+        };
+
+        let mut xml = format!(
+            "    <scan num=\"{}\" msLevel=\"{}\" peaksCount=\"{}\"",
+            num,
+            escape(&ms_level),
+            spectrum.mz.len()
+        );
+
+        let charges = metadata.get("CHARGE").map(|c| parse_charges(c));
+
+        // Charges are written as magnitudes, so negative charges imply a negative polarity.
+        let negative_charges = matches!(&charges, Some(Ok(c)) if c.iter().all(|c| *c < 0));
+        match metadata.get("POLARITY").map(|p| p.as_str()) {
+            Some("+") | Some("positive") => xml.push_str(" polarity=\"+\""),
+            Some("-") | Some("negative") => xml.push_str(" polarity=\"-\""),
+            Some(_) => kept.push("POLARITY"),
+            None if negative_charges => xml.push_str(" polarity=\"-\""),
+            None => {}
+        }
+
+        if let Some(rt) = metadata.get("RTINSECONDS") {
+            match rt.trim().parse::<f64>() {
+                Ok(rt) => xml.push_str(&format!(" retentionTime=\"PT{}S\"", rt)),
+                Err(_) => kept.push("RTINSECONDS"),
+            }
+        }
+
+        if let Some(energy) = metadata.get("COLLISIONENERGY") {
+            match energy.trim().parse::<f64>() {
+                Ok(energy) => xml.push_str(&format!(" collisionEnergy=\"{}\"", energy)),
+                Err(_) => kept.push("COLLISIONENERGY"),
+            }
+        }
+        xml.push_str(">\n");
+
+        if let Some(pepmass) = metadata.get("PEPMASS") {
+            match parse_pepmass(pepmass) {
+                Ok((mz, intensity)) => {
+                    xml.push_str("      <precursorMz");
+                    if let Some(intensity) = intensity {
+                        xml.push_str(&format!(" precursorIntensity=\"{}\"", intensity));
+                    }
+
+                    match charges {
+                        Some(Ok(charges)) if charges.len() == 1 => xml.push_str(&format!(
+                            " precursorCharge=\"{}\"",
+                            charges[0].unsigned_abs()
+                        )),
+                        Some(Ok(charges)) => {
+                            let charges: Vec<String> = charges
+                                .iter()
+                                .map(|c| c.unsigned_abs().to_string())
+                                .collect();
+                            xml.push_str(&format!(" possibleCharges=\"{}\"", charges.join(",")))
+                        }
+                        Some(Err(_)) => kept.push("CHARGE"),
+                        None => {}
+                    }
+
+                    if let Some(activation) = metadata.get("ACTIVATION") {
+                        xml.push_str(&format!(" activationMethod=\"{}\"", escape(activation)));
+                        activation_written = true;
+                    }
+                    xml.push_str(&format!(">{}</precursorMz>\n", mz));
+                }
+                Err(_) => {
+                    kept.push("PEPMASS");
+                    kept.push("CHARGE");
+                }
+            }
+        } else {
+            kept.push("CHARGE");
+        }
+
+        let mut pairs = Vec::with_capacity(spectrum.mz.len() * 16);
+        for (mz, intensity) in spectrum.mz.iter().zip(&spectrum.intensities) {
+            pairs.write_f64::<BigEndian>(*mz)?;
+            pairs.write_f64::<BigEndian>(*intensity)?;
+        }
+
+        let (compression_type, compressed_len) = if self.compression {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&pairs)?;
+            pairs = encoder.finish()?;
+            ("zlib", pairs.len())
+        } else {
+            ("none", 0)
+        };
+        xml.push_str(&format!(
+            "      <peaks compressionType=\"{}\" compressedLen=\"{}\" precision=\"64\" byteOrder=\"network\" contentType=\"m/z-int\">{}</peaks>\n",
+            compression_type,
+            compressed_len,
+            base64::encode(&pairs)
+        ));
+
+        for (k, v) in metadata.iter() {
+            let converted = (CONVERTED_KEYS.contains(&k.as_str()) && !kept.contains(&k.as_str()))
+                || (k == "ACTIVATION" && activation_written)
+                || (k == "SCANS" && is_scans);
+            if converted {
+                continue;
+            }
+            xml.push_str(&format!(
+                "      <nameValue name=\"{}\" value=\"{}\"/>\n",
+                escape(k),
+                escape(v)
+            ));
+        }
+        xml.push_str("    </scan>\n");
+
+        Ok(xml)
+    }
+
+    fn write_str(&mut self, s: &str) -> std::io::Result<()> {
+        self.writer.write_all(s.as_bytes())?;
+        self.position += s.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> SpectrumWriter for MzXMLWriter<W> {
+    fn write(&mut self, spectrum: Spectrum) -> std::io::Result<()> {
+        MzXMLWriter::write(self, spectrum)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        MzXMLWriter::finish(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::metadata::Metadata;
+
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    fn test_file() -> MzXMLReader<BufReader<File>> {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test/test.mzxml.xml");
+
+        MzXMLReader::new(BufReader::new(File::open(d).unwrap()))
+    }
+
+    #[test]
+    fn reader_test() {
+        let spectra: Vec<Spectrum> = test_file().into_spectra().map(|s| s.unwrap()).collect();
+        assert_eq!(spectra.len(), 3);
+
+        let ms1 = &spectra[0];
+        assert_eq!(ms1.mz, vec![200.25, 445.5, 1000.5]);
+        assert_eq!(ms1.intensities, vec![100.0, 2000.0, 40.0]);
+        assert_eq!(ms1.metadata["TITLE"], "scan=1");
+        assert_eq!(ms1.metadata["INDEX"], "0");
+        assert_eq!(ms1.metadata["MSLEVEL"], "1");
+        assert_eq!(ms1.metadata["RTINSECONDS"], "30.5");
+        assert_eq!(ms1.metadata["POLARITY"], "+");
+
+        // The nested scan, with 64 bit zlib compressed peaks.
+        let ms2 = &spectra[1];
+        assert_eq!(ms2.mz, vec![150.125, 300.0625]);
+        assert_eq!(ms2.intensities, vec![5.0, 7.5]);
+        assert_eq!(ms2.metadata["SCANS"], "2");
+        assert_eq!(ms2.metadata["MSLEVEL"], "2");
+        assert_eq!(ms2.metadata["RTINSECONDS"], "91");
+        assert_eq!(ms2.metadata["PEPMASS"], "445.5 1200");
+        assert_eq!(ms2.metadata["CHARGE"], "2+");
+        assert_eq!(ms2.metadata["ACTIVATION"], "CID");
+        assert_eq!(ms2.metadata["COLLISIONENERGY"], "35");
+        assert_eq!(ms2.metadata["SOURCE"], "a & b");
+
+        let empty = &spectra[2];
+        assert_eq!(empty.metadata["SCANS"], "3");
+        assert!(empty.mz.is_empty());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for compression in [false, true] {
+            let mut writer = MzXMLWriter::new(Vec::new()).with_compression(compression);
+            for spectrum in test_file().into_spectra() {
+                writer.write(spectrum.unwrap()).unwrap();
+            }
+            writer.finish().unwrap();
+            let output = writer.into_inner().unwrap();
+
+            let expected: Vec<Spectrum> = test_file().into_spectra().map(|s| s.unwrap()).collect();
+            let actual: Vec<Spectrum> = MzXMLReader::new(&output[..])
+                .into_spectra()
+                .map(|s| s.unwrap())
+                .collect();
+            assert_eq!(expected, actual);
+
+            // The index points at each scan.
+            let text = String::from_utf8(output).unwrap();
+            let offset: usize = text
+                .split("<offset id=\"2\">")
+                .nth(1)
+                .and_then(|s| s.split('<').next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(text[offset..].starts_with("<scan num=\"2\""));
+        }
+    }
+
+    #[test]
+    fn scan_num_test() {
+        let mut writer = MzXMLWriter::new(Vec::new());
+        for scans in [Some("2"), Some("2"), None, Some("100-105")] {
+            let mut spectrum = Spectrum::new(Metadata::new(), vec![100.0], vec![1.0]);
+            if let Some(scans) = scans {
+                spectrum.add_metadata_field(String::from("SCANS"), String::from(scans));
+            }
+            writer.write(spectrum).unwrap();
+        }
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+
+        let text = String::from_utf8(output.clone()).unwrap();
+        for num in ["2", "3", "4", "5"] {
+            assert_eq!(text.matches(&format!("<offset id=\"{}\">", num)).count(), 1);
+        }
+
+        let spectra: Vec<Spectrum> = MzXMLReader::new(&output[..])
+            .into_spectra()
+            .map(|s| s.unwrap())
+            .collect();
+        let titles: Vec<&str> = spectra
+            .iter()
+            .map(|s| s.metadata["TITLE"].as_str())
+            .collect();
+        assert_eq!(titles, vec!["scan=2", "scan=3", "scan=4", "scan=5"]);
+        let scans: Vec<&str> = spectra
+            .iter()
+            .map(|s| s.metadata["SCANS"].as_str())
+            .collect();
+        assert_eq!(scans, vec!["2", "2", "4", "100-105"]);
+    }
+
+    #[test]
+    fn name_value_order_test() {
+        let mut spectrum = Spectrum::new(Metadata::new(), vec![100.0], vec![1.0]);
+        spectrum
+            .add_metadata_field(String::from("ZETA"), String::from("1"))
+            .add_metadata_field(String::from("RTINSECONDS"), String::from("n/a"))
+            .add_metadata_field(String::from("ALPHA"), String::from("2"));
+        spectrum.metadata.append("ZETA", "3");
+
+        let mut writer = MzXMLWriter::new(Vec::new());
+        writer.write(spectrum).unwrap();
+        writer.finish().unwrap();
+        let output = writer.into_inner().unwrap();
+        let text = String::from_utf8(output.clone()).unwrap();
+
+        let names: Vec<&str> = text
+            .split("<nameValue name=\"")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["ZETA", "RTINSECONDS", "ALPHA", "ZETA"]);
+
+        let spectrum = MzXMLReader::new(&output[..]).read().unwrap().unwrap();
+        let zeta: Vec<&String> = spectrum.metadata.get_all("ZETA").collect();
+        assert_eq!(zeta, vec!["1", "3"]);
+    }
+
+    #[test]
+    fn invalid_peaks_test() {
+        let mzxml = br#"<mzXML><msRun>
+  <scan num="4" msLevel="1" peaksCount="1">
+    <peaks precision="32" byteOrder="network" pairOrder="m/z-int">Q8gAAA==</peaks>
+  </scan>
+</msRun></mzXML>"#;
+
+        let error = MzXMLReader::new(&mzxml[..]).read().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid mzXML at scan 4: 4 bytes of peaks aren't a whole number of 32 bit pairs"
+        );
+    }
+
+    #[test]
+    fn duration_test() {
+        assert_eq!(duration("PT30.5S"), Some(30.5));
+        assert_eq!(duration("PT1H2M3S"), Some(3723.0));
+        assert_eq!(duration("P1DT1S"), Some(86401.0));
+        assert_eq!(duration("30.5"), None);
+    }
+}
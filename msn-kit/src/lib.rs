@@ -25,5 +25,7 @@
     html_favicon_url = "https://raw.githubusercontent.com/tshauck/msn-kit/main/msn-kit/docs/msn_logo.svg"
 )]
 
+pub mod chromatogram;
 pub mod io;
+pub mod metadata;
 pub mod spectrum;
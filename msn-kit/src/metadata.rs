@@ -0,0 +1,466 @@
+// (c) Copyright 2021 Trent Hauck
+// All Rights Reserved
+//! The metadata of a spectrum, its key value pairs such as `TITLE` and `PEPMASS`.
+
+use std::fmt;
+use std::ops::{Index, RangeInclusive};
+use std::str::FromStr;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// A spectrum's metadata, kept in the order it was read, including repeated keys.
+///
+/// Lookups return the first value of a key and `get_all` returns all of them. Metadata is
+/// serialized as a map, with a list of values for repeated keys.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::metadata::Metadata;
+///
+/// let mut metadata = Metadata::new();
+/// metadata.insert("TITLE", "scan=1");
+/// metadata.append("COMMENT", "first");
+/// metadata.append("COMMENT", "second");
+///
+/// assert_eq!(metadata["TITLE"], "scan=1");
+/// assert_eq!(metadata.get_all("COMMENT").collect::<Vec<_>>(), vec!["first", "second"]);
+/// assert_eq!(metadata.len(), 3);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    /// Create empty metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of key value pairs, counting each value of a repeated key.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if there's no metadata.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all the metadata.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the first value of `key`, if it's set.
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Returns every value of `key`, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a String> {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Returns true if `key` is set.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|(k, _)| k == key)
+    }
+
+    /// Sets `key` to `value`, returning its previous first value. An existing key keeps its
+    /// position and any repeated values of it are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to set, e.g. `TITLE`.
+    /// * `value` - The key's value.
+    ///
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Option<String> {
+        let key = key.into();
+        let value = value.into();
+
+        match self.entries.iter().position(|(k, _)| *k == key) {
+            Some(i) => {
+                let previous = std::mem::replace(&mut self.entries[i].1, value);
+                let mut j = i + 1;
+                while j < self.entries.len() {
+                    if self.entries[j].0 == key {
+                        self.entries.remove(j);
+                    } else {
+                        j += 1;
+                    }
+                }
+                Some(previous)
+            }
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Adds `value` to the end, after any existing values of `key`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key to add, e.g. `COMMENT`.
+    /// * `value` - The added value.
+    ///
+    pub fn append<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.entries.push((key.into(), value.into()));
+    }
+
+    /// Removes every value of `key`, returning the first.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        let (_, first) = self.entries.remove(i);
+        self.entries.retain(|(k, _)| k != key);
+        Some(first)
+    }
+
+    /// Returns an iterator over the key value pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    /// Returns an iterator over the keys, in order, with repeated keys only returned once.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(i, (k, _))| !self.entries[..*i].iter().any(|(seen, _)| seen == k))
+            .map(|(_, (k, _))| k)
+    }
+}
+
+impl Index<&str> for Metadata {
+    type Output = String;
+
+    /// Returns the first value of `key`, panicking if it isn't set.
+    fn index(&self, key: &str) -> &String {
+        self.get(key)
+            .unwrap_or_else(|| panic!("No metadata for key {}", key))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut metadata = Metadata::new();
+        metadata.extend(iter);
+        metadata
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> Extend<(K, V)> for Metadata {
+    /// Appends the key value pairs, keeping repeated keys.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.append(k, v);
+        }
+    }
+}
+
+impl IntoIterator for Metadata {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl Serialize for Metadata {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let keys: Vec<&String> = self.keys().collect();
+
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for key in keys {
+            let values: Vec<&String> = self.get_all(key).collect();
+            match values.as_slice() {
+                [value] => map.serialize_entry(key, value)?,
+                _ => map.serialize_entry(key, &values)?,
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Metadata {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(MetadataVisitor)
+    }
+}
+
+/// A value in serialized metadata, a list for a repeated key.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Values {
+    One(String),
+    Many(Vec<String>),
+}
+
+struct MetadataVisitor;
+
+impl<'de> Visitor<'de> for MetadataVisitor {
+    type Value = Metadata;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of strings or lists of strings")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Metadata, A::Error> {
+        let mut metadata = Metadata::new();
+
+        while let Some((key, values)) = access.next_entry::<String, Values>()? {
+            match values {
+                Values::One(value) => metadata.append(key, value),
+                Values::Many(values) => {
+                    for value in values {
+                        metadata.append(key.as_str(), value);
+                    }
+                }
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+/// A metadata value that couldn't be parsed as its key's type, e.g. a `PEPMASS` that isn't a
+/// number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataError {
+    /// The key of the value, e.g. `PEPMASS`.
+    pub key: String,
+
+    /// The value that couldn't be parsed.
+    pub value: String,
+
+    /// A description of the values that were expected.
+    pub expected: &'static str,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid {} {:?}, expected {}",
+            self.key, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<MetadataError> for std::io::Error {
+    fn from(e: MetadataError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+const PEPMASS_EXPECTED: &str = "an m/z optionally followed by an intensity";
+const CHARGE_EXPECTED: &str = "charges like 2+ or 2+ and 3+";
+const RANGE_EXPECTED: &str = "a number or a range like 10-12";
+
+/// Parses a PEPMASS value, an m/z optionally followed by an intensity.
+pub(crate) fn parse_pepmass(value: &str) -> Result<(f64, Option<f64>), &'static str> {
+    let mut parts = value.split_whitespace();
+
+    let parse = |part: &str| part.parse::<f64>().map_err(|_| PEPMASS_EXPECTED);
+    let mz = parse(parts.next().ok_or(PEPMASS_EXPECTED)?)?;
+    let intensity = parts.next().map(parse).transpose()?;
+
+    match parts.next() {
+        Some(_) => Err(PEPMASS_EXPECTED),
+        None => Ok((mz, intensity)),
+    }
+}
+
+/// Parses a CHARGE value like `2+`, `3`, `2-` or `2+ and 3+` into signed charges.
+pub(crate) fn parse_charges(value: &str) -> Result<Vec<i32>, &'static str> {
+    let charges = value
+        .split([',', ' ', '\t'])
+        .filter(|c| !c.is_empty() && *c != "and")
+        .map(|c| {
+            let negative = c.starts_with('-') || c.ends_with('-');
+            let magnitude = c
+                .trim_matches(['+', '-'])
+                .parse::<i32>()
+                .map_err(|_| CHARGE_EXPECTED)?;
+            Ok(if negative { -magnitude } else { magnitude })
+        })
+        .collect::<Result<Vec<i32>, _>>()?;
+
+    if charges.is_empty() {
+        return Err(CHARGE_EXPECTED);
+    }
+
+    Ok(charges)
+}
+
+/// Parses a single value or an inclusive range of them, like `10-12`.
+pub(crate) fn parse_range<T>(value: &str) -> Result<RangeInclusive<T>, &'static str>
+where
+    T: FromStr + PartialOrd + Copy,
+{
+    let value = value.trim();
+    if let Ok(v) = value.parse::<T>() {
+        return Ok(v..=v);
+    }
+
+    // Try each dash, as a float can have one in its exponent.
+    for (i, _) in value.match_indices('-').filter(|(i, _)| *i > 0) {
+        if let (Ok(start), Ok(end)) = (
+            value[..i].trim().parse::<T>(),
+            value[i + 1..].trim().parse::<T>(),
+        ) {
+            if start <= end {
+                return Ok(start..=end);
+            }
+        }
+    }
+
+    Err(RANGE_EXPECTED)
+}
+
+pub(crate) fn format_pepmass(mz: f64, intensity: Option<f64>) -> String {
+    match intensity {
+        Some(intensity) => format!("{} {}", mz, intensity),
+        None => mz.to_string(),
+    }
+}
+
+pub(crate) fn format_charges(charges: &[i32]) -> String {
+    charges
+        .iter()
+        .map(|c| match c.signum() {
+            -1 => format!("{}-", c.abs()),
+            0 => String::from("0"),
+            _ => format!("{}+", c),
+        })
+        .collect::<Vec<String>>()
+        .join(" and ")
+}
+
+pub(crate) fn format_range<T: fmt::Display + PartialEq>(range: &RangeInclusive<T>) -> String {
+    if range.start() == range.end() {
+        range.start().to_string()
+    } else {
+        format!("{}-{}", range.start(), range.end())
+    }
+}
+
+/// Returns the canonical form of the value of a standard key, `None` if `key` isn't one or the
+/// value can't be parsed.
+pub(crate) fn canonical(key: &str, value: &str) -> Option<String> {
+    match key {
+        "PEPMASS" => parse_pepmass(value)
+            .ok()
+            .map(|(mz, intensity)| format_pepmass(mz, intensity)),
+        "CHARGE" => parse_charges(value).ok().map(|c| format_charges(&c)),
+        "RTINSECONDS" => parse_range::<f64>(value).ok().map(|r| format_range(&r)),
+        "SCANS" => parse_range::<u64>(value).ok().map(|r| format_range(&r)),
+        "MSLEVEL" => value.trim().parse::<u32>().ok().map(|l| l.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_test() {
+        let mut metadata: Metadata = [("TITLE", "1"), ("CHARGE", "2+"), ("CHARGE", "3+")]
+            .into_iter()
+            .collect();
+        metadata.append("SCANS", "1");
+
+        assert_eq!(metadata.insert("CHARGE", "1+"), Some(String::from("2+")));
+        assert_eq!(metadata.insert("RTINSECONDS", "10"), None);
+
+        let pairs: Vec<(&String, &String)> = metadata.iter().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (&String::from("TITLE"), &String::from("1")),
+                (&String::from("CHARGE"), &String::from("1+")),
+                (&String::from("SCANS"), &String::from("1")),
+                (&String::from("RTINSECONDS"), &String::from("10")),
+            ]
+        );
+
+        assert_eq!(metadata.remove("TITLE"), Some(String::from("1")));
+        assert!(!metadata.contains_key("TITLE"));
+        assert_eq!(metadata.len(), 3);
+    }
+
+    #[test]
+    fn serde_test() {
+        let metadata: Metadata = [
+            ("TITLE", "1"),
+            ("COMMENT", "a"),
+            ("SCANS", "2"),
+            ("COMMENT", "b"),
+        ]
+        .into_iter()
+        .collect();
+
+        let keys: Vec<&String> = metadata.keys().collect();
+        assert_eq!(keys, vec!["TITLE", "COMMENT", "SCANS"]);
+
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert_eq!(json, r#"{"TITLE":"1","COMMENT":["a","b"],"SCANS":"2"}"#);
+
+        let read: Metadata = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.get_all("COMMENT").collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(read.keys().collect::<Vec<_>>(), keys);
+
+        assert!(serde_json::from_str::<Metadata>(r#"{"TITLE":1}"#).is_err());
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse_pepmass("898.727"), Ok((898.727, None)));
+        assert_eq!(
+            parse_pepmass("898.727\t12345.6"),
+            Ok((898.727, Some(12345.6)))
+        );
+        assert!(parse_pepmass("898.727 1 2").is_err());
+        assert!(parse_pepmass("").is_err());
+
+        assert_eq!(parse_charges("2+,3+"), Ok(vec![2, 3]));
+        assert_eq!(parse_charges("2+ and 3+"), Ok(vec![2, 3]));
+        assert_eq!(parse_charges("3"), Ok(vec![3]));
+        assert_eq!(parse_charges("-1, 2-"), Ok(vec![-1, -2]));
+        assert!(parse_charges("two").is_err());
+        assert!(parse_charges("").is_err());
+
+        assert_eq!(parse_range::<u64>("100-105"), Ok(100..=105));
+        assert_eq!(parse_range::<u64>("7"), Ok(7..=7));
+        assert!(parse_range::<u64>("105-100").is_err());
+        assert_eq!(parse_range::<f64>("1e-3"), Ok(0.001..=0.001));
+        assert_eq!(parse_range::<f64>("10.5-1e2"), Ok(10.5..=100.0));
+        assert!(parse_range::<f64>("ten").is_err());
+
+        assert_eq!(
+            canonical("CHARGE", "2+,3+"),
+            Some(String::from("2+ and 3+"))
+        );
+        assert_eq!(
+            canonical("PEPMASS", "898.7270 12345.60"),
+            Some(String::from("898.727 12345.6"))
+        );
+        assert_eq!(
+            canonical("SCANS", "0100-0105"),
+            Some(String::from("100-105"))
+        );
+        assert_eq!(canonical("CHARGE", "two"), None);
+        assert_eq!(canonical("TITLE", "scan=1"), None);
+    }
+}
@@ -2,23 +2,25 @@
 // All Rights Reserved
 //! A Spectrum is a set of peaks and associated metadata.
 
+use crate::io::{mgf_parser, mzml_parser};
+use crate::metadata::{self, Metadata, MetadataError};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
 /// # Examples
 ///
 /// The simplest spectrum with a single peak.
 ///
 /// ```
-/// use std::collections::HashMap;
+/// use msn_kit::metadata::Metadata;
 ///
-/// let s = msn_kit::spectrum::Spectrum::new(HashMap::<String, String>::new(), vec![1.0], vec![1.0]);
+/// let s = msn_kit::spectrum::Spectrum::new(Metadata::new(), vec![1.0], vec![1.0]);
 /// assert_eq!(s.mz, vec![1.0]);
 /// assert_eq!(s.intensities, vec![1.0]);
 /// ```
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Spectrum {
-    pub metadata: HashMap<String, String>,
+    pub metadata: Metadata,
     pub mz: Vec<f64>,
     pub intensities: Vec<f64>,
 }
@@ -32,7 +34,7 @@ impl Spectrum {
     /// * `mz` - The mass to charge ratio vector.
     /// * `intensities` - The intensities vector.
     ///
-    pub fn new(metadata: HashMap<String, String>, mz: Vec<f64>, intensities: Vec<f64>) -> Self {
+    pub fn new(metadata: Metadata, mz: Vec<f64>, intensities: Vec<f64>) -> Self {
         Self {
             metadata,
             mz,
@@ -44,7 +46,7 @@ impl Spectrum {
     ///
     pub fn empty() -> Self {
         Self {
-            metadata: HashMap::<String, String>::new(),
+            metadata: Metadata::new(),
             mz: Vec::<f64>::new(),
             intensities: Vec::<f64>::new(),
         }
@@ -73,4 +75,224 @@ impl Spectrum {
         self.intensities = intensities;
         self
     }
+
+    /// Returns the `TITLE`, if it's set.
+    pub fn title(&self) -> Option<&str> {
+        self.metadata.get("TITLE").map(|t| t.as_str())
+    }
+
+    /// Sets the `TITLE`.
+    pub fn set_title(&mut self, title: &str) -> &mut Self {
+        self.metadata.insert("TITLE", title);
+        self
+    }
+
+    /// Returns the precursor m/z from `PEPMASS`, if it's set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use msn_kit::spectrum::Spectrum;
+    ///
+    /// let mut s = Spectrum::empty();
+    /// s.add_metadata_field(String::from("PEPMASS"), String::from("898.727 12345.6"));
+    ///
+    /// assert_eq!(s.precursor_mz().unwrap(), Some(898.727));
+    /// assert_eq!(s.precursor_intensity().unwrap(), Some(12345.6));
+    ///
+    /// s.add_metadata_field(String::from("PEPMASS"), String::from("high"));
+    /// assert_eq!(
+    ///     s.precursor_mz().unwrap_err().to_string(),
+    ///     "invalid PEPMASS \"high\", expected an m/z optionally followed by an intensity"
+    /// );
+    /// ```
+    pub fn precursor_mz(&self) -> Result<Option<f64>, MetadataError> {
+        Ok(self
+            .typed("PEPMASS", metadata::parse_pepmass)?
+            .map(|(mz, _)| mz))
+    }
+
+    /// Returns the precursor intensity from `PEPMASS`, if it has one.
+    pub fn precursor_intensity(&self) -> Result<Option<f64>, MetadataError> {
+        Ok(self
+            .typed("PEPMASS", metadata::parse_pepmass)?
+            .and_then(|(_, intensity)| intensity))
+    }
+
+    /// Sets `PEPMASS` to the precursor m/z and, optionally, its intensity.
+    pub fn set_precursor(&mut self, mz: f64, intensity: Option<f64>) -> &mut Self {
+        self.metadata
+            .insert("PEPMASS", metadata::format_pepmass(mz, intensity));
+        self
+    }
+
+    /// Returns the signed charges in `CHARGE`, e.g. `[2, 3]` for `2+ and 3+` or `2+,3+`.
+    pub fn charges(&self) -> Result<Option<Vec<i32>>, MetadataError> {
+        self.typed("CHARGE", metadata::parse_charges)
+    }
+
+    /// Sets `CHARGE` to `charges`, e.g. `2+ and 3+` for `[2, 3]`, or removes it if there are
+    /// none.
+    pub fn set_charges(&mut self, charges: &[i32]) -> &mut Self {
+        if charges.is_empty() {
+            self.metadata.remove("CHARGE");
+        } else {
+            self.metadata
+                .insert("CHARGE", metadata::format_charges(charges));
+        }
+        self
+    }
+
+    /// Returns the retention time in seconds from `RTINSECONDS`, as a range for a spectrum that
+    /// spans several, e.g. `10.5-12.0`. A single time is a range that starts and ends at it.
+    pub fn retention_time(&self) -> Result<Option<RangeInclusive<f64>>, MetadataError> {
+        self.typed("RTINSECONDS", metadata::parse_range)
+    }
+
+    /// Sets `RTINSECONDS`, written as a single time when the range starts and ends at it.
+    pub fn set_retention_time(&mut self, retention_time: RangeInclusive<f64>) -> &mut Self {
+        self.metadata
+            .insert("RTINSECONDS", metadata::format_range(&retention_time));
+        self
+    }
+
+    /// Returns the scan numbers from `SCANS`, e.g. `100..=105` for `100-105` or `7..=7` for `7`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use msn_kit::spectrum::Spectrum;
+    ///
+    /// let mut s = Spectrum::empty();
+    /// s.set_scans(100..=105).set_charges(&[2, 3]).set_ms_level(2);
+    ///
+    /// assert_eq!(s.metadata["SCANS"], "100-105");
+    /// assert_eq!(s.metadata["CHARGE"], "2+ and 3+");
+    /// assert_eq!(s.scans().unwrap(), Some(100..=105));
+    /// assert_eq!(s.ms_level().unwrap(), Some(2));
+    /// assert_eq!(s.retention_time().unwrap(), None);
+    /// ```
+    pub fn scans(&self) -> Result<Option<RangeInclusive<u64>>, MetadataError> {
+        self.typed("SCANS", metadata::parse_range)
+    }
+
+    /// Sets `SCANS`, written as a single scan when the range starts and ends at it.
+    pub fn set_scans(&mut self, scans: RangeInclusive<u64>) -> &mut Self {
+        self.metadata
+            .insert("SCANS", metadata::format_range(&scans));
+        self
+    }
+
+    /// Returns the `MSLEVEL`, if it's set.
+    pub fn ms_level(&self) -> Result<Option<u32>, MetadataError> {
+        self.typed("MSLEVEL", |v| {
+            v.trim().parse().map_err(|_| "a whole number like 2")
+        })
+    }
+
+    /// Sets the `MSLEVEL`.
+    pub fn set_ms_level(&mut self, ms_level: u32) -> &mut Self {
+        self.metadata.insert("MSLEVEL", ms_level.to_string());
+        self
+    }
+
+    /// Parses the first value of `key` with `parse`, which returns what it expected on failure.
+    fn typed<T, F>(&self, key: &str, parse: F) -> Result<Option<T>, MetadataError>
+    where
+        F: Fn(&str) -> Result<T, &'static str>,
+    {
+        self.metadata
+            .get(key)
+            .map(|value| {
+                parse(value).map_err(|expected| MetadataError {
+                    key: key.to_string(),
+                    value: value.clone(),
+                    expected,
+                })
+            })
+            .transpose()
+    }
+}
+
+/// The peaks of a `LazySpectrum`, as they were read.
+#[derive(Debug)]
+enum Peaks {
+    /// The unparsed peaks of an MGF record.
+    Mgf(mgf_parser::MgfPeaks),
+
+    /// An mzML spectrum, with its arrays still base64 encoded.
+    MzML(Box<mzml_parser::Spectrum>),
+
+    /// Peaks that were already parsed, for formats that can't be read lazily.
+    Parsed(Vec<f64>, Vec<f64>),
+}
+
+/// A spectrum's metadata, with the peaks only parsed or decoded when asked for.
+///
+/// # Examples
+///
+/// ```
+/// use msn_kit::io::mgf_parser::MGFReader;
+///
+/// let mgf = b"BEGIN IONS\nTITLE=1\n100.5\t10\n200.5\t20\nEND IONS\n";
+/// let lazy = MGFReader::new(&mgf[..]).lazy_spectra().next().unwrap().unwrap();
+///
+/// assert_eq!(lazy.metadata["TITLE"], "1");
+/// assert_eq!(lazy.n_peaks(), 2);
+/// assert_eq!(lazy.into_spectrum().unwrap().mz, vec![100.5, 200.5]);
+/// ```
+#[derive(Debug)]
+pub struct LazySpectrum {
+    pub metadata: Metadata,
+    n_peaks: usize,
+    peaks: Peaks,
+}
+
+impl LazySpectrum {
+    pub(crate) fn from_mgf(metadata: Metadata, peaks: mgf_parser::MgfPeaks) -> Self {
+        Self {
+            metadata,
+            n_peaks: peaks.len(),
+            peaks: Peaks::Mgf(peaks),
+        }
+    }
+
+    pub(crate) fn from_mzml(
+        metadata: Metadata,
+        n_peaks: usize,
+        spectrum: mzml_parser::Spectrum,
+    ) -> Self {
+        Self {
+            metadata,
+            n_peaks,
+            peaks: Peaks::MzML(Box::new(spectrum)),
+        }
+    }
+
+    /// Returns the number of peaks, without parsing them.
+    pub fn n_peaks(&self) -> usize {
+        self.n_peaks
+    }
+
+    /// Parses or decodes the peaks into a `Spectrum`.
+    pub fn into_spectrum(self) -> std::io::Result<Spectrum> {
+        let (mz, intensities) = match self.peaks {
+            Peaks::Mgf(peaks) => peaks.parse()?,
+            Peaks::MzML(spectrum) => mzml_parser::decode_peaks(&spectrum)?,
+            Peaks::Parsed(mz, intensities) => (mz, intensities),
+        };
+
+        Ok(Spectrum::new(self.metadata, mz, intensities))
+    }
+}
+
+/// Wraps an already parsed spectrum.
+impl From<Spectrum> for LazySpectrum {
+    fn from(spectrum: Spectrum) -> Self {
+        Self {
+            metadata: spectrum.metadata,
+            n_peaks: spectrum.mz.len(),
+            peaks: Peaks::Parsed(spectrum.mz, spectrum.intensities),
+        }
+    }
 }